@@ -0,0 +1,127 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::CONFIG;
+
+// How long to wait on the desktop before giving up, so a sleeping computer
+// doesn't hang the app
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where AnkiConnect listens, as host:port. It only answers on localhost
+/// until its webBindAddress is set to 0.0.0.0, so this is the desktop's
+/// address on the Wi-Fi. Set with `anki_address` in the config.
+fn address() -> String {
+    CONFIG.get("anki_address", "localhost:8765".to_owned())
+}
+
+/// The deck cards are added to, created if it's missing. Set with
+/// `anki_deck` in the config.
+fn deck() -> String {
+    CONFIG.get("anki_deck", "Flashcards".to_owned())
+}
+
+/// Adds a Basic note to Anki with `front` and `back`, PNGs of the card's
+/// sides, as its two fields. `name` keeps the images apart in Anki's media
+/// folder.
+pub fn add_card(name: &str, front: &[u8], back: &[u8]) -> std::io::Result<()> {
+    let deck = json_string(&deck());
+    request("createDeck", &format!("{{\"deck\": {0}}}", deck))?;
+    let mut fields = Vec::new();
+    for (side, png) in [("front", front), ("back", back)] {
+        let filename = format!("flashcards-{0}-{1}.png", name, side);
+        let params = format!(
+            "{{\"filename\": {0}, \"data\": \"{1}\"}}",
+            json_string(&filename),
+            base64(png)
+        );
+        request("storeMediaFile", &params)?;
+        fields.push(json_string(&format!("<img src=\"{0}\">", filename)));
+    }
+    let params = format!(
+        concat!(
+            "{{\"note\": {{\"deckName\": {0}, \"modelName\": \"Basic\", ",
+            "\"fields\": {{\"Front\": {1}, \"Back\": {2}}}, ",
+            "\"tags\": [\"flashcards\"]}}}}"
+        ),
+        deck, fields[0], fields[1]
+    );
+    request("addNote", &params)
+}
+
+/// Posts one action to AnkiConnect and checks it came back without an error
+fn request(action: &str, params: &str) -> std::io::Result<()> {
+    let address = address();
+    let socket = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such host"))?;
+    let mut stream = TcpStream::connect_timeout(&socket, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let body = format!(
+        "{{\"action\": \"{0}\", \"version\": 6, \"params\": {1}}}",
+        action, params
+    );
+    write!(
+        stream,
+        concat!(
+            "POST / HTTP/1.1\r\n",
+            "Host: {0}\r\n",
+            "Content-Type: application/json\r\n",
+            "Content-Length: {1}\r\n",
+            "Connection: close\r\n\r\n",
+            "{2}"
+        ),
+        address,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    if !head.starts_with("HTTP/1.1 200") && !head.starts_with("HTTP/1.0 200") {
+        let status = head.lines().next().unwrap_or_default();
+        return Err(Error::other(status.to_owned()));
+    }
+    // Version 6 answers {"result": ..., "error": ...}, with a null error on
+    // success
+    match body.split_once("\"error\":") {
+        Some((_, error)) if error.trim_start().starts_with("null") => Ok(()),
+        _ => Err(Error::other(body.trim().to_owned())),
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{0:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
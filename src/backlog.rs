@@ -0,0 +1,178 @@
+use libremarkable::input::{InputEvent, WacomEvent};
+
+use once_cell::sync::Lazy;
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+
+use crate::CONFIG;
+
+// How far drawing may fall behind the pen before the points waiting are
+// thinned out. Set with `max_pen_lag_ms` in the config.
+static MAX_LAG: Lazy<Duration> =
+    Lazy::new(|| Duration::from_millis(CONFIG.get("max_pen_lag_ms", 60)));
+
+/// Takes input events off the channel and keeps drawing from falling too far
+/// behind the pen. Events can't be older than the last time the channel was
+/// found empty, so once that's longer ago than the allowed lag, the run of
+/// pen draw events waiting is cut down to its ends and pressure extremes.
+pub struct Backlog {
+    caught_up: Instant,
+    /// Events already taken off the channel, next up, each with how many
+    /// pen reports it stands for
+    held: VecDeque<(InputEvent, u32)>,
+    /// How many reports the event last handed out stands for
+    reports: u32,
+}
+
+impl Backlog {
+    pub fn new() -> Self {
+        Backlog {
+            caught_up: Instant::now(),
+            held: VecDeque::new(),
+            reports: 1,
+        }
+    }
+
+    /// How many pen reports the event last handed out stands for. It's more
+    /// than one when the reports just before it were thinned out.
+    pub fn reports(&self) -> u32 {
+        self.reports
+    }
+
+    /// Like `rx.recv_timeout`, with any backlog of draw events thinned
+    pub fn recv_timeout(
+        &mut self,
+        rx: &Receiver<InputEvent>,
+        timeout: Duration,
+    ) -> Result<InputEvent, RecvTimeoutError> {
+        self.reports = 1;
+        if let Some((event, reports)) = self.held.pop_front() {
+            self.reports = reports;
+            return Ok(event);
+        }
+        let event = match rx.try_recv() {
+            Ok(event) => event,
+            Err(TryRecvError::Empty) => {
+                let event = rx.recv_timeout(timeout)?;
+                // Whatever was waited on is fresh, however long the wait
+                self.caught_up = Instant::now();
+                return Ok(event);
+            }
+            Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+        };
+        if !is_draw(&event) || self.caught_up.elapsed() <= *MAX_LAG {
+            return Ok(event);
+        }
+        let mut run = vec![event];
+        loop {
+            match rx.try_recv() {
+                Ok(event) if is_draw(&event) => run.push(event),
+                Ok(event) => {
+                    self.held.push_back((event, 1));
+                    break;
+                }
+                Err(_) => {
+                    self.caught_up = Instant::now();
+                    break;
+                }
+            }
+        }
+        let mut thinned = thin(run);
+        thinned.extend(self.held.drain(..));
+        self.held = thinned;
+        let (event, reports) = self.held.pop_front().unwrap();
+        self.reports = reports;
+        Ok(event)
+    }
+}
+
+fn is_draw(event: &InputEvent) -> bool {
+    matches!(
+        event,
+        InputEvent::WacomEvent {
+            event: WacomEvent::Draw { .. }
+        }
+    )
+}
+
+fn pressure(event: &InputEvent) -> u16 {
+    match event {
+        InputEvent::WacomEvent {
+            event: WacomEvent::Draw { pressure, .. },
+        } => *pressure,
+        _ => 0,
+    }
+}
+
+/// The first and last of a run of draw events, and the lightest and
+/// heaviest in between, in their original order. Each comes with the number
+/// of reports it stands for, itself and those dropped just before it.
+fn thin(run: Vec<InputEvent>) -> VecDeque<(InputEvent, u32)> {
+    if run.len() <= 4 {
+        return run.into_iter().map(|event| (event, 1)).collect();
+    }
+    let last = run.len() - 1;
+    let middle = 1..last;
+    let lightest = middle.clone().min_by_key(|&i| pressure(&run[i])).unwrap();
+    let heaviest = middle.max_by_key(|&i| pressure(&run[i])).unwrap();
+    let mut kept = 0;
+    run.into_iter()
+        .enumerate()
+        .filter(|(i, _)| [0, lightest, heaviest, last].contains(i))
+        .map(|(i, event)| {
+            let reports = (i - kept).max(1) as u32;
+            kept = i;
+            (event, reports)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libremarkable::framebuffer::cgmath;
+
+    fn draw(x: f32, pressure: u16) -> InputEvent {
+        InputEvent::WacomEvent {
+            event: WacomEvent::Draw {
+                position: cgmath::Point2 { x, y: 0.0 },
+                pressure,
+                tilt: cgmath::vec2(0, 0),
+            },
+        }
+    }
+
+    fn x(event: &InputEvent) -> f32 {
+        match event {
+            InputEvent::WacomEvent {
+                event: WacomEvent::Draw { position, .. },
+            } => position.x,
+            _ => panic!("not a draw event"),
+        }
+    }
+
+    #[test]
+    fn short_runs_are_left_alone() {
+        let run: Vec<_> = (0..4).map(|i| draw(i as f32, 1000)).collect();
+        let thinned = thin(run);
+        assert_eq!(thinned.len(), 4);
+        assert!(thinned.iter().all(|&(_, reports)| reports == 1));
+    }
+
+    #[test]
+    fn long_runs_keep_their_ends_and_pressure_extremes_in_order() {
+        let pressures = [900, 1000, 2500, 1000, 1000, 300, 1000, 1000, 1200, 1100];
+        let run: Vec<_> = pressures
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| draw(i as f32, p))
+            .collect();
+        let thinned = thin(run);
+        let kept: Vec<f32> = thinned.iter().map(|(event, _)| x(event)).collect();
+        assert_eq!(kept, vec![0.0, 2.0, 5.0, 9.0]);
+        let reports: Vec<u32> = thinned.iter().map(|&(_, reports)| reports).collect();
+        assert_eq!(reports, vec![1, 2, 3, 4]);
+    }
+}
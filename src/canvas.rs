@@ -0,0 +1,1021 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::{FramebufferDraw, FramebufferIO};
+
+use log::error;
+
+use std::collections::VecDeque;
+
+use crate::fill;
+use crate::guide::Guide;
+use crate::shapes;
+use crate::stroke::{self, Brush, Layer, Snapshot, Span, Stroke, StrokePoint};
+
+// Each undo entry can hold a compressed snapshot of the pixels a stroke
+// replaced, so the depth is capped to keep memory in check.
+const UNDO_LIMIT: usize = 200;
+
+// Width of the border drawn by the canvas region element. Repainting stays
+// inside it so the border survives.
+const BORDER_PX: u32 = 2;
+
+pub const MAX_ZOOM: f32 = 4.0;
+
+/// The part of the canvas on screen. Strokes are kept in canvas coordinates,
+/// which are the same as the screen's at 1x.
+#[derive(Copy, Clone, PartialEq)]
+pub struct View {
+    pub zoom: f32,
+    /// Canvas point shown in the top left corner of the drawable area
+    pub origin: cgmath::Point2<f32>,
+}
+
+/// A stroke's index, with the points, fill spans and brush size it had or is
+/// to be given
+type Shape = (usize, Vec<StrokePoint>, Vec<Span>, u32);
+
+/// Undoable changes, newest last on the undo stack
+enum Edit {
+    /// The last stroke in the list was drawn, over the pixels saved here.
+    /// None once they're no use for undoing, as for strokes drawn zoomed in.
+    Drew(Option<Snapshot>),
+    /// Strokes deleted by the stroke eraser, with the index each was removed
+    /// from, in removal order
+    Erased(Vec<(usize, Stroke)>),
+    /// Strokes that were moved or resized, with their previous shapes
+    Reshaped(Vec<Shape>),
+    /// This many strokes were added at the end of the list in one go
+    Pasted(usize),
+}
+
+/// Undone changes waiting to be redone
+enum Undone {
+    Drew(Stroke),
+    Erased(Vec<usize>),
+    Reshaped(Vec<Shape>),
+    Pasted(Vec<Stroke>),
+}
+
+/// Every stroke on one canvas region, in drawing order, plus its edit history
+pub struct Canvas {
+    pub bounds: mxcfb_rect,
+    strokes: Vec<Stroke>,
+    /// Stroke being drawn right now, not yet in `strokes`
+    current: Option<Stroke>,
+    /// Strokes deleted since the stroke eraser last touched down
+    erasing: Vec<(usize, Stroke)>,
+    /// Indices of the strokes picked by the lasso, ascending
+    selection: Vec<usize>,
+    undo: VecDeque<Edit>,
+    redo: Vec<Undone>,
+    view: View,
+    /// Layer new strokes go on
+    layer: Layer,
+    guide: Guide,
+}
+
+impl Canvas {
+    pub fn new(bounds: mxcfb_rect) -> Self {
+        Canvas {
+            bounds,
+            strokes: Vec::new(),
+            current: None,
+            erasing: Vec::new(),
+            selection: Vec::new(),
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            view: View {
+                zoom: 1.0,
+                origin: cgmath::Point2 {
+                    x: (bounds.left + BORDER_PX) as f32,
+                    y: (bounds.top + BORDER_PX) as f32,
+                },
+            },
+            layer: Layer::Diagram,
+            guide: Guide::None,
+        }
+    }
+
+    /// The stroke in progress, starting a new one with the given brush if the
+    /// pen just touched down
+    pub fn current_stroke(&mut self, color: color, mult: u32, brush: Brush) -> &mut Stroke {
+        let layer = self.layer;
+        self.current.get_or_insert_with(|| {
+            let mut stroke = Stroke::new(color, mult, brush);
+            stroke.layer = layer;
+            stroke
+        })
+    }
+
+    pub fn guide(&self) -> Guide {
+        self.guide
+    }
+
+    /// Swaps the guide drawn under the canvas. Returns the area repainted.
+    pub fn set_guide(&mut self, framebuffer: &mut Framebuffer, guide: Guide) -> mxcfb_rect {
+        self.guide = guide;
+        // What the strokes covered had the old guide in it
+        self.discard_snapshots();
+        self.repaint(framebuffer, self.inner())
+    }
+
+    /// The pixels of `rect` as they'd be at 1x without the guide, for saving.
+    /// What was on screen is put straight back, so nothing needs refreshing.
+    pub fn dump_unguided(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        rect: mxcfb_rect,
+    ) -> Result<Vec<u8>, &'static str> {
+        if self.guide == Guide::None && !self.zoomed() {
+            return framebuffer.dump_region(rect);
+        }
+        let area = rect.merge_rect(&self.inner());
+        let screen = framebuffer.dump_region(area)?;
+        let unzoomed = View {
+            zoom: 1.0,
+            origin: self.inner().top_left().cast().unwrap(),
+        };
+        let guide = std::mem::replace(&mut self.guide, Guide::None);
+        let view = std::mem::replace(&mut self.view, unzoomed);
+        self.repaint(framebuffer, self.inner());
+        self.guide = guide;
+        self.view = view;
+        let unguided = framebuffer.dump_region(rect);
+        if let Err(e) = framebuffer.restore_region(area, &screen) {
+            error!("Error while restoring region: {0}", e);
+        }
+        unguided
+    }
+
+    /// The drawable area as an SVG document, strokes in the order they're
+    /// painted so the notes layer ends up on top and white erasures cover
+    /// what they erased
+    pub fn svg(&self) -> String {
+        let inner = self.inner();
+        let origin = cgmath::Point2 {
+            x: inner.left as f32,
+            y: inner.top as f32,
+        };
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+            inner.width, inner.height
+        );
+        svg.push_str("<rect width=\"100%\" height=\"100%\" fill=\"#ffffff\"/>\n");
+        for stroke in self.stacked() {
+            svg.push_str(&stroke.svg(origin));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    pub fn stroke_count(&self) -> usize {
+        self.strokes.len()
+    }
+
+    pub fn layer(&self) -> Layer {
+        self.layer
+    }
+
+    /// Switches the layer that gets drawn on, dropping the selection as it
+    /// belongs to the other one
+    pub fn set_layer(&mut self, layer: Layer) {
+        self.layer = layer;
+        self.selection.clear();
+    }
+
+    /// Commits whatever the pen was doing since it touched down. Returns the
+    /// stroke that was drawn, if any.
+    pub fn pen_up(&mut self, framebuffer: &mut Framebuffer) -> Option<&Stroke> {
+        if !self.erasing.is_empty() {
+            let erased = std::mem::take(&mut self.erasing);
+            self.push_edit(Edit::Erased(erased));
+        }
+        let mut stroke = self.current.take()?;
+        if stroke.is_empty() {
+            return None;
+        }
+        if self.zoomed() {
+            // The stroke was drawn in screen space. Bring it back to the
+            // canvas; what it covered on screen is no use for undoing at 1x.
+            let view = self.view;
+            stroke.discard_snapshot();
+            stroke.points = stroke
+                .points
+                .iter()
+                .map(|p| (self.to_canvas(p.0), p.1))
+                .collect();
+            stroke.mult = ((stroke.mult as f32 / view.zoom).round() as u32).max(1);
+            stroke.rect = stroke.bounds();
+        } else {
+            stroke.finish(framebuffer);
+        }
+        let rect = stroke.rect;
+        if stroke.color == color::WHITE {
+            self.guide
+                .draw(framebuffer, &self.inner(), &self.on_screen(&rect));
+        }
+        let snapshot = stroke.take_snapshot();
+        self.strokes.push(stroke);
+        self.push_edit(Edit::Drew(snapshot));
+        self.restack(framebuffer, rect);
+        self.strokes.last()
+    }
+
+    /// Draws a stroke that was built whole rather than traced by the pen and
+    /// adds it to the canvas. Returns the area drawn.
+    pub fn commit(&mut self, framebuffer: &mut Framebuffer, mut stroke: Stroke) -> mxcfb_rect {
+        stroke.layer = self.layer;
+        let rect = stroke.redraw(framebuffer, &self.bounds);
+        if stroke.color == color::WHITE {
+            self.guide.draw(framebuffer, &self.inner(), &rect);
+        }
+        let snapshot = stroke.take_snapshot();
+        self.strokes.push(stroke);
+        self.push_edit(Edit::Drew(snapshot));
+        match self.restack(framebuffer, rect) {
+            Some(repainted) => repainted,
+            None => rect,
+        }
+    }
+
+    /// Ink goes on top of whatever is on screen, so a stroke added to a lower
+    /// layer than others it crosses covers them until they're repainted over
+    /// it. Returns the area repainted, if any.
+    fn restack(&mut self, framebuffer: &mut Framebuffer, rect: mxcfb_rect) -> Option<mxcfb_rect> {
+        if self.is_covered(&rect) {
+            Some(self.repaint(framebuffer, rect))
+        } else {
+            None
+        }
+    }
+
+    /// Whether strokes on a layer above the active one cross `rect`
+    pub fn is_covered(&self, rect: &mxcfb_rect) -> bool {
+        self.strokes
+            .iter()
+            .any(|s| s.layer > self.layer && intersects(&s.rect, rect))
+    }
+
+    /// Flood fills the area around `pos` with `color`, as an undoable stroke.
+    /// Returns the area filled.
+    pub fn fill(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        pos: cgmath::Point2<f32>,
+        color: color,
+    ) -> Option<mxcfb_rect> {
+        let spans = fill::flood(framebuffer, &self.inner(), pos, color, self.guide);
+        if spans.is_empty() {
+            return None;
+        }
+        Some(self.commit(framebuffer, Stroke::fill(color, spans)))
+    }
+
+    /// Swaps the stroke in progress for the shape it most looks like, if any.
+    /// Returns the area that changed.
+    pub fn straighten_current(&mut self, framebuffer: &mut Framebuffer) -> Option<mxcfb_rect> {
+        let stroke = self.current.as_mut()?;
+        let points = shapes::straighten(&stroke.points)?;
+        stroke.finish(framebuffer);
+        let lifted = stroke.undraw(framebuffer);
+        stroke.points = points;
+        let drawn = stroke.redraw(framebuffer, &self.bounds);
+        Some(lifted.merge_rect(&drawn))
+    }
+
+    /// Deletes every stroke touched by an eraser of `radius` at `pos` on
+    /// screen and repaints what was under them
+    pub fn erase_strokes_at(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        pos: cgmath::Point2<f32>,
+        radius: f32,
+    ) -> Option<mxcfb_rect> {
+        let pos = self.to_canvas(pos);
+        let radius = radius / self.view.zoom;
+        let mut dirty = mxcfb_rect::invalid();
+        let mut i = 0;
+        while i < self.strokes.len() {
+            // White strokes are pixel erasing; removing them would bring back
+            // ink that was deliberately rubbed out
+            let stroke = &self.strokes[i];
+            if stroke.layer == self.layer
+                && stroke.color != color::WHITE
+                && stroke.hit_by(pos, radius)
+            {
+                let stroke = self.strokes.remove(i);
+                dirty = dirty.merge_rect(&stroke.rect);
+                self.erasing.push((i, stroke));
+            } else {
+                i += 1;
+            }
+        }
+        if dirty == mxcfb_rect::invalid() {
+            return None;
+        }
+        Some(self.repaint(framebuffer, dirty))
+    }
+
+    pub fn undo(&mut self, framebuffer: &mut Framebuffer) -> Option<mxcfb_rect> {
+        self.selection.clear();
+        match self.undo.pop_back()? {
+            Edit::Drew(snapshot) => {
+                let mut stroke = self.strokes.pop()?;
+                let rect = stroke.rect;
+                stroke.rect = mxcfb_rect::invalid();
+                let restored = match snapshot {
+                    Some(snapshot) if !self.zoomed() => stroke::restore(framebuffer, snapshot),
+                    _ => mxcfb_rect::invalid(),
+                };
+                self.redo.push(Undone::Drew(stroke));
+                // Strokes drawn while zoomed in have no pixels to restore
+                if restored == mxcfb_rect::invalid() {
+                    Some(self.repaint(framebuffer, rect))
+                } else {
+                    Some(restored)
+                }
+            }
+            Edit::Erased(erased) => {
+                let mut dirty = mxcfb_rect::invalid();
+                let mut indices = Vec::with_capacity(erased.len());
+                for (index, stroke) in erased.into_iter().rev() {
+                    dirty = dirty.merge_rect(&stroke.rect);
+                    self.strokes.insert(index, stroke);
+                    indices.push(index);
+                }
+                indices.reverse();
+                self.redo.push(Undone::Erased(indices));
+                Some(self.repaint(framebuffer, dirty))
+            }
+            Edit::Reshaped(shapes) => {
+                let (shapes, dirty) = self.swap_shapes(shapes);
+                self.redo.push(Undone::Reshaped(shapes));
+                Some(self.repaint(framebuffer, dirty))
+            }
+            Edit::Pasted(count) => {
+                let pasted = self.strokes.split_off(self.strokes.len() - count);
+                let dirty = rects_around(&pasted);
+                self.redo.push(Undone::Pasted(pasted));
+                Some(self.repaint(framebuffer, dirty))
+            }
+        }
+    }
+
+    pub fn redo(&mut self, framebuffer: &mut Framebuffer) -> Option<mxcfb_rect> {
+        self.selection.clear();
+        match self.redo.pop()? {
+            Undone::Drew(mut stroke) => {
+                if self.zoomed() {
+                    stroke.rect = stroke.bounds();
+                    let rect = stroke.rect;
+                    self.strokes.push(stroke);
+                    self.undo.push_back(Edit::Drew(None));
+                    return Some(self.repaint(framebuffer, rect));
+                }
+                let rect = stroke.redraw(framebuffer, &self.bounds);
+                let snapshot = stroke.take_snapshot();
+                self.strokes.push(stroke);
+                self.undo.push_back(Edit::Drew(snapshot));
+                Some(rect)
+            }
+            Undone::Erased(indices) => {
+                let mut dirty = mxcfb_rect::invalid();
+                let mut erased = Vec::with_capacity(indices.len());
+                for index in indices {
+                    let stroke = self.strokes.remove(index);
+                    dirty = dirty.merge_rect(&stroke.rect);
+                    erased.push((index, stroke));
+                }
+                self.undo.push_back(Edit::Erased(erased));
+                Some(self.repaint(framebuffer, dirty))
+            }
+            Undone::Reshaped(shapes) => {
+                let (shapes, dirty) = self.swap_shapes(shapes);
+                self.undo.push_back(Edit::Reshaped(shapes));
+                Some(self.repaint(framebuffer, dirty))
+            }
+            Undone::Pasted(pasted) => {
+                let dirty = rects_around(&pasted);
+                self.undo.push_back(Edit::Pasted(pasted.len()));
+                self.strokes.extend(pasted);
+                Some(self.repaint(framebuffer, dirty))
+            }
+        }
+    }
+
+    /// Deletes every stroke, on both layers, as a single edit. Returns the
+    /// area blanked.
+    pub fn clear(&mut self, framebuffer: &mut Framebuffer) -> Option<mxcfb_rect> {
+        if self.strokes.is_empty() {
+            return None;
+        }
+        // Last first, so that each index is still right at its removal
+        let erased = self.strokes.drain(..).enumerate().rev().collect();
+        self.push_edit(Edit::Erased(erased));
+        Some(self.repaint(framebuffer, self.inner()))
+    }
+
+    /// Moves the canvas to `bounds`, taking its strokes and their history
+    /// along. Strokes left outside a smaller canvas are kept, only hidden.
+    /// Returns the area repainted.
+    pub fn set_bounds(&mut self, framebuffer: &mut Framebuffer, bounds: mxcfb_rect) -> mxcfb_rect {
+        let dx = bounds.left as i32 - self.bounds.left as i32;
+        let dy = bounds.top as i32 - self.bounds.top as i32;
+        let shift = cgmath::vec2(dx as f32, dy as f32);
+        let shift_shape = |points: &mut Vec<StrokePoint>, spans: &mut Vec<Span>| {
+            for point in points.iter_mut() {
+                point.0 += shift;
+            }
+            for span in spans.iter_mut() {
+                *span = (
+                    (span.0 as i32 + dy) as u32,
+                    (span.1 as i32 + dx) as u32,
+                    (span.2 as i32 + dx) as u32,
+                );
+            }
+        };
+        for stroke in self.strokes.iter_mut() {
+            *stroke = stroke.translated(dx, dy);
+        }
+        for edit in self.undo.iter_mut() {
+            match edit {
+                Edit::Erased(erased) => {
+                    for (_, stroke) in erased.iter_mut() {
+                        *stroke = stroke.translated(dx, dy);
+                    }
+                }
+                Edit::Reshaped(shapes) => {
+                    for (_, points, spans, _) in shapes.iter_mut() {
+                        shift_shape(points, spans);
+                    }
+                }
+                // Saved at the old position
+                Edit::Drew(snapshot) => *snapshot = None,
+                Edit::Pasted(_) => {}
+            }
+        }
+        for undone in self.redo.iter_mut() {
+            match undone {
+                Undone::Drew(stroke) => *stroke = stroke.translated(dx, dy),
+                Undone::Pasted(strokes) => {
+                    for stroke in strokes.iter_mut() {
+                        *stroke = stroke.translated(dx, dy);
+                    }
+                }
+                Undone::Reshaped(shapes) => {
+                    for (_, points, spans, _) in shapes.iter_mut() {
+                        shift_shape(points, spans);
+                    }
+                }
+                Undone::Erased(_) => {}
+            }
+        }
+        self.current = None;
+        self.erasing.clear();
+        self.selection.clear();
+        self.bounds = bounds;
+        self.view = View {
+            zoom: 1.0,
+            origin: self.inner().top_left().cast().unwrap(),
+        };
+        self.repaint(framebuffer, self.inner())
+    }
+
+    /// Copies of the selected strokes, shifted by `dx` and `dy`
+    pub fn copy_selection(&self, dx: i32, dy: i32) -> Vec<Stroke> {
+        self.selection
+            .iter()
+            .map(|&i| self.strokes[i].translated(dx, dy))
+            .collect()
+    }
+
+    /// Pastes copies of the selected strokes shifted by `dx` and `dy`, then
+    /// selects the copies in their place. Returns the area redrawn.
+    pub fn duplicate_selection(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        dx: i32,
+        dy: i32,
+    ) -> Option<mxcfb_rect> {
+        let copies = self.copy_selection(dx, dy);
+        let first = self.strokes.len();
+        let rect = self.paste(framebuffer, copies)?;
+        self.selection = (first..self.strokes.len()).collect();
+        Some(rect)
+    }
+
+    /// Adds `strokes` as a single edit, keeping only the ones that land inside
+    /// the canvas. Returns the area redrawn.
+    pub fn paste(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        strokes: Vec<Stroke>,
+    ) -> Option<mxcfb_rect> {
+        let inner = self.inner();
+        let strokes: Vec<Stroke> = strokes
+            .into_iter()
+            .filter(|s| intersection(&s.rect, &inner) == Some(s.rect))
+            .collect();
+        if strokes.is_empty() {
+            return None;
+        }
+        let dirty = rects_around(&strokes);
+        let count = strokes.len();
+        self.strokes.extend(strokes);
+        self.push_edit(Edit::Pasted(count));
+        Some(self.repaint(framebuffer, dirty))
+    }
+
+    /// Selects the strokes that lie mostly inside the closed `lasso` loop,
+    /// fills and placed pictures included, returning the rect around them
+    pub fn select(&mut self, lasso: &[cgmath::Point2<f32>]) -> Option<mxcfb_rect> {
+        self.selection = self
+            .strokes
+            .iter()
+            .enumerate()
+            .filter(|(_, stroke)| stroke.layer == self.layer)
+            .filter(|(_, stroke)| {
+                let outline = stroke.outline();
+                let inside = outline
+                    .iter()
+                    .filter(|&&p| point_in_polygon(p, lasso))
+                    .count();
+                !outline.is_empty() && inside * 2 >= outline.len()
+            })
+            .map(|(i, _)| i)
+            .collect();
+        self.selection_rect()
+    }
+
+    pub fn selection_rect(&self) -> Option<mxcfb_rect> {
+        if self.selection.is_empty() {
+            return None;
+        }
+        Some(
+            self.selection
+                .iter()
+                .fold(mxcfb_rect::invalid(), |acc, &i| {
+                    acc.merge_rect(&self.strokes[i].rect)
+                }),
+        )
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection.clear();
+    }
+
+    /// Moves every point of the selected strokes, and every pixel of selected
+    /// fills, through `map`, scaling their brush size by `scale`
+    pub fn transform_selection<F>(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        map: F,
+        scale: f32,
+    ) -> Option<mxcfb_rect>
+    where
+        F: Fn(cgmath::Point2<f32>) -> cgmath::Point2<f32>,
+    {
+        if self.selection.is_empty() {
+            return None;
+        }
+        let inner = self.inner();
+        let shapes = self
+            .selection
+            .iter()
+            .map(|&i| {
+                let stroke = &self.strokes[i];
+                let points = stroke.points.iter().map(|p| (map(p.0), p.1)).collect();
+                let spans = stroke::map_spans(&stroke.spans, &map, &inner);
+                let mult = ((stroke.mult as f32 * scale).round() as u32).max(1);
+                (i, points, spans, mult)
+            })
+            .collect();
+        let (shapes, dirty) = self.swap_shapes(shapes);
+        // Keep the strokes selected so they can be nudged again
+        let selection = std::mem::take(&mut self.selection);
+        self.push_edit(Edit::Reshaped(shapes));
+        self.selection = selection;
+        Some(self.repaint(framebuffer, dirty))
+    }
+
+    pub fn delete_selection(&mut self, framebuffer: &mut Framebuffer) -> Option<mxcfb_rect> {
+        if self.selection.is_empty() {
+            return None;
+        }
+        let mut dirty = mxcfb_rect::invalid();
+        let mut erased = Vec::with_capacity(self.selection.len());
+        // Highest index first so the ones still to go don't shift
+        for i in std::mem::take(&mut self.selection).into_iter().rev() {
+            let stroke = self.strokes.remove(i);
+            dirty = dirty.merge_rect(&stroke.rect);
+            erased.push((i, stroke));
+        }
+        self.push_edit(Edit::Erased(erased));
+        Some(self.repaint(framebuffer, dirty))
+    }
+
+    /// Puts the given shapes on their strokes, handing back the ones they
+    /// replaced along with the area that changed
+    fn swap_shapes(&mut self, shapes: Vec<Shape>) -> (Vec<Shape>, mxcfb_rect) {
+        let mut dirty = mxcfb_rect::invalid();
+        let swapped = shapes
+            .into_iter()
+            .map(|(i, points, spans, mult)| {
+                let stroke = &mut self.strokes[i];
+                dirty = dirty.merge_rect(&stroke.rect);
+                let old_points = std::mem::replace(&mut stroke.points, points);
+                let old_spans = std::mem::replace(&mut stroke.spans, spans);
+                let old_mult = std::mem::replace(&mut stroke.mult, mult);
+                stroke.rect = stroke.bounds();
+                dirty = dirty.merge_rect(&stroke.rect);
+                (i, old_points, old_spans, old_mult)
+            })
+            .collect();
+        (swapped, dirty)
+    }
+
+    pub fn view(&self) -> View {
+        self.view
+    }
+
+    pub fn zoomed(&self) -> bool {
+        self.view.zoom != 1.0
+    }
+
+    /// Zooms to `zoom` with the canvas point `center` in the middle of the
+    /// screen, as near as the canvas edges allow, and redraws. Returns the
+    /// area redrawn.
+    pub fn set_view(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        zoom: f32,
+        center: cgmath::Point2<f32>,
+    ) -> mxcfb_rect {
+        let inner = self.inner();
+        let zoom = zoom.clamp(1.0, MAX_ZOOM);
+        let visible = cgmath::vec2(inner.width as f32, inner.height as f32) / zoom;
+        let origin = center - visible / 2.0;
+        self.view = View {
+            zoom,
+            origin: cgmath::Point2 {
+                x: origin
+                    .x
+                    .max(inner.left as f32)
+                    .min((inner.left + inner.width) as f32 - visible.x),
+                y: origin
+                    .y
+                    .max(inner.top as f32)
+                    .min((inner.top + inner.height) as f32 - visible.y),
+            },
+        };
+        self.repaint_view(framebuffer)
+    }
+
+    /// Canvas point under the screen point `pos`
+    pub fn to_canvas(&self, pos: cgmath::Point2<f32>) -> cgmath::Point2<f32> {
+        let corner = self.inner().top_left().cast().unwrap();
+        self.view.origin + (pos - corner) / self.view.zoom
+    }
+
+    /// Screen point showing the canvas point `pos`
+    pub fn to_screen(&self, pos: cgmath::Point2<f32>) -> cgmath::Point2<f32> {
+        let corner: cgmath::Point2<f32> = self.inner().top_left().cast().unwrap();
+        corner + (pos - self.view.origin) * self.view.zoom
+    }
+
+    /// Where the canvas area `rect` is on screen
+    pub fn on_screen(&self, rect: &mxcfb_rect) -> mxcfb_rect {
+        if !self.zoomed() {
+            return *rect;
+        }
+        let top_left = self.to_screen(rect.top_left().cast().unwrap());
+        let bottom_right = self.to_screen(cgmath::Point2 {
+            x: (rect.left + rect.width) as f32,
+            y: (rect.top + rect.height) as f32,
+        });
+        let left = top_left.x.max(0.0);
+        let top = top_left.y.max(0.0);
+        mxcfb_rect {
+            top: top as u32,
+            left: left as u32,
+            width: (bottom_right.x - left).max(0.0).ceil() as u32,
+            height: (bottom_right.y - top).max(0.0).ceil() as u32,
+        }
+    }
+
+    /// The canvas minus its border
+    pub fn inner(&self) -> mxcfb_rect {
+        mxcfb_rect {
+            top: self.bounds.top + BORDER_PX,
+            left: self.bounds.left + BORDER_PX,
+            width: self.bounds.width - 2 * BORDER_PX,
+            height: self.bounds.height - 2 * BORDER_PX,
+        }
+    }
+
+    /// Forgets the pixels saved for undoing strokes, for when they no longer
+    /// match what's under them
+    fn discard_snapshots(&mut self) {
+        for edit in self.undo.iter_mut() {
+            if let Edit::Drew(snapshot) = edit {
+                *snapshot = None;
+            }
+        }
+    }
+
+    fn push_edit(&mut self, edit: Edit) {
+        // Indices would go stale under the selection
+        self.selection.clear();
+        self.undo.push_back(edit);
+        if self.undo.len() > UNDO_LIMIT {
+            self.undo.pop_front();
+        }
+        self.redo.clear();
+    }
+
+    /// Clears `dirty` and replays the strokes inside it. The area is first
+    /// grown until no stroke straddles its edge, otherwise blanking it would
+    /// cut strokes in half.
+    pub fn repaint(&mut self, framebuffer: &mut Framebuffer, dirty: mxcfb_rect) -> mxcfb_rect {
+        if self.zoomed() {
+            return self.repaint_view(framebuffer);
+        }
+        let mut dirty = dirty;
+        loop {
+            let grown = self
+                .strokes
+                .iter()
+                .filter(|stroke| intersects(&stroke.rect, &dirty))
+                .fold(dirty, |acc, stroke| acc.merge_rect(&stroke.rect));
+            if grown == dirty {
+                break;
+            }
+            dirty = grown;
+        }
+
+        let dirty = match intersection(&dirty, &self.inner()) {
+            Some(rect) => rect,
+            None => return mxcfb_rect::invalid(),
+        };
+        // Strokes cut by the inner edge would otherwise be drawn over the
+        // border and whatever is past it, such as the other side of the card
+        let strokes: Vec<&Stroke> = self
+            .stacked()
+            .filter(|s| intersects(&s.rect, &dirty))
+            .collect();
+        let spill = strokes.iter().fold(dirty, |acc, s| acc.merge_rect(&s.rect));
+        let saved = save_around(framebuffer, &dirty, &spill);
+        framebuffer.fill_rect(dirty.top_left().cast().unwrap(), dirty.size(), color::WHITE);
+        for stroke in strokes {
+            stroke.render(framebuffer);
+        }
+        self.guide.draw(framebuffer, &self.inner(), &dirty);
+        restore_saved(framebuffer, saved);
+        dirty
+    }
+
+    /// Every stroke in the order they should be painted, bottom layer first
+    pub fn stacked(&self) -> impl Iterator<Item = &Stroke> {
+        let diagram = self.strokes.iter().filter(|s| s.layer == Layer::Diagram);
+        let notes = self.strokes.iter().filter(|s| s.layer == Layer::Notes);
+        diagram.chain(notes)
+    }
+
+    /// Redraws the whole drawable area through the view. Strokes running off
+    /// its edge get drawn in full, so whatever they spill onto outside it is
+    /// saved beforehand and put back after.
+    fn repaint_view(&mut self, framebuffer: &mut Framebuffer) -> mxcfb_rect {
+        let inner = self.inner();
+        let visible: Vec<&Stroke> = self
+            .stacked()
+            .filter(|s| intersects(&self.on_screen(&s.rect), &inner))
+            .collect();
+        let spill = visible
+            .iter()
+            .fold(inner, |acc, s| acc.merge_rect(&self.on_screen(&s.rect)));
+        let saved = save_around(framebuffer, &inner, &spill);
+
+        framebuffer.fill_rect(inner.top_left().cast().unwrap(), inner.size(), color::WHITE);
+        let corner: cgmath::Point2<f32> = inner.top_left().cast().unwrap();
+        let view = self.view;
+        let map = |p: cgmath::Point2<f32>| corner + (p - view.origin) * view.zoom;
+        for stroke in visible {
+            stroke.render_through(framebuffer, map, view.zoom, &inner);
+        }
+        self.guide.draw(framebuffer, &inner, &inner);
+
+        restore_saved(framebuffer, saved);
+        inner
+    }
+}
+
+/// Strokes are drawn in full, so redrawing `keep` can spill onto whatever
+/// is around it, up to `spill`. Saves those parts of the screen, to be put
+/// back by `restore_saved` once the drawing is done.
+fn save_around(
+    framebuffer: &mut Framebuffer,
+    keep: &mxcfb_rect,
+    spill: &mxcfb_rect,
+) -> Vec<(mxcfb_rect, Vec<u8>)> {
+    let screen = mxcfb_rect {
+        top: 0,
+        left: 0,
+        width: DISPLAYWIDTH as u32,
+        height: DISPLAYHEIGHT as u32,
+    };
+    let spill = match intersection(&spill.merge_rect(keep), &screen) {
+        Some(spill) => spill,
+        None => return Vec::new(),
+    };
+
+    let keep_bottom = keep.top + keep.height;
+    let keep_right = keep.left + keep.width;
+    let spill_bottom = spill.top + spill.height;
+    let spill_right = spill.left + spill.width;
+    let strips = [
+        (spill.left, spill.top, spill.width, keep.top - spill.top),
+        (
+            spill.left,
+            keep_bottom,
+            spill.width,
+            spill_bottom - keep_bottom,
+        ),
+        (spill.left, keep.top, keep.left - spill.left, keep.height),
+        (keep_right, keep.top, spill_right - keep_right, keep.height),
+    ];
+    let mut saved = Vec::new();
+    for &(left, top, width, height) in strips.iter() {
+        if width == 0 || height == 0 {
+            continue;
+        }
+        let strip = mxcfb_rect {
+            top,
+            left,
+            width,
+            height,
+        };
+        match framebuffer.dump_region(strip) {
+            Err(err) => error!("Failed to dump region around a repaint: {0}", err),
+            Ok(buff) => saved.push((strip, buff)),
+        }
+    }
+    saved
+}
+
+fn restore_saved(framebuffer: &mut Framebuffer, saved: Vec<(mxcfb_rect, Vec<u8>)>) {
+    for (strip, buff) in saved {
+        if let Err(e) = framebuffer.restore_region(strip, &buff) {
+            error!("Error while restoring region: {0}", e);
+        }
+    }
+}
+
+fn rects_around(strokes: &[Stroke]) -> mxcfb_rect {
+    strokes
+        .iter()
+        .fold(mxcfb_rect::invalid(), |acc, s| acc.merge_rect(&s.rect))
+}
+
+/// Even-odd rule; the polygon is implicitly closed
+fn point_in_polygon(p: cgmath::Point2<f32>, polygon: &[cgmath::Point2<f32>]) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len().wrapping_sub(1);
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[j]);
+        if (a.y > p.y) != (b.y > p.y) && p.x < (b.x - a.x) * (p.y - a.y) / (b.y - a.y) + a.x {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+pub fn intersects(a: &mxcfb_rect, b: &mxcfb_rect) -> bool {
+    intersection(a, b).is_some()
+}
+
+pub fn intersection(a: &mxcfb_rect, b: &mxcfb_rect) -> Option<mxcfb_rect> {
+    let left = a.left.max(b.left);
+    let top = a.top.max(b.top);
+    let right = (a.left + a.width).min(b.left + b.width);
+    let bottom = (a.top + a.height).min(b.top + b.height);
+    if right <= left || bottom <= top {
+        return None;
+    }
+    Some(mxcfb_rect {
+        top,
+        left,
+        width: right - left,
+        height: bottom - top,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libremarkable::framebuffer::storage;
+
+    fn rect(left: u32, top: u32, width: u32, height: u32) -> mxcfb_rect {
+        mxcfb_rect {
+            top,
+            left,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn intersection_is_the_overlap() {
+        let overlap = intersection(&rect(0, 0, 100, 100), &rect(50, 80, 100, 100));
+        assert_eq!(overlap, Some(rect(50, 80, 50, 20)));
+    }
+
+    #[test]
+    fn rects_that_only_touch_do_not_intersect() {
+        assert_eq!(
+            intersection(&rect(0, 0, 100, 100), &rect(100, 0, 50, 50)),
+            None
+        );
+        assert!(!intersects(&rect(0, 0, 10, 10), &rect(20, 20, 10, 10)));
+    }
+
+    #[test]
+    fn undo_history_is_capped() {
+        let mut canvas = Canvas::new(rect(0, 0, 400, 400));
+        for _ in 0..UNDO_LIMIT + 5 {
+            canvas.push_edit(Edit::Drew(None));
+        }
+        assert_eq!(canvas.undo.len(), UNDO_LIMIT);
+    }
+
+    #[test]
+    fn snapshots_go_with_the_history_they_belong_to() {
+        let mut canvas = Canvas::new(rect(0, 0, 400, 400));
+        let pixels = vec![0xff; 8 * 8 * 2];
+        for _ in 0..UNDO_LIMIT + 5 {
+            let mut stroke = Stroke::new(color::BLACK, 2, Brush::Pen);
+            stroke.points = vec![(cgmath::Point2 { x: 10.0, y: 10.0 }, 2048); 3];
+            canvas.strokes.push(stroke);
+            let snapshot = storage::CompressedCanvasState::new(&pixels, 8, 8);
+            canvas.push_edit(Edit::Drew(Some((rect(10, 10, 8, 8), snapshot))));
+        }
+        let held = |canvas: &Canvas| {
+            canvas
+                .undo
+                .iter()
+                .filter(|edit| matches!(edit, Edit::Drew(Some(_))))
+                .count()
+        };
+        // Strokes past the undo limit keep their points but not their pixels
+        assert_eq!(canvas.strokes.len(), UNDO_LIMIT + 5);
+        assert_eq!(held(&canvas), UNDO_LIMIT);
+        canvas.discard_snapshots();
+        assert_eq!(held(&canvas), 0);
+    }
+
+    #[test]
+    fn a_new_edit_drops_what_could_be_redone() {
+        let mut canvas = Canvas::new(rect(0, 0, 400, 400));
+        canvas.redo.push(Undone::Erased(vec![0]));
+        canvas.selection.push(0);
+        canvas.push_edit(Edit::Drew(None));
+        assert!(canvas.redo.is_empty());
+        assert!(canvas.selection.is_empty());
+    }
+
+    #[test]
+    fn screen_and_canvas_points_round_trip_when_zoomed() {
+        let mut canvas = Canvas::new(rect(10, 20, 400, 400));
+        canvas.view = View {
+            zoom: 2.0,
+            origin: cgmath::Point2 { x: 100.0, y: 50.0 },
+        };
+        let pos = cgmath::Point2 { x: 150.0, y: 230.0 };
+        let back = canvas.to_screen(canvas.to_canvas(pos));
+        assert!((back.x - pos.x).abs() < 1e-3 && (back.y - pos.y).abs() < 1e-3);
+        // The view's origin shows in the top left corner of the inner area
+        let corner = canvas.to_screen(canvas.view.origin);
+        assert_eq!((corner.x, corner.y), (12.0, 22.0));
+    }
+
+    #[test]
+    fn lasso_picks_up_fills_inside_it() {
+        let mut canvas = Canvas::new(rect(0, 0, 400, 400));
+        let spans = (50..60).map(|y| (y, 40, 80)).collect();
+        canvas.strokes.push(Stroke::fill(color::BLACK, spans));
+        let far = (300..310).map(|y| (y, 300, 320)).collect();
+        canvas.strokes.push(Stroke::fill(color::BLACK, far));
+        let lasso = [(20.0, 20.0), (120.0, 20.0), (120.0, 120.0), (20.0, 120.0)]
+            .map(|(x, y)| cgmath::Point2 { x, y });
+        canvas.select(&lasso);
+        assert_eq!(canvas.selection, vec![0]);
+    }
+
+    #[test]
+    fn unzoomed_canvas_is_where_it_is_on_screen() {
+        let canvas = Canvas::new(rect(10, 20, 400, 400));
+        let area = rect(30, 40, 50, 60);
+        assert_eq!(canvas.on_screen(&area), area);
+    }
+}
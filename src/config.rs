@@ -0,0 +1,87 @@
+use log::{error, warn};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Settings from ~/.config/flashcards/flashcards.conf. One `key = value` per
+/// line, `#` starts a comment. Anything missing falls back to its default.
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let mut values = HashMap::new();
+        let path = Config::path();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    error!("Failed to read {0}: {1}", path.display(), err);
+                }
+                String::new()
+            }
+        };
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) => {
+                    values.insert(key.trim().to_owned(), value.trim().to_owned());
+                }
+                None => warn!(
+                    "Ignoring line {0} of {1}, expected key = value",
+                    number + 1,
+                    path.display()
+                ),
+            }
+        }
+        Config { values }
+    }
+
+    /// The value of `key`, or `default` if it isn't set or doesn't parse
+    pub fn get<T: FromStr>(&self, key: &str, default: T) -> T {
+        match self.values.get(key) {
+            None => default,
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                warn!("Ignoring invalid value for {0}: {1}", key, value);
+                default
+            }),
+        }
+    }
+
+    fn path() -> PathBuf {
+        let home = std::env::var_os("HOME").unwrap_or_else(|| "/home/root".into());
+        PathBuf::from(home).join(".config/flashcards/flashcards.conf")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pairs: &[(&str, &str)]) -> Config {
+        let values = pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Config { values }
+    }
+
+    #[test]
+    fn set_values_are_parsed() {
+        let config = config(&[("min_pressure", "120"), ("smoothing", "true")]);
+        assert_eq!(config.get("min_pressure", 0u16), 120);
+        assert!(config.get("smoothing", false));
+    }
+
+    #[test]
+    fn missing_and_invalid_values_fall_back_to_the_default() {
+        let config = config(&[("max_pen_lag_ms", "soon")]);
+        assert_eq!(config.get("max_pen_lag_ms", 60u64), 60);
+        assert_eq!(config.get("pen_up_timeout_ms", 200u64), 200);
+    }
+}
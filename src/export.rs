@@ -0,0 +1,63 @@
+use libremarkable::framebuffer::common::*;
+use libremarkable::image;
+
+use std::path::{Path, PathBuf};
+
+use crate::CONFIG;
+
+/// Where exported cards are written, to be copied off over SSH. Set with
+/// `export_dir` in the config.
+pub fn dir() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_else(|| "/home/root".into());
+    CONFIG.get("export_dir", PathBuf::from(home).join("flashcards/export"))
+}
+
+/// One past the highest card number already exported to `dir`, so exports
+/// never overwrite each other
+pub fn next_number(dir: &Path) -> u32 {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 1,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let number = name.strip_prefix("card-")?.split('-').next()?;
+            number.parse::<u32>().ok()
+        })
+        .max()
+        .map_or(1, |n| n + 1)
+}
+
+/// The file one side of card `number` is exported to, as `ext`
+pub fn path(dir: &Path, number: u32, side: &str, ext: &str) -> PathBuf {
+    dir.join(format!("card-{0:04}-{1}.{2}", number, side, ext))
+}
+
+/// Writes `buff`, a framebuffer dump of `rect`, out as a grayscale PNG
+pub fn save_png(buff: &[u8], rect: &mxcfb_rect, path: &Path) -> image::ImageResult<()> {
+    gray_image(buff, rect).save(path)
+}
+
+/// `buff`, a framebuffer dump of `rect`, encoded as a grayscale PNG
+pub fn png_bytes(buff: &[u8], rect: &mxcfb_rect) -> image::ImageResult<Vec<u8>> {
+    let mut out = Vec::new();
+    let img = gray_image(buff, rect);
+    image::png::PngEncoder::new(&mut out).encode(
+        &img,
+        rect.width,
+        rect.height,
+        image::ColorType::L8,
+    )?;
+    Ok(out)
+}
+
+fn gray_image(buff: &[u8], rect: &mxcfb_rect) -> image::GrayImage {
+    let bpp = buff.len() / (rect.width * rect.height) as usize;
+    image::GrayImage::from_fn(rect.width, rect.height, |x, y| {
+        let i = ((y * rect.width + x) as usize) * bpp;
+        let rgb = color::from_native([buff[i], buff[i + 1]]).to_rgb8();
+        image::Luma([rgb[0]])
+    })
+}
@@ -0,0 +1,181 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::FramebufferIO;
+
+use log::error;
+
+use crate::guide::Guide;
+use crate::stroke::Span;
+
+/// Finds the area of `bounds` that has the same colour as `seed` and is
+/// connected to it, as the spans a fill of it would cover. Empty if `seed`
+/// is outside `bounds` or filling with `col` would change nothing. The dots
+/// of `guide` count as blank paper.
+pub fn flood(
+    framebuffer: &mut Framebuffer,
+    bounds: &mxcfb_rect,
+    seed: cgmath::Point2<f32>,
+    col: color,
+    guide: Guide,
+) -> Vec<Span> {
+    let (width, height) = (bounds.width as usize, bounds.height as usize);
+    if seed.x < bounds.left as f32 || seed.y < bounds.top as f32 {
+        return Vec::new();
+    }
+    let (sx, sy) = (
+        seed.x as usize - bounds.left as usize,
+        seed.y as usize - bounds.top as usize,
+    );
+    if sx >= width || sy >= height {
+        return Vec::new();
+    }
+
+    match framebuffer.dump_region(*bounds) {
+        Err(err) => {
+            error!("Failed to dump fill region: {0}", err);
+            Vec::new()
+        }
+        Ok(buff) => scan(&buff, bounds, (sx, sy), col, guide),
+    }
+}
+
+/// The fill itself, over `buff` holding the pixels of `bounds`, from the seed
+/// at (`sx`, `sy`) within them
+fn scan(
+    buff: &[u8],
+    bounds: &mxcfb_rect,
+    (sx, sy): (usize, usize),
+    col: color,
+    guide: Guide,
+) -> Vec<Span> {
+    let (width, height) = (bounds.width as usize, bounds.height as usize);
+    let bpp = buff.len() / (width * height);
+    let white = color::WHITE.as_native();
+    let dot =
+        |x: usize, y: usize| guide.is_dot(bounds, x as u32 + bounds.left, y as u32 + bounds.top);
+    let raw = |x: usize, y: usize| {
+        let i = (y * width + x) * bpp;
+        [buff[i], buff[i + 1]]
+    };
+    let target = if dot(sx, sy) { white } else { raw(sx, sy) };
+    // When filling paper, ink that happens to cross a guide line right on a
+    // dot gets read as paper too. That only opens a gap in one pixel lines.
+    let pixel = |x: usize, y: usize| {
+        if target == white && dot(x, y) {
+            white
+        } else {
+            raw(x, y)
+        }
+    };
+    if let color::BLACK | color::WHITE = col {
+        if col.as_native() == target {
+            return Vec::new();
+        }
+    }
+
+    // Scanline fill: take the whole run around each seed, then seed the rows
+    // above and below once per run of matching pixels
+    let mut filled = vec![false; width * height];
+    let open =
+        |filled: &[bool], x: usize, y: usize| !filled[y * width + x] && pixel(x, y) == target;
+    let mut spans = Vec::new();
+    let mut seeds = vec![(sx, sy)];
+    while let Some((x, y)) = seeds.pop() {
+        if !open(&filled, x, y) {
+            continue;
+        }
+        let mut left = x;
+        while left > 0 && open(&filled, left - 1, y) {
+            left -= 1;
+        }
+        let mut right = x;
+        while right + 1 < width && open(&filled, right + 1, y) {
+            right += 1;
+        }
+        for done in &mut filled[y * width + left..=y * width + right] {
+            *done = true;
+        }
+        spans.push((
+            y as u32 + bounds.top,
+            left as u32 + bounds.left,
+            right as u32 + bounds.left,
+        ));
+
+        let rows = [
+            y.checked_sub(1),
+            Some(y + 1).filter(|&below| below < height),
+        ];
+        for row in rows.iter().flatten() {
+            for column in left..=right {
+                if open(&filled, column, *row)
+                    && (column == left || !open(&filled, column - 1, *row))
+                {
+                    seeds.push((column, *row));
+                }
+            }
+        }
+    }
+    spans.sort_unstable();
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: mxcfb_rect = mxcfb_rect {
+        top: 100,
+        left: 50,
+        width: 10,
+        height: 8,
+    };
+
+    /// White paper the size of BOUNDS with a black line down column 5
+    fn paper_with_line() -> Vec<u8> {
+        let mut buff = Vec::new();
+        for _ in 0..BOUNDS.height {
+            for x in 0..BOUNDS.width {
+                let col = if x == 5 { color::BLACK } else { color::WHITE };
+                buff.extend(col.as_native());
+            }
+        }
+        buff
+    }
+
+    #[test]
+    fn fill_stops_at_ink() {
+        let spans = scan(
+            &paper_with_line(),
+            &BOUNDS,
+            (1, 1),
+            color::BLACK,
+            Guide::None,
+        );
+        let expected: Vec<Span> = (100..108).map(|y| (y, 50, 54)).collect();
+        assert_eq!(spans, expected);
+    }
+
+    #[test]
+    fn fill_reaches_around_gaps() {
+        let mut buff = paper_with_line();
+        // Open the line up at the bottom row
+        let i = ((BOUNDS.height - 1) * BOUNDS.width + 5) as usize * 2;
+        buff[i..i + 2].copy_from_slice(&color::WHITE.as_native());
+        let spans = scan(&buff, &BOUNDS, (8, 0), color::BLACK, Guide::None);
+        let filled: u32 = spans.iter().map(|&(_, left, right)| right - left + 1).sum();
+        assert_eq!(filled, BOUNDS.width * BOUNDS.height - (BOUNDS.height - 1));
+    }
+
+    #[test]
+    fn filling_with_the_same_colour_changes_nothing() {
+        let spans = scan(
+            &paper_with_line(),
+            &BOUNDS,
+            (1, 1),
+            color::WHITE,
+            Guide::None,
+        );
+        assert!(spans.is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::{EuclideanSpace, InnerSpace};
+use libremarkable::input::MultitouchEvent;
+
+use std::time::{Duration, Instant};
+
+// How far a finger can drift and still count as holding still
+const SLOP_PX: f32 = 30.0;
+// Shortest travel that counts as a swipe
+const SWIPE_PX: f32 = 150.0;
+// Change in the distance between two fingers, as a fraction of where it
+// started, that makes their movement a pinch rather than a swipe
+const PINCH_RATIO: f32 = 0.2;
+const LONG_PRESS: Duration = Duration::from_millis(600);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Gesture {
+    /// Fingers put down and lifted again without moving. `pos` is where the
+    /// first one went down.
+    Tap {
+        pos: cgmath::Point2<f32>,
+        fingers: usize,
+    },
+    /// One finger held still. Reported while it's still down, and no tap
+    /// follows when it lifts.
+    LongPress { pos: cgmath::Point2<f32> },
+    /// Fingers moved together, from the middle of where they went down to the
+    /// middle of where they lifted
+    Swipe {
+        start: cgmath::Point2<f32>,
+        end: cgmath::Point2<f32>,
+        direction: Direction,
+        fingers: usize,
+    },
+    /// Two fingers moved apart or together. `scale` is how far apart they
+    /// lifted over how far apart they went down, `center` the point midway
+    /// between where they went down.
+    Pinch {
+        center: cgmath::Point2<f32>,
+        scale: f32,
+    },
+}
+
+struct Contact {
+    id: i32,
+    start: cgmath::Point2<f32>,
+    pos: cgmath::Point2<f32>,
+}
+
+impl Contact {
+    fn moved(&self) -> bool {
+        (self.pos - self.start).magnitude() > SLOP_PX
+    }
+}
+
+/// Turns raw touchscreen contacts into gestures. A gesture runs from the
+/// first finger going down to the last one lifting.
+pub struct Recognizer {
+    /// Every finger of the gesture so far, lifted ones included
+    contacts: Vec<Contact>,
+    /// How many are still down
+    down: usize,
+    started: Instant,
+    long_pressed: bool,
+}
+
+impl Recognizer {
+    pub fn new() -> Self {
+        Recognizer {
+            contacts: Vec::new(),
+            down: 0,
+            started: Instant::now(),
+            long_pressed: false,
+        }
+    }
+
+    /// Takes in a touchscreen event. Returns the gesture it completes, if
+    /// any.
+    pub fn on_event(&mut self, event: &MultitouchEvent) -> Option<Gesture> {
+        let finger = event.finger()?;
+        let pos = cgmath::Point2 {
+            x: finger.pos.x as f32,
+            y: finger.pos.y as f32,
+        };
+        let id = finger.tracking_id;
+        match event {
+            MultitouchEvent::Press { .. } => {
+                if self.down == 0 {
+                    self.contacts.clear();
+                    self.started = Instant::now();
+                    self.long_pressed = false;
+                }
+                self.down += 1;
+                self.contacts.push(Contact {
+                    id,
+                    start: pos,
+                    pos,
+                });
+                None
+            }
+            MultitouchEvent::Move { .. } => {
+                if let Some(contact) = self.contacts.iter_mut().find(|c| c.id == id) {
+                    contact.pos = pos;
+                }
+                None
+            }
+            MultitouchEvent::Release { .. } => {
+                // A release of a contact from before this gesture started
+                if !self.contacts.iter().any(|c| c.id == id) {
+                    return None;
+                }
+                self.down = self.down.saturating_sub(1);
+                match self.down {
+                    0 => self.classify(),
+                    _ => None,
+                }
+            }
+            MultitouchEvent::Unknown => None,
+        }
+    }
+
+    /// Called every so often while no events come in, for gestures made by
+    /// holding still. Returns the gesture if one was completed.
+    pub fn poll(&mut self) -> Option<Gesture> {
+        if self.down != 1
+            || self.contacts.len() != 1
+            || self.long_pressed
+            || self.contacts[0].moved()
+            || self.started.elapsed() < LONG_PRESS
+        {
+            return None;
+        }
+        self.long_pressed = true;
+        Some(Gesture::LongPress {
+            pos: self.contacts[0].start,
+        })
+    }
+
+    fn classify(&self) -> Option<Gesture> {
+        let fingers = self.contacts.len();
+        if !self.contacts.iter().any(Contact::moved) {
+            return match self.long_pressed {
+                true => None,
+                false => Some(Gesture::Tap {
+                    pos: self.contacts[0].start,
+                    fingers,
+                }),
+            };
+        }
+
+        if let [a, b] = &self.contacts[..] {
+            let before = (a.start - b.start).magnitude();
+            let after = (a.pos - b.pos).magnitude();
+            if before > 0.0 && (after / before - 1.0).abs() > PINCH_RATIO {
+                return Some(Gesture::Pinch {
+                    center: a.start.midpoint(b.start),
+                    scale: after / before,
+                });
+            }
+        }
+
+        let start =
+            cgmath::Point2::centroid(&self.contacts.iter().map(|c| c.start).collect::<Vec<_>>());
+        let end =
+            cgmath::Point2::centroid(&self.contacts.iter().map(|c| c.pos).collect::<Vec<_>>());
+        let travel = end - start;
+        if travel.magnitude() < SWIPE_PX {
+            return None;
+        }
+        let direction = match travel.x.abs() > travel.y.abs() {
+            true if travel.x < 0.0 => Direction::Left,
+            true => Direction::Right,
+            false if travel.y < 0.0 => Direction::Up,
+            false => Direction::Down,
+        };
+        Some(Gesture::Swipe {
+            start,
+            end,
+            direction,
+            fingers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32) -> cgmath::Point2<f32> {
+        cgmath::Point2 { x, y }
+    }
+
+    /// Where a finger went down and where it lifted
+    type Move = ((f32, f32), (f32, f32));
+
+    /// A recognizer whose fingers have all lifted, each having gone from
+    /// its start to its end
+    fn lifted(moves: &[Move]) -> Recognizer {
+        let mut recognizer = Recognizer::new();
+        for (id, &(start, end)) in moves.iter().enumerate() {
+            recognizer.contacts.push(Contact {
+                id: id as i32,
+                start: point(start.0, start.1),
+                pos: point(end.0, end.1),
+            });
+        }
+        recognizer
+    }
+
+    #[test]
+    fn fingers_that_stay_within_the_slop_tap() {
+        let recognizer = lifted(&[
+            ((100.0, 100.0), (110.0, 95.0)),
+            ((300.0, 100.0), (300.0, 100.0)),
+        ]);
+        assert_eq!(
+            recognizer.classify(),
+            Some(Gesture::Tap {
+                pos: point(100.0, 100.0),
+                fingers: 2
+            })
+        );
+    }
+
+    #[test]
+    fn no_tap_follows_a_long_press() {
+        let mut recognizer = lifted(&[((100.0, 100.0), (100.0, 100.0))]);
+        recognizer.long_pressed = true;
+        assert_eq!(recognizer.classify(), None);
+    }
+
+    #[test]
+    fn two_fingers_spreading_pinch() {
+        let recognizer = lifted(&[
+            ((200.0, 300.0), (100.0, 300.0)),
+            ((400.0, 300.0), (500.0, 300.0)),
+        ]);
+        assert_eq!(
+            recognizer.classify(),
+            Some(Gesture::Pinch {
+                center: point(300.0, 300.0),
+                scale: 2.0
+            })
+        );
+    }
+
+    #[test]
+    fn two_fingers_moving_together_swipe() {
+        // Their distance changes by less than the pinch ratio
+        let recognizer = lifted(&[
+            ((200.0, 300.0), (200.0, 100.0)),
+            ((400.0, 300.0), (420.0, 100.0)),
+        ]);
+        assert_eq!(
+            recognizer.classify(),
+            Some(Gesture::Swipe {
+                start: point(300.0, 300.0),
+                end: point(310.0, 100.0),
+                direction: Direction::Up,
+                fingers: 2
+            })
+        );
+    }
+
+    #[test]
+    fn swipes_need_enough_travel() {
+        let short = lifted(&[((100.0, 100.0), (100.0 + SWIPE_PX - 10.0, 100.0))]);
+        assert_eq!(short.classify(), None);
+        let long = lifted(&[((600.0, 100.0), (600.0 - SWIPE_PX - 10.0, 120.0))]);
+        assert!(matches!(
+            long.classify(),
+            Some(Gesture::Swipe {
+                direction: Direction::Left,
+                fingers: 1,
+                ..
+            })
+        ));
+    }
+}
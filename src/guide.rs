@@ -0,0 +1,84 @@
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::FramebufferIO;
+
+use log::error;
+use once_cell::sync::Lazy;
+
+use crate::canvas;
+use crate::CONFIG;
+
+// Distance between guide lines, set with `guide_spacing` in the config
+static SPACING: Lazy<u32> = Lazy::new(|| CONFIG.get("guide_spacing", 48).max(8));
+// Guide lines are dotted, one black pixel in this many. Gray lines would come
+// out white or black under the fast waveforms used while drawing.
+const DOT_GAP: u32 = 4;
+
+/// Lines dotted under the ink to help write straight. They aren't strokes:
+/// the canvas paints them back in wherever it repaints or erases, and leaves
+/// them out of saved cards.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Guide {
+    None,
+    /// Horizontal and vertical lines
+    Grid,
+    /// Horizontal lines only, like ruled paper
+    Ruled,
+}
+
+impl Guide {
+    /// The guide after this one in the toolbar cycle
+    pub fn next(self) -> Guide {
+        match self {
+            Guide::None => Guide::Grid,
+            Guide::Grid => Guide::Ruled,
+            Guide::Ruled => Guide::None,
+        }
+    }
+
+    /// Whether the pixel at `x`, `y` is one of the guide's dots, with the
+    /// lines laid out from the top left of `area`
+    pub fn is_dot(self, area: &mxcfb_rect, x: u32, y: u32) -> bool {
+        if self == Guide::None || x < area.left || y < area.top {
+            return false;
+        }
+        let (dx, dy) = (x - area.left, y - area.top);
+        let across = dy > 0 && dy % *SPACING == 0 && dx % DOT_GAP == 0;
+        let down = dx > 0 && dx % *SPACING == 0 && dy % DOT_GAP == 0;
+        across || (self == Guide::Grid && down)
+    }
+
+    /// Dots the guide in on the white pixels of `rect`, clipped to `area`
+    pub fn draw(self, framebuffer: &mut Framebuffer, area: &mxcfb_rect, rect: &mxcfb_rect) {
+        if self == Guide::None {
+            return;
+        }
+        let rect = match canvas::intersection(rect, area) {
+            Some(rect) => rect,
+            None => return,
+        };
+        let mut buff = match framebuffer.dump_region(rect) {
+            Err(err) => {
+                error!("Failed to dump guide region: {0}", err);
+                return;
+            }
+            Ok(buff) => buff,
+        };
+        let bpp = buff.len() / (rect.width * rect.height) as usize;
+        let (black, white) = (color::BLACK.as_native(), color::WHITE.as_native());
+        for y in rect.top..rect.top + rect.height {
+            for x in rect.left..rect.left + rect.width {
+                if !self.is_dot(area, x, y) {
+                    continue;
+                }
+                let i = (((y - rect.top) * rect.width + (x - rect.left)) as usize) * bpp;
+                if buff[i..i + 2] == white {
+                    buff[i..i + 2].copy_from_slice(&black);
+                }
+            }
+        }
+        if let Err(e) = framebuffer.restore_region(rect, &buff) {
+            error!("Error while restoring region: {0}", e);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use libremarkable::appctx;
+
+use log::error;
+use once_cell::sync::Lazy;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Points a user script can run at. Each runs the script in the hooks
+/// directory named after it, e.g. export.lua, with Lua's standard libraries
+/// and libremarkable's `fb` functions to hand.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Hook {
+    /// A card was exported. `card` is its number and `dir` the folder its
+    /// files went to.
+    Export,
+    /// One of the tablet's buttons was pressed. `button` is left, middle,
+    /// right or power.
+    Button,
+    /// The app is about to hand the screen back to xochitl
+    Exit,
+}
+
+const HOOKS: [(Hook, &str); 3] = [
+    (Hook::Export, "export"),
+    (Hook::Button, "button"),
+    (Hook::Exit, "exit"),
+];
+
+// Scripts are read the first time any hook fires, so edits take a restart
+static SCRIPTS: Lazy<HashMap<&'static str, String>> = Lazy::new(|| {
+    let dir = dir();
+    let mut scripts = HashMap::new();
+    for (_, name) in HOOKS.iter() {
+        let path = dir.join(format!("{0}.lua", name));
+        match std::fs::read_to_string(&path) {
+            Ok(script) => {
+                scripts.insert(*name, script);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => error!("Failed to read {0}: {1}", path.display(), err),
+        }
+    }
+    scripts
+});
+
+/// Where hook scripts are kept
+fn dir() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_else(|| "/home/root".into());
+    PathBuf::from(home).join(".config/flashcards/hooks")
+}
+
+/// Runs the script for `hook`, if there is one, with each of `args` set as a
+/// global string first. Errors in the script are logged by libremarkable.
+pub fn run(app: &mut appctx::ApplicationContext<'_>, hook: Hook, args: &[(&str, &str)]) {
+    let name = HOOKS.iter().find(|(h, _)| *h == hook).unwrap().1;
+    let script = match SCRIPTS.get(name) {
+        Some(script) => script,
+        None => return,
+    };
+    let mut code = String::new();
+    for (name, value) in args.iter() {
+        code.push_str(&format!("{0} = {1}\n", name, lua_string(value)));
+    }
+    code.push_str(script);
+    app.execute_lua(&code);
+}
+
+fn lua_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,107 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::FramebufferIO;
+
+use log::error;
+
+use crate::canvas;
+
+/// Circle outline under the hovering pen, as wide as the brush would draw.
+/// The pixels it covers are kept so that taking it off leaves the canvas as
+/// it was, lasso outlines and previews included.
+pub struct HoverMark {
+    /// Pixels under the outline, and where it's centred
+    saved: Option<(mxcfb_rect, Vec<u8>, cgmath::Point2<f32>)>,
+    /// Where the outline was taken off without a refresh yet. Putting it
+    /// back up within a pixel of there needs none at all.
+    cleared: Option<(mxcfb_rect, cgmath::Point2<f32>)>,
+}
+
+impl HoverMark {
+    pub fn new() -> Self {
+        HoverMark {
+            saved: None,
+            cleared: None,
+        }
+    }
+
+    /// Puts the outline up at `pos`, `diameter` pixels across, `thickness`
+    /// thick and clipped to `area`. Returns the area to refresh, taking in
+    /// wherever the outline was just taken off. None if nothing on the panel
+    /// needs to change, as when the pen has moved less than a pixel.
+    pub fn show(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        pos: cgmath::Point2<f32>,
+        diameter: u32,
+        thickness: u32,
+        area: &mxcfb_rect,
+    ) -> Option<mxcfb_rect> {
+        let radius = (diameter as f32 / 2.0).max(1.0);
+        let reach = radius.ceil() + thickness as f32;
+        let left = (pos.x - reach).max(0.0);
+        let top = (pos.y - reach).max(0.0);
+        let around = mxcfb_rect {
+            top: top as u32,
+            left: left as u32,
+            height: (pos.y + reach - top).ceil() as u32,
+            width: (pos.x + reach - left).ceil() as u32,
+        };
+        let rect = canvas::intersection(&around, area)?;
+        let saved = match framebuffer.dump_region(rect) {
+            Err(err) => {
+                error!("Failed to dump hover region: {0}", err);
+                return None;
+            }
+            Ok(buff) => buff,
+        };
+        let mut buff = saved.clone();
+        let bpp = buff.len() / (rect.width * rect.height) as usize;
+        let black = color::BLACK.as_native();
+        for y in rect.top..rect.top + rect.height {
+            for x in rect.left..rect.left + rect.width {
+                let offset = cgmath::vec2(x as f32 + 0.5 - pos.x, y as f32 + 0.5 - pos.y);
+                let distance = (offset.x * offset.x + offset.y * offset.y).sqrt();
+                if (distance - radius).abs() > thickness as f32 / 2.0 {
+                    continue;
+                }
+                let i = (((y - rect.top) * rect.width + (x - rect.left)) as usize) * bpp;
+                buff[i..i + 2].copy_from_slice(&black);
+            }
+        }
+        if let Err(e) = framebuffer.restore_region(rect, &buff) {
+            error!("Error while restoring region: {0}", e);
+            return None;
+        }
+        self.saved = Some((rect, saved, pos));
+        match self.cleared.take() {
+            Some((before, at)) if before == rect && (pos - at).magnitude() < 1.0 => None,
+            Some((before, _)) => Some(before.merge_rect(&rect)),
+            None => Some(rect),
+        }
+    }
+
+    /// Takes the outline off again, if it's up. The refresh is left for
+    /// `show` to fold into its own, or for `take_cleared`.
+    pub fn clear(&mut self, framebuffer: &mut Framebuffer) {
+        let (rect, saved, pos) = match self.saved.take() {
+            Some(saved) => saved,
+            None => return,
+        };
+        if let Err(e) = framebuffer.restore_region(rect, &saved) {
+            error!("Error while restoring region: {0}", e);
+        }
+        self.cleared = Some(match self.cleared {
+            Some((before, _)) => (before.merge_rect(&rect), pos),
+            None => (rect, pos),
+        });
+    }
+
+    /// Where the outline was taken off and not put back up, still to be
+    /// refreshed
+    pub fn take_cleared(&mut self) -> Option<mxcfb_rect> {
+        self.cleared.take().map(|(rect, _)| rect)
+    }
+}
@@ -0,0 +1,70 @@
+use log::error;
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::thread::sleep;
+use std::time::Duration;
+
+const LOCK_PATH: &str = "/tmp/flashcards.lock";
+
+/// Held for the lifetime of the process. The advisory lock goes away with the
+/// file descriptor, so a crashed instance never leaves a stale lock behind.
+pub struct InstanceLock {
+    _file: Option<File>,
+}
+
+impl InstanceLock {
+    /// Tries to become the only running instance. On failure, returns the pid
+    /// of the instance holding the lock if it could be read.
+    pub fn acquire() -> Result<InstanceLock, Option<i32>> {
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(LOCK_PATH)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Failed to open {0}, running unlocked: {1}", LOCK_PATH, err);
+                return Ok(InstanceLock { _file: None });
+            }
+        };
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let mut contents = String::new();
+            let _ = file.read_to_string(&mut contents);
+            return Err(contents.trim().parse().ok());
+        }
+
+        // Only truncate once we own the lock, the holder's pid lives in here
+        let _ = file.set_len(0);
+        let _ = file.seek(SeekFrom::Start(0));
+        let _ = write!(file, "{}", std::process::id());
+        Ok(InstanceLock { _file: Some(file) })
+    }
+
+    /// Asks the instance holding the lock to exit, killing it if it doesn't
+    /// let go within a few seconds. Blocks the calling thread for up to
+    /// about 3.2s. It's run from the take-over button on the UI thread, which
+    /// is fine: only the prompt is up, and the other instance is still
+    /// drawing on the screen until it lets go.
+    pub fn take_over(holder: Option<i32>) -> Option<InstanceLock> {
+        if let Some(pid) = holder {
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+        }
+        for _ in 0..30 {
+            if let Ok(lock) = InstanceLock::acquire() {
+                return Some(lock);
+            }
+            sleep(Duration::from_millis(100));
+        }
+
+        if let Some(pid) = holder {
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+            sleep(Duration::from_millis(200));
+        }
+        InstanceLock::acquire().ok()
+    }
+}
@@ -0,0 +1,242 @@
+use evdev::{Device, InputEventKind, Key};
+use log::error;
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+/// A key typed on an external keyboard, after the modifiers were applied
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum KeyPress {
+    Char(char),
+    Backspace,
+    Enter,
+    Escape,
+}
+
+/// Keyboards plugged in over USB or paired over Bluetooth, like the Type
+/// Folio. Each one is read on a thread of its own and its key presses come
+/// through here.
+pub struct Keyboards {
+    /// Devices already being read
+    paths: Vec<PathBuf>,
+    tx: Sender<KeyPress>,
+    rx: Receiver<KeyPress>,
+    /// Devices whose thread has stopped reading them, as when unplugged
+    gone_tx: Sender<PathBuf>,
+    gone_rx: Receiver<PathBuf>,
+}
+
+impl Keyboards {
+    pub fn new() -> Self {
+        let (tx, rx) = channel();
+        let (gone_tx, gone_rx) = channel();
+        Keyboards {
+            paths: Vec::new(),
+            tx,
+            rx,
+            gone_tx,
+            gone_rx,
+        }
+    }
+
+    /// Starts reading any keyboards connected since the last scan
+    pub fn scan(&mut self) {
+        self.forget_gone();
+        for (path, device) in evdev::enumerate() {
+            if self.paths.contains(&path) || !is_keyboard(&device) {
+                continue;
+            }
+            self.paths.push(path.clone());
+            let tx = self.tx.clone();
+            let gone_tx = self.gone_tx.clone();
+            thread::spawn(move || {
+                read_keys(&path, device, tx);
+                let _ = gone_tx.send(path);
+            });
+        }
+    }
+
+    pub fn connected(&mut self) -> bool {
+        self.forget_gone();
+        !self.paths.is_empty()
+    }
+
+    /// Drops the devices no longer being read, so that they count as
+    /// disconnected and are picked up again by the next scan if they're back
+    fn forget_gone(&mut self) {
+        while let Ok(path) = self.gone_rx.try_recv() {
+            self.paths.retain(|p| *p != path);
+        }
+    }
+
+    /// The next key typed, if one is waiting
+    pub fn try_recv(&self) -> Option<KeyPress> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/// The buttons and digitizers report keys too, but no letters
+fn is_keyboard(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(Key::KEY_A) && keys.contains(Key::KEY_ENTER))
+}
+
+fn read_keys(path: &Path, mut device: Device, tx: Sender<KeyPress>) {
+    let mut modifiers = Modifiers::default();
+    loop {
+        let events = match device.fetch_events() {
+            Err(err) => {
+                // Unplugged or out of range
+                error!("Failed to read keyboard {0}: {1}", path.display(), err);
+                return;
+            }
+            Ok(events) => events,
+        };
+        for event in events {
+            let key = match event.kind() {
+                InputEventKind::Key(key) => key,
+                _ => continue,
+            };
+            // 0 is a release, 1 a press and 2 a held key repeating
+            let down = event.value() != 0;
+            match key {
+                Key::KEY_LEFTSHIFT => modifiers.left_shift = down,
+                Key::KEY_RIGHTSHIFT => modifiers.right_shift = down,
+                Key::KEY_RIGHTALT => modifiers.altgr = down,
+                Key::KEY_CAPSLOCK if event.value() == 1 => {
+                    modifiers.caps_lock = !modifiers.caps_lock
+                }
+                _ if down => {
+                    if let Some(press) = translate(key, &modifiers) {
+                        if tx.send(press).is_err() {
+                            return;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Modifiers {
+    left_shift: bool,
+    right_shift: bool,
+    /// The right alt key, for the accented letters
+    altgr: bool,
+    caps_lock: bool,
+}
+
+impl Modifiers {
+    fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+}
+
+/// Maps a key to what it types on a US layout. AltGr adds the accented
+/// letters of the US international layout.
+fn translate(key: Key, modifiers: &Modifiers) -> Option<KeyPress> {
+    match key {
+        Key::KEY_BACKSPACE => return Some(KeyPress::Backspace),
+        Key::KEY_ENTER | Key::KEY_KPENTER => return Some(KeyPress::Enter),
+        Key::KEY_ESC => return Some(KeyPress::Escape),
+        Key::KEY_SPACE => return Some(KeyPress::Char(' ')),
+        _ => {}
+    }
+    if let Some(letter) = letter(key) {
+        let letter = match modifiers.altgr {
+            true => accented(letter)?,
+            false => letter,
+        };
+        // Caps lock only reaches the letters, and shift undoes it
+        return match modifiers.shift() != modifiers.caps_lock {
+            true => Some(KeyPress::Char(letter.to_uppercase().next()?)),
+            false => Some(KeyPress::Char(letter)),
+        };
+    }
+    let (plain, shifted) = symbol(key)?;
+    match modifiers.shift() {
+        true => Some(KeyPress::Char(shifted)),
+        false => Some(KeyPress::Char(plain)),
+    }
+}
+
+fn letter(key: Key) -> Option<char> {
+    let letter = match key {
+        Key::KEY_A => 'a',
+        Key::KEY_B => 'b',
+        Key::KEY_C => 'c',
+        Key::KEY_D => 'd',
+        Key::KEY_E => 'e',
+        Key::KEY_F => 'f',
+        Key::KEY_G => 'g',
+        Key::KEY_H => 'h',
+        Key::KEY_I => 'i',
+        Key::KEY_J => 'j',
+        Key::KEY_K => 'k',
+        Key::KEY_L => 'l',
+        Key::KEY_M => 'm',
+        Key::KEY_N => 'n',
+        Key::KEY_O => 'o',
+        Key::KEY_P => 'p',
+        Key::KEY_Q => 'q',
+        Key::KEY_R => 'r',
+        Key::KEY_S => 's',
+        Key::KEY_T => 't',
+        Key::KEY_U => 'u',
+        Key::KEY_V => 'v',
+        Key::KEY_W => 'w',
+        Key::KEY_X => 'x',
+        Key::KEY_Y => 'y',
+        Key::KEY_Z => 'z',
+        _ => return None,
+    };
+    Some(letter)
+}
+
+fn accented(letter: char) -> Option<char> {
+    match letter {
+        'a' => Some('á'),
+        'e' => Some('é'),
+        'i' => Some('í'),
+        'o' => Some('ó'),
+        'u' => Some('ú'),
+        'y' => Some('ü'),
+        'n' => Some('ñ'),
+        'c' => Some('ç'),
+        's' => Some('ß'),
+        _ => None,
+    }
+}
+
+/// What the digit and punctuation keys type, without and with shift
+fn symbol(key: Key) -> Option<(char, char)> {
+    let pair = match key {
+        Key::KEY_1 => ('1', '!'),
+        Key::KEY_2 => ('2', '@'),
+        Key::KEY_3 => ('3', '#'),
+        Key::KEY_4 => ('4', '$'),
+        Key::KEY_5 => ('5', '%'),
+        Key::KEY_6 => ('6', '^'),
+        Key::KEY_7 => ('7', '&'),
+        Key::KEY_8 => ('8', '*'),
+        Key::KEY_9 => ('9', '('),
+        Key::KEY_0 => ('0', ')'),
+        Key::KEY_MINUS => ('-', '_'),
+        Key::KEY_EQUAL => ('=', '+'),
+        Key::KEY_LEFTBRACE => ('[', '{'),
+        Key::KEY_RIGHTBRACE => (']', '}'),
+        Key::KEY_BACKSLASH => ('\\', '|'),
+        Key::KEY_SEMICOLON => (';', ':'),
+        Key::KEY_APOSTROPHE => ('\'', '"'),
+        Key::KEY_GRAVE => ('`', '~'),
+        Key::KEY_COMMA => (',', '<'),
+        Key::KEY_DOT => ('.', '>'),
+        Key::KEY_SLASH => ('/', '?'),
+        _ => return None,
+    };
+    Some(pair)
+}
@@ -0,0 +1,453 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::{FramebufferDraw, FramebufferIO};
+
+use log::error;
+
+use crate::canvas::{self, Canvas};
+
+// How far down and to the right a duplicate lands from the original
+const DUPLICATE_OFFSET: i32 = 24;
+// Size of the square in the bottom right corner of a selection that scales
+// it when dragged
+const HANDLE_SIZE: u32 = 24;
+const OUTLINE_PX: u32 = 2;
+const DASH_PX: u32 = 8;
+
+enum State {
+    Idle,
+    /// Drawing the loop around the strokes to select
+    Looping(Vec<cgmath::Point2<f32>>),
+    /// Dragging the selection from where the pen went down
+    Moving(cgmath::Point2<f32>),
+    /// Dragging the scale handle from where the pen went down
+    Scaling(cgmath::Point2<f32>),
+}
+
+/// Lasso tool: draw a loop around strokes to select them, then drag inside
+/// the selection to move it or drag its corner handle to scale it
+pub struct Lasso {
+    state: State,
+    outline: Outline,
+    /// Where the pen was last seen, as pen-up carries no position
+    last_pos: cgmath::Point2<f32>,
+}
+
+impl Default for Lasso {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lasso {
+    pub fn new() -> Self {
+        Lasso {
+            state: State::Idle,
+            outline: Outline { saved: Vec::new() },
+            last_pos: cgmath::Point2 { x: 0.0, y: 0.0 },
+        }
+    }
+
+    /// Pen touching the canvas at `pos`. Returns the area to refresh.
+    pub fn on_draw(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+        pos: cgmath::Point2<f32>,
+    ) -> Option<mxcfb_rect> {
+        self.last_pos = pos;
+        match self.state {
+            State::Idle => {
+                let selection = canvas.selection_rect();
+                match selection {
+                    Some(rect) if contains(&handle_rect(&rect), pos) => {
+                        self.state = State::Scaling(pos);
+                        None
+                    }
+                    Some(rect) if contains(&rect, pos) => {
+                        self.state = State::Moving(pos);
+                        None
+                    }
+                    _ => {
+                        let cleared = self.cancel(framebuffer, canvas);
+                        self.state = State::Looping(vec![pos]);
+                        cleared
+                    }
+                }
+            }
+            State::Looping(ref mut points) => {
+                let last = *points.last().unwrap();
+                points.push(pos);
+                Some(framebuffer.draw_line(
+                    last.cast().unwrap(),
+                    pos.cast().unwrap(),
+                    1,
+                    color::BLACK,
+                ))
+            }
+            State::Moving(anchor) | State::Scaling(anchor) => {
+                let scaling = matches!(self.state, State::Scaling(_));
+                let selection = canvas.selection_rect()?;
+                let (map, _) = drag_transform(canvas, selection, scaling, anchor, pos);
+                let target = map_rect(&selection, &map);
+                let lifted = self.outline.clear(framebuffer);
+                let shown = self.outline.show(framebuffer, &canvas.inner(), target);
+                Some(lifted.merge_rect(&shown))
+            }
+        }
+    }
+
+    /// Pen lifted. Finishes the loop or commits the drag.
+    pub fn on_pen_up(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+    ) -> Option<mxcfb_rect> {
+        let pos = self.last_pos;
+        let scaling = matches!(self.state, State::Scaling(_));
+        match std::mem::replace(&mut self.state, State::Idle) {
+            State::Idle => None,
+            State::Looping(points) => {
+                // Repainting the area the loop crossed wipes the loop itself
+                let loop_rect = points_rect(&points);
+                let mut rect = canvas.repaint(framebuffer, loop_rect);
+                if let Some(selection) = canvas.select(&points) {
+                    let shown = self.outline.show(framebuffer, &canvas.inner(), selection);
+                    rect = rect.merge_rect(&shown);
+                }
+                Some(rect)
+            }
+            State::Moving(anchor) | State::Scaling(anchor) => {
+                let selection = canvas.selection_rect()?;
+                let (map, scale) = drag_transform(canvas, selection, scaling, anchor, pos);
+                let lifted = self.outline.clear(framebuffer);
+                let repainted = canvas.transform_selection(framebuffer, map, scale)?;
+                let shown = match canvas.selection_rect() {
+                    Some(selection) => self.outline.show(framebuffer, &canvas.inner(), selection),
+                    None => mxcfb_rect::invalid(),
+                };
+                Some(lifted.merge_rect(&repainted).merge_rect(&shown))
+            }
+        }
+    }
+
+    pub fn delete(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+    ) -> Option<mxcfb_rect> {
+        let lifted = self.outline.clear(framebuffer);
+        let deleted = canvas.delete_selection(framebuffer)?;
+        Some(lifted.merge_rect(&deleted))
+    }
+
+    /// Stamps a copy of the selection a little way off and selects the copy,
+    /// ready to be dragged where it's wanted
+    pub fn duplicate(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+    ) -> Option<mxcfb_rect> {
+        self.state = State::Idle;
+        let selection = canvas.selection_rect()?;
+        let inner = canvas.inner();
+        // Go up and left instead where the copy would run off the canvas
+        let dx = match selection.left + selection.width + DUPLICATE_OFFSET as u32
+            > inner.left + inner.width
+        {
+            true => -DUPLICATE_OFFSET,
+            false => DUPLICATE_OFFSET,
+        };
+        let dy = match selection.top + selection.height + DUPLICATE_OFFSET as u32
+            > inner.top + inner.height
+        {
+            true => -DUPLICATE_OFFSET,
+            false => DUPLICATE_OFFSET,
+        };
+
+        let lifted = self.outline.clear(framebuffer);
+        let pasted = canvas.duplicate_selection(framebuffer, dx, dy);
+        let shown = match canvas.selection_rect() {
+            Some(selection) => self.outline.show(framebuffer, &canvas.inner(), selection),
+            None => mxcfb_rect::invalid(),
+        };
+        let pasted = pasted.unwrap_or_else(mxcfb_rect::invalid);
+        Some(lifted.merge_rect(&pasted).merge_rect(&shown))
+    }
+
+    /// Turns the selection a quarter turn clockwise about its middle
+    pub fn rotate(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+    ) -> Option<mxcfb_rect> {
+        self.turn(framebuffer, canvas, |v| cgmath::vec2(-v.y, v.x))
+    }
+
+    /// Flips the selection left to right
+    pub fn mirror(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+    ) -> Option<mxcfb_rect> {
+        self.turn(framebuffer, canvas, |v| cgmath::vec2(-v.x, v.y))
+    }
+
+    /// Moves every point of the selection through `turn`, taken about the
+    /// selection's middle, then shifts it back inside the canvas if it now
+    /// runs over an edge
+    fn turn<F>(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+        turn: F,
+    ) -> Option<mxcfb_rect>
+    where
+        F: Fn(cgmath::Vector2<f32>) -> cgmath::Vector2<f32>,
+    {
+        self.state = State::Idle;
+        let selection = canvas.selection_rect()?;
+        let inner = canvas.inner();
+        let center = cgmath::Point2 {
+            x: selection.left as f32 + selection.width as f32 / 2.0,
+            y: selection.top as f32 + selection.height as f32 / 2.0,
+        };
+        let size = turn(cgmath::vec2(
+            selection.width as f32,
+            selection.height as f32,
+        ));
+        let (width, height) = (size.x.abs(), size.y.abs());
+        let left = center.x - width / 2.0;
+        let top = center.y - height / 2.0;
+        // Kept inside the canvas, against its left and top edges should it
+        // come out wider or taller
+        let (min_left, min_top) = (inner.left as f32, inner.top as f32);
+        let max_left = ((inner.left + inner.width) as f32 - width).max(min_left);
+        let max_top = ((inner.top + inner.height) as f32 - height).max(min_top);
+        let offset = cgmath::vec2(
+            left.clamp(min_left, max_left) - left,
+            top.clamp(min_top, max_top) - top,
+        );
+        let map = move |p: cgmath::Point2<f32>| center + turn(p - center) + offset;
+
+        let lifted = self.outline.clear(framebuffer);
+        let repainted = canvas.transform_selection(framebuffer, map, 1.0)?;
+        let shown = match canvas.selection_rect() {
+            Some(selection) => self.outline.show(framebuffer, &canvas.inner(), selection),
+            None => mxcfb_rect::invalid(),
+        };
+        Some(lifted.merge_rect(&repainted).merge_rect(&shown))
+    }
+
+    /// Drops the selection and takes its outline off the canvas
+    pub fn cancel(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+    ) -> Option<mxcfb_rect> {
+        self.state = State::Idle;
+        canvas.clear_selection();
+        let lifted = self.outline.clear(framebuffer);
+        if lifted == mxcfb_rect::invalid() {
+            None
+        } else {
+            Some(lifted)
+        }
+    }
+}
+
+/// Works out where a drag from `anchor` to `pos` puts the selection,
+/// keeping it within the canvas. Returns the point mapping and the scale.
+fn drag_transform(
+    canvas: &Canvas,
+    selection: mxcfb_rect,
+    scaling: bool,
+    anchor: cgmath::Point2<f32>,
+    pos: cgmath::Point2<f32>,
+) -> (impl Fn(cgmath::Point2<f32>) -> cgmath::Point2<f32>, f32) {
+    let inner = canvas.inner();
+    let origin = cgmath::Point2 {
+        x: selection.left as f32,
+        y: selection.top as f32,
+    };
+    let size = cgmath::vec2(selection.width as f32, selection.height as f32);
+
+    let (offset, scale) = if scaling {
+        let grabbed = (anchor - origin).magnitude().max(1.0);
+        let fit_x = (inner.left + inner.width) as f32 - origin.x;
+        let fit_y = (inner.top + inner.height) as f32 - origin.y;
+        let max_scale = (fit_x / size.x).min(fit_y / size.y);
+        let scale = ((pos - origin).magnitude() / grabbed).clamp(max_scale.min(0.1), max_scale);
+        (cgmath::vec2(0.0, 0.0), scale)
+    } else {
+        let delta = pos - anchor;
+        // Short of the right and bottom edges wins should the selection
+        // already hang past the canvas
+        let max_dx = (inner.left + inner.width) as f32 - origin.x - size.x;
+        let max_dy = (inner.top + inner.height) as f32 - origin.y - size.y;
+        let dx = delta
+            .x
+            .clamp((inner.left as f32 - origin.x).min(max_dx), max_dx);
+        let dy = delta
+            .y
+            .clamp((inner.top as f32 - origin.y).min(max_dy), max_dy);
+        (cgmath::vec2(dx, dy), 1.0)
+    };
+    let map = move |p: cgmath::Point2<f32>| origin + (p - origin) * scale + offset;
+    (map, scale)
+}
+
+/// Dashed selection outline with a scale handle. Remembers the strips of
+/// canvas underneath so it can be lifted off without repainting the canvas.
+struct Outline {
+    saved: Vec<(mxcfb_rect, Vec<u8>)>,
+}
+
+impl Outline {
+    fn show(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        bounds: &mxcfb_rect,
+        rect: mxcfb_rect,
+    ) -> mxcfb_rect {
+        let right = rect.left + rect.width;
+        let bottom = rect.top + rect.height;
+        let handle = handle_rect(&rect);
+        let strips = [
+            (rect.left, rect.top, rect.width, OUTLINE_PX),
+            (
+                rect.left,
+                bottom.saturating_sub(OUTLINE_PX),
+                rect.width,
+                OUTLINE_PX,
+            ),
+            (rect.left, rect.top, OUTLINE_PX, rect.height),
+            (
+                right.saturating_sub(OUTLINE_PX),
+                rect.top,
+                OUTLINE_PX,
+                rect.height,
+            ),
+            (handle.left, handle.top, handle.width, handle.height),
+        ];
+
+        let mut drawn = mxcfb_rect::invalid();
+        for &(left, top, width, height) in strips.iter() {
+            let strip = mxcfb_rect {
+                top,
+                left,
+                width,
+                height,
+            };
+            let strip = match canvas::intersection(&strip, bounds) {
+                Some(strip) => strip,
+                None => continue,
+            };
+            match framebuffer.dump_region(strip) {
+                Err(err) => error!("Failed to dump outline region: {0}", err),
+                Ok(buff) => self.saved.push((strip, buff)),
+            }
+            drawn = drawn.merge_rect(&strip);
+        }
+
+        // Dashes along the edges, then the solid handle
+        for &(left, top, width, height) in strips[..4].iter() {
+            let horizontal = width > height;
+            let length = if horizontal { width } else { height };
+            let mut along = 0;
+            while along < length {
+                let dash = DASH_PX.min(length - along);
+                let (x, y, w, h) = if horizontal {
+                    (left + along, top, dash, height)
+                } else {
+                    (left, top + along, width, dash)
+                };
+                if let Some(dash) = canvas::intersection(
+                    &mxcfb_rect {
+                        top: y,
+                        left: x,
+                        width: w,
+                        height: h,
+                    },
+                    bounds,
+                ) {
+                    framebuffer.fill_rect(
+                        dash.top_left().cast().unwrap(),
+                        dash.size(),
+                        color::BLACK,
+                    );
+                }
+                along += 2 * DASH_PX;
+            }
+        }
+        if let Some(handle) = canvas::intersection(&handle, bounds) {
+            framebuffer.fill_rect(
+                handle.top_left().cast().unwrap(),
+                handle.size(),
+                color::BLACK,
+            );
+        }
+        drawn
+    }
+
+    /// Puts back what was under the outline. Returns the area restored.
+    fn clear(&mut self, framebuffer: &mut Framebuffer) -> mxcfb_rect {
+        let mut restored = mxcfb_rect::invalid();
+        for (rect, buff) in self.saved.drain(..).rev() {
+            if let Err(e) = framebuffer.restore_region(rect, &buff) {
+                error!("Error while restoring region: {0}", e);
+            }
+            restored = restored.merge_rect(&rect);
+        }
+        restored
+    }
+}
+
+fn handle_rect(selection: &mxcfb_rect) -> mxcfb_rect {
+    mxcfb_rect {
+        top: (selection.top + selection.height).saturating_sub(HANDLE_SIZE / 2),
+        left: (selection.left + selection.width).saturating_sub(HANDLE_SIZE / 2),
+        width: HANDLE_SIZE,
+        height: HANDLE_SIZE,
+    }
+}
+
+fn contains(rect: &mxcfb_rect, pos: cgmath::Point2<f32>) -> bool {
+    pos.x >= rect.left as f32
+        && pos.y >= rect.top as f32
+        && pos.x < (rect.left + rect.width) as f32
+        && pos.y < (rect.top + rect.height) as f32
+}
+
+fn map_rect<F>(rect: &mxcfb_rect, map: &F) -> mxcfb_rect
+where
+    F: Fn(cgmath::Point2<f32>) -> cgmath::Point2<f32>,
+{
+    let top_left = map(cgmath::Point2 {
+        x: rect.left as f32,
+        y: rect.top as f32,
+    });
+    let bottom_right = map(cgmath::Point2 {
+        x: (rect.left + rect.width) as f32,
+        y: (rect.top + rect.height) as f32,
+    });
+    mxcfb_rect {
+        top: top_left.y.max(0.0) as u32,
+        left: top_left.x.max(0.0) as u32,
+        width: (bottom_right.x - top_left.x).max(1.0) as u32,
+        height: (bottom_right.y - top_left.y).max(1.0) as u32,
+    }
+}
+
+fn points_rect(points: &[cgmath::Point2<f32>]) -> mxcfb_rect {
+    points.iter().fold(mxcfb_rect::invalid(), |acc, p| {
+        acc.merge_rect(&mxcfb_rect {
+            top: (p.y as u32).saturating_sub(2),
+            left: (p.x as u32).saturating_sub(2),
+            width: 5,
+            height: 5,
+        })
+    })
+}
@@ -0,0 +1,105 @@
+use libremarkable::{end_bench, start_bench};
+use once_cell::sync::Lazy;
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "enable-runtime-benchmarking")]
+use libremarkable::stopwatch;
+
+/// Steps between a pen report coming in and its ink going to the display
+#[derive(Copy, Clone, PartialEq)]
+pub enum Stage {
+    /// All of on_wacom_input for one report
+    Event,
+    /// Fitting a bezier segment and writing it to the framebuffer
+    Segment,
+    /// Submitting the partial refresh for a batch of segments
+    Refresh,
+}
+
+const STAGES: [(Stage, &str); 3] = [
+    (Stage::Event, "event"),
+    (Stage::Segment, "bezier + framebuffer write"),
+    (Stage::Refresh, "refresh submit"),
+];
+
+// Histogram buckets, each twice the last, from 50us up
+const FIRST_BUCKET_US: u64 = 50;
+const BUCKETS: usize = 12;
+
+// How long each stage took, while `--bench-latency` runs. None otherwise, so
+// timing costs nothing in normal use beyond reading the clock.
+static SAMPLES: Lazy<Mutex<Option<Samples>>> = Lazy::new(|| Mutex::new(None));
+type Samples = Vec<(Stage, Duration)>;
+
+/// Runs the benchmark in `f`, keeping the time every stage takes, then
+/// prints them. With runtime benchmarking on, the whole run is timed too.
+pub fn run(f: impl FnOnce()) {
+    *SAMPLES.lock().unwrap() = Some(Vec::new());
+    start_bench!(stopwatch, bench_latency);
+    f();
+    end_bench!(bench_latency);
+    report();
+}
+
+/// Runs `f`, noting how long it took as `stage` if a benchmark is running
+pub fn timed<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    record(stage, started.elapsed());
+    result
+}
+
+pub fn record(stage: Stage, took: Duration) {
+    if let Some(samples) = SAMPLES.lock().unwrap().as_mut() {
+        samples.push((stage, took));
+    }
+}
+
+/// Prints percentiles and a histogram for each stage
+fn report() {
+    let samples = SAMPLES.lock().unwrap().take().unwrap_or_default();
+    for (stage, name) in STAGES.iter() {
+        let mut times: Vec<u64> = samples
+            .iter()
+            .filter(|(s, _)| s == stage)
+            .map(|(_, took)| took.as_micros() as u64)
+            .collect();
+        if times.is_empty() {
+            println!("{0}: no samples", name);
+            continue;
+        }
+        times.sort_unstable();
+        let at = |p: usize| times[(times.len() - 1) * p / 100];
+        println!(
+            "{0}: {1} samples, p50 {2}us, p90 {3}us, p99 {4}us, max {5}us",
+            name,
+            times.len(),
+            at(50),
+            at(90),
+            at(99),
+            times[times.len() - 1]
+        );
+        let mut counts = [0usize; BUCKETS];
+        for &time in times.iter() {
+            let mut bucket = 0;
+            while bucket + 1 < BUCKETS && time >= FIRST_BUCKET_US << bucket {
+                bucket += 1;
+            }
+            counts[bucket] += 1;
+        }
+        let most = *counts.iter().max().unwrap();
+        for (bucket, &count) in counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let bar = "#".repeat((count * 40).div_ceil(most));
+            let label = match bucket + 1 < BUCKETS {
+                true => format!("< {0}us", FIRST_BUCKET_US << bucket),
+                false => format!(">= {0}us", FIRST_BUCKET_US << (bucket - 1)),
+            };
+            println!("  {0:>10} {1:>5} {2}", label, count, bar);
+        }
+    }
+}
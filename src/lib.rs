@@ -0,0 +1,21 @@
+// The card model and everything that draws it, kept apart from the app's
+// input handling and UI so it can be built and tried out on a desktop and
+// shared with command line tools.
+pub mod canvas;
+pub mod config;
+#[cfg(feature = "export")]
+pub mod export;
+pub mod fill;
+pub mod guide;
+pub mod lasso;
+pub mod notebook;
+pub mod picture;
+pub mod shapes;
+pub mod smoothing;
+pub mod stamps;
+pub mod stroke;
+
+use config::Config;
+use once_cell::sync::Lazy;
+
+pub static CONFIG: Lazy<Config> = Lazy::new(Config::load);
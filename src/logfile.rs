@@ -0,0 +1,115 @@
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::CONFIG;
+
+// Once the log grows past this it's moved aside to flashcards.log.1, replacing
+// the one before, so the two together never take more than twice this
+const MAX_LOG_BYTES: u64 = 512 * 1024;
+// After the log fails to open, as on a read-only home, it's tried again only
+// this often rather than on every line
+const RETRY_OPEN: Duration = Duration::from_secs(60);
+
+/// Writes log lines to a file on the device, as well as to stderr for when
+/// the app is started over ssh
+struct FileLogger {
+    /// The open log and how much has been written to it
+    file: Mutex<Option<(File, u64)>>,
+    /// When the log last failed to open
+    failed: Mutex<Option<Instant>>,
+}
+
+static LOGGER: FileLogger = FileLogger {
+    file: Mutex::new(None),
+    failed: Mutex::new(None),
+};
+
+/// Where the log is kept
+pub fn path() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_else(|| "/home/root".into());
+    PathBuf::from(home).join(".local/share/flashcards/flashcards.log")
+}
+
+/// Starts logging. The level is `RUST_LOG` if it's set to one, as `make run`
+/// does, and otherwise `log_level` in the config.
+pub fn init() {
+    if let Err(err) = log::set_logger(&LOGGER) {
+        eprintln!("Failed to start logging: {0}", err);
+        return;
+    }
+    // Reading the config can log, so that's left until the logger is in
+    log::set_max_level(LevelFilter::Info);
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or_else(|| CONFIG.get("log_level", LevelFilter::Info));
+    log::set_max_level(level);
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{0} {1:<5} {2}: {3}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        eprint!("{0}", line);
+
+        let mut file = self.file.lock().unwrap();
+        if file.as_ref().is_none_or(|(_, len)| *len >= MAX_LOG_BYTES) {
+            let mut failed = self.failed.lock().unwrap();
+            if failed.is_none_or(|at| at.elapsed() >= RETRY_OPEN) {
+                *file = open(file.is_some());
+                *failed = file.is_none().then(Instant::now);
+            }
+        }
+        if let Some((log, len)) = file.as_mut() {
+            // Nowhere left to report a failed write, so it's dropped
+            if log.write_all(line.as_bytes()).is_ok() {
+                *len += line.len() as u64;
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((log, _)) = self.file.lock().unwrap().as_mut() {
+            let _ = log.flush();
+        }
+    }
+}
+
+/// Opens the log to append to, first moving the full one aside if `rotate`
+fn open(rotate: bool) -> Option<(File, u64)> {
+    let path = path();
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if rotate {
+        let _ = std::fs::rename(&path, path.with_extension("log.1"));
+    }
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(file) => {
+            let len = file.metadata().map_or(0, |m| m.len());
+            Some((file, len))
+        }
+        Err(err) => {
+            eprintln!("Failed to open {0}: {1}", path.display(), err);
+            None
+        }
+    }
+}
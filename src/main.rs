@@ -1,49 +1,100 @@
+#[cfg(feature = "anki")]
+mod anki;
+mod backlog;
+mod gesture;
+mod hooks;
+mod hover;
+mod instance;
+mod keyboard;
+mod latency;
+mod logfile;
+mod palm;
+mod record;
+mod refresh;
+
+use libremarkable::dimensions::{DISPLAYHEIGHT, DISPLAYWIDTH};
 use libremarkable::framebuffer::cgmath;
-use libremarkable::framebuffer::cgmath::EuclideanSpace;
+use libremarkable::framebuffer::cgmath::InnerSpace;
 use libremarkable::framebuffer::common::*;
-use libremarkable::framebuffer::storage;
-use libremarkable::framebuffer::PartialRefreshMode;
-use libremarkable::framebuffer::{FramebufferDraw, FramebufferIO, FramebufferRefresh};
+use libremarkable::framebuffer::FramebufferDraw;
 use libremarkable::image::GenericImage;
 use libremarkable::input::{gpio, multitouch, wacom, InputDevice, InputEvent};
 use libremarkable::ui_extensions::element::{
-    UIConstraintRefresh, UIElement, UIElementHandle, UIElementWrapper,
+    ActiveRegionFunction, UIConstraintRefresh, UIElement, UIElementHandle, UIElementWrapper,
 };
-use libremarkable::{appctx, battery, image, input};
-use libremarkable::{end_bench, start_bench};
-
-#[cfg(feature = "enable-runtime-benchmarking")]
-use libremarkable::stopwatch;
+use libremarkable::{appctx, battery, device, image, input};
 
 use atomic::Atomic;
-use chrono::{DateTime, Local};
-use log::info;
+use chrono::DateTime;
+#[cfg(feature = "anki")]
+use chrono::Local;
+use log::{debug, error, info, warn};
 use once_cell::sync::Lazy;
 
 use std::collections::VecDeque;
 use std::fmt;
+use std::path::PathBuf;
 use std::process::Command;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Mutex;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Mutex, MutexGuard};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "export")]
+use flashcards_core::export;
+#[cfg(any(feature = "export", feature = "import"))]
+use flashcards_core::notebook;
+use flashcards_core::{canvas, guide, lasso, picture};
+use flashcards_core::{shapes, smoothing, stamps, stroke, CONFIG};
+
+use backlog::Backlog;
+use canvas::Canvas;
+use gesture::{Direction, Gesture, Recognizer};
+use hooks::Hook;
+use guide::Guide;
+use hover::HoverMark;
+use instance::InstanceLock;
+use keyboard::{KeyPress, Keyboards};
+use latency::Stage;
+use lasso::Lasso;
+use palm::PalmFilter;
+use record::{Recorder, Replay};
+use refresh::{refresh_chrome, refresh_gray, refresh_ink, refresh_view, Speed};
+use shapes::{Hold, Shape, ShapeTool};
+use smoothing::OneEuroFilter;
+use stamps::Stamp;
+use stroke::{Brush, Layer, Stroke, REFERENCE_PRESSURE};
 
 #[derive(Copy, Clone, PartialEq)]
 enum DrawMode {
     Draw(u32),
     Erase(u32),
+    /// Deletes whole strokes instead of painting white
+    EraseStrokes(u32),
+    /// Wide light gray that stays underneath black ink
+    Highlight(u32),
+    /// Ink that shades like a pencil when the pen is held low
+    Pencil(u32),
 }
 impl DrawMode {
     fn set_size(self, new_size: u32) -> Self {
         match self {
             DrawMode::Draw(_) => DrawMode::Draw(new_size),
             DrawMode::Erase(_) => DrawMode::Erase(new_size),
+            DrawMode::EraseStrokes(_) => DrawMode::EraseStrokes(new_size),
+            DrawMode::Highlight(_) => DrawMode::Highlight(new_size),
+            DrawMode::Pencil(_) => DrawMode::Pencil(new_size),
         }
     }
     fn color_as_string(self) -> String {
         match self {
             DrawMode::Draw(_) => "Black",
             DrawMode::Erase(_) => "White",
+            DrawMode::EraseStrokes(_) => "None",
+            DrawMode::Highlight(_) => "Gray",
+            DrawMode::Pencil(_) => "Black",
         }
         .into()
     }
@@ -51,307 +102,2618 @@ impl DrawMode {
         match self {
             DrawMode::Draw(s) => s,
             DrawMode::Erase(s) => s,
+            DrawMode::EraseStrokes(s) => s,
+            DrawMode::Highlight(s) => s,
+            DrawMode::Pencil(s) => s,
+        }
+    }
+}
+
+/// A brush and ink level the preset button jumps straight to, written in the
+/// config as `<brush> <size> <ink %>`, e.g. `preset_1 = pencil 4 75`
+#[derive(Copy, Clone)]
+struct Preset {
+    mode: DrawMode,
+    ink_level: u8,
+}
+impl FromStr for Preset {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let (brush, size, ink) = match (words.next(), words.next(), words.next(), words.next()) {
+            (Some(brush), Some(size), Some(ink), None) => (brush, size, ink),
+            _ => return Err(()),
+        };
+        let size: u32 = size.parse().map_err(|_| ())?;
+        let ink: u32 = ink.parse().map_err(|_| ())?;
+        if !(1..=99).contains(&size) || !(1..=100).contains(&ink) {
+            return Err(());
+        }
+        let mode = match brush {
+            "pen" => DrawMode::Draw(size),
+            "pencil" => DrawMode::Pencil(size),
+            "highlighter" => DrawMode::Highlight(size),
+            "eraser" => DrawMode::Erase(size),
+            "stroke_eraser" => DrawMode::EraseStrokes(size),
+            _ => return Err(()),
+        };
+        Ok(Preset {
+            mode,
+            ink_level: ((ink * 255 + 50) / 100) as u8,
+        })
+    }
+}
+
+/// What one of the hardware buttons does, set in the config as
+/// `button_left`, `button_middle` or `button_right`, e.g. `button_right = undo`.
+/// Buttons on the pen barrel are set the same way with `stylus_button` and
+/// `stylus2_button`.
+#[derive(Copy, Clone)]
+enum ButtonAction {
+    SmallerBrush,
+    BiggerBrush,
+    Eraser,
+    Highlighter,
+    /// The pen erases like its rubber end for as long as the button is held.
+    /// Only the pen's own buttons can be held while drawing.
+    HoldEraser,
+    Undo,
+    Redo,
+    /// Hands the screen back to xochitl, as the power button does
+    Exit,
+    None,
+}
+impl FromStr for ButtonAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smaller_brush" => Ok(ButtonAction::SmallerBrush),
+            "bigger_brush" => Ok(ButtonAction::BiggerBrush),
+            "eraser" => Ok(ButtonAction::Eraser),
+            "highlighter" => Ok(ButtonAction::Highlighter),
+            "hold_eraser" => Ok(ButtonAction::HoldEraser),
+            "undo" => Ok(ButtonAction::Undo),
+            "redo" => Ok(ButtonAction::Redo),
+            "exit" => Ok(ButtonAction::Exit),
+            "none" => Ok(ButtonAction::None),
+            _ => Err(()),
         }
     }
 }
 
-// This region will have the following size at rest:
+/// What the pen does on the canvas, on top of the brush settings in DrawMode
+#[derive(Copy, Clone, PartialEq)]
+enum Tool {
+    Brush,
+    Lasso,
+    Shape(Shape),
+    Fill,
+    /// Tap to step through the zoom levels, drag to pan
+    Zoom,
+    /// Tap to type a label there on the on-screen keyboard
+    Text,
+    /// Tap to place the stamp, or drag out from its center to size it
+    Stamp(Stamp),
+    /// Drag out a box to put the chosen image in, or tap to place it at its
+    /// own size
+    Image,
+}
+
+/// A label being typed on the on-screen keyboard, which covers the other side
+/// of the card until it's done
+struct TextEntry {
+    side: usize,
+    /// Where the label's baseline starts
+    anchor: cgmath::Point2<f32>,
+    text: String,
+    /// Whether the next letter comes out in capitals
+    shift: bool,
+}
+
+// Everything below the toolbar, split between the front and back of the card.
+// At an even split each side will have the following size at rest:
 //   raw: 5896 kB
 //   zstd: 10 kB
-const FRONT_CANVAS: mxcfb_rect = mxcfb_rect {
+const CARD_AREA: mxcfb_rect = mxcfb_rect {
     top: 74,
     left: 4,
-    height: 896,
-    width: 1396,
-};
-const BACK_CANVAS: mxcfb_rect = mxcfb_rect {
-    top: 972,
-    left: 4,
-    height: 896,
+    height: 1794,
     width: 1396,
 };
+// Space left between the two sides
+const DIVIDER_PX: u32 = 2;
+// Percentages of the card the split button steps the front through
+const FRONT_SHARES: [u32; 3] = [35, 50, 65];
+// Percentage of the card taken by the front, set per deck with `front_share`
+// in the config. Either side has to leave room for the keyboard.
+static FRONT_SHARE: Lazy<AtomicU32> =
+    Lazy::new(|| AtomicU32::new(CONFIG.get("front_share", 50).clamp(35, 65)));
 static G_DRAW_MODE: Lazy<Atomic<DrawMode>> = Lazy::new(|| Atomic::new(DrawMode::Draw(2)));
+static G_TOOL: Lazy<Atomic<Tool>> = Lazy::new(|| Atomic::new(Tool::Brush));
+// Darkness of the ink in DrawMode::Draw, 255 being solid black
+static G_INK_LEVEL: AtomicU8 = AtomicU8::new(255);
+// Whether erasing deletes whole strokes rather than painting white. Starts
+// from `stroke_eraser` in the config.
+static ERASE_STROKES: Lazy<AtomicBool> =
+    Lazy::new(|| AtomicBool::new(CONFIG.get("stroke_eraser", false)));
+const HIGHLIGHTER_LEVEL: u8 = 80;
 static UNPRESS_OBSERVED: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
 static WACOM_IN_RANGE: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
 static WACOM_RUBBER_SIDE: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
+// How wide the rubber end erases, and whether it takes whole strokes rather
+// than painting white, set with `rubber_size` and `rubber_stroke_eraser` in
+// the config
+static RUBBER_SIZE: Lazy<u32> = Lazy::new(|| CONFIG.get("rubber_size", 50).max(1));
+static RUBBER_STROKES: Lazy<bool> = Lazy::new(|| CONFIG.get("rubber_stroke_eraser", false));
+// The last few pen positions, for drawing the next segment. It never holds
+// more than three points, so room for them is made up front and a stroke
+// never allocates. Only the UI thread takes the lock, so it never waits.
 static WACOM_HISTORY: Lazy<Mutex<VecDeque<(cgmath::Point2<f32>, i32)>>> =
-    Lazy::new(|| Mutex::new(VecDeque::new()));
-static G_COUNTER: Lazy<Mutex<u32>> = Lazy::new(|| Mutex::new(0));
-static SAVED_CANVAS: Lazy<Mutex<Option<storage::CompressedCanvasState>>> =
+    Lazy::new(|| Mutex::new(VecDeque::with_capacity(3)));
+// Draw events lighter than this are taken for the pen hovering, set with
+// `min_pressure` in the config
+static MIN_PRESSURE: Lazy<u16> = Lazy::new(|| CONFIG.get("min_pressure", 0));
+// When the pen last drew, cleared once it lifts. The lift is sometimes never
+// reported, so a stroke is also ended after `pen_up_timeout_ms` without any.
+static LAST_DRAW: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+static PEN_UP_TIMEOUT: Lazy<Duration> =
+    Lazy::new(|| Duration::from_millis(CONFIG.get("pen_up_timeout_ms", 200)));
+
+// The front and back of the card. The pen works on whichever side it last
+// touched down on.
+static CANVASES: Lazy<[Mutex<Canvas>; 2]> = Lazy::new(|| {
+    [
+        Mutex::new(Canvas::new(card_sides()[0])),
+        Mutex::new(Canvas::new(card_sides()[1])),
+    ]
+});
+static ACTIVE_SIDE: AtomicUsize = AtomicUsize::new(0);
+static LASSO: Lazy<Mutex<Lasso>> = Lazy::new(|| Mutex::new(Lasso::new()));
+static SHAPE_TOOL: Lazy<Mutex<ShapeTool>> = Lazy::new(|| Mutex::new(ShapeTool::new()));
+static HOLD: Lazy<Mutex<Hold>> = Lazy::new(|| Mutex::new(Hold::new()));
+static PALM: Lazy<Mutex<PalmFilter>> = Lazy::new(|| Mutex::new(PalmFilter::new()));
+static GESTURES: Lazy<Mutex<Recognizer>> = Lazy::new(|| Mutex::new(Recognizer::new()));
+static HOVER: Lazy<Mutex<HoverMark>> = Lazy::new(|| Mutex::new(HoverMark::new()));
+// A two-finger tap undoes, but two in a row on a zoomed view reset the zoom
+// instead. There the undo waits this long to see if a second tap follows.
+static PENDING_UNDO: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+const DOUBLE_TAP: Duration = Duration::from_millis(500);
+// None unless smoothing is turned on in the config
+static SMOOTHING: Lazy<Mutex<Option<OneEuroFilter>>> = Lazy::new(|| {
+    Mutex::new(if CONFIG.get("smoothing", false) {
+        Some(OneEuroFilter::new(
+            CONFIG.get("smoothing_min_cutoff", 1.0),
+            CONFIG.get("smoothing_beta", 0.01),
+            CONFIG.get("smoothing_d_cutoff", 1.0),
+        ))
+    } else {
+        None
+    })
+});
+// How many pen reports the event being handled stands for, more than one
+// when the backlog thinned out those before it. Smoothing is timed by it.
+static PEN_REPORTS: AtomicU32 = AtomicU32::new(1);
+// Where the pen went down and where it is now, while using the zoom or stamp
+// tools
+static TOOL_DRAG: Lazy<Mutex<Option<shapes::Drag>>> = Lazy::new(|| Mutex::new(None));
+// A zoom or stamp tool drag shorter than this counts as a tap
+const TAP_SLOP: f32 = 10.0;
+// Set between the clear button's first tap and the choice to go ahead or not
+static CONFIRMING_CLEAR: AtomicBool = AtomicBool::new(false);
+// Where the context menu was opened, while it's up
+static CONTEXT_MENU: Lazy<Mutex<Option<cgmath::Point2<f32>>>> = Lazy::new(|| Mutex::new(None));
+// Whether the drawer swiped in from the edge is out
+static DRAWER_OPEN: AtomicBool = AtomicBool::new(false);
+// The image the image tool puts down
+static IMAGE_CHOICE: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+// The notebook page imported last, so the next import takes the one after
+#[cfg(feature = "import")]
+static IMPORTED_PAGE: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+// Set while a card is on its way to Anki, and the label saying how it went
+// once it got there or didn't
+#[cfg(feature = "anki")]
+static ANKI_SENDING: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "anki")]
+static ANKI_SENT: Lazy<Mutex<Option<&'static str>>> = Lazy::new(|| Mutex::new(None));
+static TEXT_ENTRY: Lazy<Mutex<Option<TextEntry>>> = Lazy::new(|| Mutex::new(None));
+static KEYBOARDS: Lazy<Mutex<Keyboards>> = Lazy::new(|| Mutex::new(Keyboards::new()));
+// How often typing on an external keyboard is picked up while the text entry
+// is open. The event loop otherwise only wakes for the pen-up timeout.
+const KEY_POLL: Duration = Duration::from_millis(30);
+// The synthetic stroke `--bench-latency` draws, a few seconds of reports at
+// about the digitizer's rate
+const BENCH_REPORTS: usize = 600;
+const BENCH_REPORT_INTERVAL: Duration = Duration::from_millis(5);
+const MENU_ELEMENTS: [&str; 4] = ["menuInfo", "menuImage", "menuPaste", "menuClose"];
+
+// How wide the navigation drawer is, and how far in from the edge a swipe has
+// to start to open it
+const DRAWER_WIDTH: u32 = 420;
+const EDGE_PX: f32 = 60.0;
+const DRAWER_ELEMENTS: [&str; 5] = [
+    "drawerRegion",
+    "drawerTitle",
+    "drawerImport",
+    "drawerExit",
+    "drawerClose",
+];
+// Puts the toolbar in mirror image and the drawer on the right, out from under
+// a left hand, set with `left_handed` in the config
+static LEFT_HANDED: Lazy<bool> = Lazy::new(|| CONFIG.get("left_handed", false));
+
+// Character keys of the on-screen keyboard, a row at a time. Shift, space,
+// delete, cancel and done go on a row of their own below these.
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl'", "zxcvbnm,.?"];
+
+static PRESETS: Lazy<Vec<Preset>> = Lazy::new(|| {
+    ["pen 2 100", "pen 8 100", "pencil 4 75", "highlighter 2 100"]
+        .iter()
+        .enumerate()
+        .map(|(i, default)| {
+            let default = default.parse().unwrap();
+            CONFIG.get(&format!("preset_{0}", i + 1), default)
+        })
+        .collect()
+});
+// The preset last jumped to, if any, so the button knows which comes next
+static PRESET_INDEX: Lazy<Atomic<Option<usize>>> = Lazy::new(|| Atomic::new(None));
+
+// Actions of the left, middle and right buttons
+static BUTTON_ACTIONS: Lazy<[ButtonAction; 3]> = Lazy::new(|| {
+    [
+        CONFIG.get("button_left", ButtonAction::SmallerBrush),
+        CONFIG.get("button_middle", ButtonAction::BiggerBrush),
+        CONFIG.get("button_right", ButtonAction::Eraser),
+    ]
+});
+// A repeat press of the same button sooner than this after the last one is
+// taken for a worn switch bouncing, set with `button_debounce_ms` in the config
+static BUTTON_DEBOUNCE: Lazy<Duration> =
+    Lazy::new(|| Duration::from_millis(CONFIG.get("button_debounce_ms", 200)));
+static LAST_BUTTON_PRESS: Lazy<Mutex<Option<(input::PhysicalButton, Instant)>>> =
     Lazy::new(|| Mutex::new(None));
+// Actions of the lower and upper buttons on the pen barrel, on pens that have
+// them
+static STYLUS_ACTIONS: Lazy<[ButtonAction; 2]> = Lazy::new(|| {
+    [
+        CONFIG.get("stylus_button", ButtonAction::HoldEraser),
+        CONFIG.get("stylus2_button", ButtonAction::Highlighter),
+    ]
+});
+
+// The top bar has more buttons than fit across the screen, so all but undo
+// and redo are split over pages
+static TOOLBAR_PAGE: AtomicUsize = AtomicUsize::new(0);
+// Buttons relabelled while a stroke was being inked, and their new labels.
+// They're redrawn once the pen lifts so the ink isn't kept waiting.
+static DEFERRED_LABELS: Lazy<Mutex<Vec<(String, String)>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+const TOOLBAR_PAGES: [&[&str]; 5] = [
+    &[
+        "eraserButton",
+        "lassoButton",
+        "deleteSelectionButton",
+        "inkLevelButton",
+        "highlighterButton",
+        "shapeButton",
+        "fillButton",
+        "pageButton",
+    ],
+    &[
+        "zoomButton",
+        "layerButton",
+        "copyButton",
+        "pencilButton",
+        "presetButton",
+        "textButton",
+        "pageButton",
+    ],
+    &[
+        "stampButton",
+        "imageButton",
+        "clearButton",
+        "cancelClearButton",
+        "pageButton",
+    ],
+    &[
+        "guideButton",
+        "splitButton",
+        "eraserKindButton",
+        "exportButton",
+        "pageButton",
+    ],
+    &[
+        "rotateButton",
+        "mirrorButton",
+        "duplicateButton",
+        "ankiButton",
+        "pageButton",
+    ],
+];
+
+// Stays None while another instance owns the display, in which case the pen
+// can only press the take over / quit buttons. 0 means the holder is unknown.
+static INSTANCE_LOCK: Lazy<Mutex<Option<InstanceLock>>> = Lazy::new(|| Mutex::new(None));
+static LOCK_HOLDER_PID: AtomicI32 = AtomicI32::new(0);
 
 // ####################
 // ## Button Handlers
 // ####################
 
-fn on_save_canvas(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
-    start_bench!(stopwatch, save_canvas);
-    let framebuffer = app.get_framebuffer_ref();
-    match framebuffer.dump_region(FRONT_CANVAS) {
-        Err(err) => println!("Failed to dump buffer: {0}", err),
-        Ok(buff) => {
-            let mut hist = SAVED_CANVAS.lock().unwrap();
-            *hist = Some(storage::CompressedCanvasState::new(
-                buff.as_slice(),
-                FRONT_CANVAS.height,
-                FRONT_CANVAS.width,
-            ));
+fn on_toggle_eraser(app: &mut appctx::ApplicationContext<'_>) {
+    let (new_mode, name) = match G_DRAW_MODE.load(Ordering::Relaxed) {
+        DrawMode::Erase(s) => (DrawMode::Draw(s), "Black".to_owned()),
+        DrawMode::EraseStrokes(s) => (DrawMode::Draw(s), "Black".to_owned()),
+        DrawMode::Draw(s) => (eraser_mode(s), "White".to_owned()),
+        DrawMode::Highlight(s) => (eraser_mode(s), "White".to_owned()),
+        DrawMode::Pencil(s) => (eraser_mode(s), "White".to_owned()),
+    };
+    G_DRAW_MODE.store(new_mode, Ordering::Relaxed);
+}
+
+fn on_toggle_eraser_button(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    select_tool(app, Tool::Brush);
+    on_toggle_eraser(app);
+}
+
+/// Switches the toolbar eraser between painting white and deleting whole
+/// strokes. The rubber end goes by `rubber_stroke_eraser` in the config
+/// instead.
+fn on_toggle_eraser_kind(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let strokes = !ERASE_STROKES.fetch_xor(true, Ordering::Relaxed);
+    match G_DRAW_MODE.load(Ordering::Relaxed) {
+        DrawMode::Erase(s) | DrawMode::EraseStrokes(s) => {
+            G_DRAW_MODE.store(eraser_mode(s), Ordering::Relaxed);
         }
+        DrawMode::Draw(_) | DrawMode::Highlight(_) | DrawMode::Pencil(_) => {}
+    }
+
+    set_button_label(app, "eraserKindButton", eraser_kind_label(strokes));
+}
+
+fn on_toggle_highlighter(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    toggle_highlighter(app);
+}
+
+fn toggle_highlighter(app: &mut appctx::ApplicationContext<'_>) {
+    select_tool(app, Tool::Brush);
+    let new_mode = match G_DRAW_MODE.load(Ordering::Relaxed) {
+        DrawMode::Highlight(s) => DrawMode::Draw(s),
+        DrawMode::Draw(s)
+        | DrawMode::Erase(s)
+        | DrawMode::EraseStrokes(s)
+        | DrawMode::Pencil(s) => DrawMode::Highlight(s),
     };
-    end_bench!(save_canvas);
+    G_DRAW_MODE.store(new_mode, Ordering::Relaxed);
 }
 
-fn on_toggle_eraser(app: &mut appctx::ApplicationContext<'_>) {
-    let (new_mode, name) = match G_DRAW_MODE.load(Ordering::Relaxed) {
-        DrawMode::Erase(s) => (DrawMode::Draw(s), "Black".to_owned()),
-        DrawMode::Draw(s) => (DrawMode::Erase(s), "White".to_owned()),
+fn on_toggle_pencil(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    select_tool(app, Tool::Brush);
+    let new_mode = match G_DRAW_MODE.load(Ordering::Relaxed) {
+        DrawMode::Pencil(s) => DrawMode::Draw(s),
+        DrawMode::Draw(s)
+        | DrawMode::Erase(s)
+        | DrawMode::EraseStrokes(s)
+        | DrawMode::Highlight(s) => DrawMode::Pencil(s),
     };
     G_DRAW_MODE.store(new_mode, Ordering::Relaxed);
 }
 
-// ####################
-// ## Miscellaneous
-// ####################
+fn on_next_preset(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let index = match PRESET_INDEX.load(Ordering::Relaxed) {
+        Some(i) => (i + 1) % PRESETS.len(),
+        None => 0,
+    };
+    let preset = PRESETS[index];
+    select_tool(app, Tool::Brush);
+    G_DRAW_MODE.store(preset.mode, Ordering::Relaxed);
+    G_INK_LEVEL.store(preset.ink_level, Ordering::Relaxed);
+    PRESET_INDEX.store(Some(index), Ordering::Relaxed);
 
-/// Called on button press on rm2 or left gpio on rm1
-fn quick_redraw(app: &mut appctx::ApplicationContext<'_>) {
-    app.clear(false);
-    app.draw_elements();
+    for (name, label) in [
+        ("presetButton", preset_label(Some(index))),
+        ("inkLevelButton", ink_level_label(preset.ink_level)),
+    ] {
+        set_button_label(app, name, label);
+    }
 }
 
-/// Called on button press on rm2 or middle gpio on rm1
-fn full_redraw(app: &mut appctx::ApplicationContext<'_>) {
-    app.clear(true);
-    app.draw_elements();
+fn on_cycle_ink_level(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let new_level = match G_INK_LEVEL.load(Ordering::Relaxed) {
+        255 => 192,
+        192 => 128,
+        128 => 64,
+        _ => 255,
+    };
+    G_INK_LEVEL.store(new_level, Ordering::Relaxed);
+
+    set_button_label(app, "inkLevelButton", ink_level_label(new_level));
 }
 
-fn change_brush_width(app: &mut appctx::ApplicationContext<'_>, delta: i32) {
-    let current = G_DRAW_MODE.load(Ordering::Relaxed);
-    let current_size = current.get_size() as i32;
-    let proposed_size = current_size + delta;
-    let new_size = if proposed_size < 1 {
-        1
-    } else if proposed_size > 99 {
-        99
-    } else {
-        proposed_size
+fn on_toggle_lasso(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    match G_TOOL.load(Ordering::Relaxed) {
+        Tool::Lasso => select_tool(app, Tool::Brush),
+        _ => select_tool(app, Tool::Lasso),
+    }
+}
+
+fn on_cycle_shape(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    match G_TOOL.load(Ordering::Relaxed) {
+        Tool::Shape(shape) => match shape.next() {
+            Some(next) => select_tool(app, Tool::Shape(next)),
+            None => select_tool(app, Tool::Brush),
+        },
+        _ => select_tool(app, Tool::Shape(Shape::Line)),
+    }
+}
+
+fn on_toggle_fill(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    match G_TOOL.load(Ordering::Relaxed) {
+        Tool::Fill => select_tool(app, Tool::Brush),
+        _ => select_tool(app, Tool::Fill),
+    }
+}
+
+fn on_cycle_stamp(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    match G_TOOL.load(Ordering::Relaxed) {
+        Tool::Stamp(stamp) => match stamp.next() {
+            Some(next) => select_tool(app, Tool::Stamp(next)),
+            None => select_tool(app, Tool::Brush),
+        },
+        _ => select_tool(app, Tool::Stamp(Stamp::Arrow)),
+    }
+}
+
+/// Steps through the images in the image directory, back to the brush after
+/// the last one
+fn on_cycle_image(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let dir = picture::dir();
+    let files = picture::list(&dir);
+    if files.is_empty() {
+        warn!("No images to insert in {0}", dir.display());
+    }
+    let next = {
+        let mut choice = IMAGE_CHOICE.lock().unwrap();
+        let next = match (G_TOOL.load(Ordering::Relaxed), choice.as_ref()) {
+            (Tool::Image, Some(current)) => files
+                .iter()
+                .position(|f| f == current)
+                .and_then(|i| files.get(i + 1)),
+            _ => files.first(),
+        };
+        *choice = next.cloned();
+        next.is_some()
     };
-    if new_size == current_size {
+    select_tool(app, if next { Tool::Image } else { Tool::Brush });
+
+    set_button_label(app, "imageButton", image_label(G_TOOL.load(Ordering::Relaxed)));
+}
+
+/// First tap asks for confirmation, second clears every stroke off the side
+/// of the card the pen was last on
+fn on_clear_side(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    if !CONFIRMING_CLEAR.swap(true, Ordering::Relaxed) {
+        let side = ACTIVE_SIDE.load(Ordering::Relaxed);
+        let label = format!("Yes, {0}", clear_label(side).to_lowercase());
+        set_button_label(app, "clearButton", label);
+        add_button(app, "cancelClearButton", 1130, "Cancel".to_owned(), on_cancel_clear);
+        app.draw_element("cancelClearButton");
         return;
     }
 
-    G_DRAW_MODE.store(current.set_size(new_size as u32), Ordering::Relaxed);
+    {
+        let framebuffer = app.get_framebuffer_ref();
+        let mut canvas = active_canvas();
+        if let Some(rect) = LASSO.lock().unwrap().cancel(framebuffer, &mut canvas) {
+            refresh_ink(framebuffer, &rect);
+        }
+        if let Some(rect) = canvas.clear(framebuffer) {
+            refresh_view(framebuffer, &rect);
+        }
+    }
+    end_clear_confirmation(app);
 }
 
-// ####################
-// ## Input Handlers
-// ####################
+fn on_cancel_clear(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    end_clear_confirmation(app);
+}
 
-fn on_wacom_input(app: &mut appctx::ApplicationContext<'_>, input: input::WacomEvent) {
-    match input {
-        input::WacomEvent::Draw {
-            position,
-            pressure,
-            tilt: _,
-        } => {
-            let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
+/// Steps both sides of the card through the guides
+fn on_cycle_guide(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let framebuffer = app.get_framebuffer_ref();
+    let guide = CANVASES[0].lock().unwrap().guide().next();
+    for canvas in CANVASES.iter() {
+        let rect = canvas.lock().unwrap().set_guide(framebuffer, guide);
+        refresh_view(framebuffer, &rect);
+    }
 
-            // This is so that we can click the buttons outside the canvas region
-            // normally meant to be touched with a finger using our stylus
-            if !FRONT_CANVAS.contains_point(&position.cast().unwrap()) {
-                wacom_stack.clear();
-                if UNPRESS_OBSERVED.fetch_and(false, Ordering::Relaxed) {
-                    let region = app
-                        .find_active_region(position.y.round() as u16, position.x.round() as u16);
-                    let element = region.map(|(region, _)| region.element.clone());
-                    if let Some(element) = element {
-                        (region.unwrap().0.handler)(app, element)
-                    }
-                }
-                return;
-            }
+    set_button_label(app, "guideButton", guide_label(guide));
+}
 
-            let (mut col, mut mult) = match G_DRAW_MODE.load(Ordering::Relaxed) {
-                DrawMode::Draw(s) => (color::BLACK, s),
-                DrawMode::Erase(s) => (color::WHITE, s * 3),
-            };
-            if WACOM_RUBBER_SIDE.load(Ordering::Relaxed) {
-                col = match col {
-                    color::WHITE => color::BLACK,
-                    _ => color::WHITE,
-                };
-                mult = 50; // Rough size of the rubber end
-            }
+/// Moves the divider so the front takes the next share of the card
+fn on_cycle_split(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    finish_stroke(app);
+    close_keyboard(app);
+    {
+        let framebuffer = app.get_framebuffer_ref();
+        let mut canvas = active_canvas();
+        if let Some(rect) = LASSO.lock().unwrap().cancel(framebuffer, &mut canvas) {
+            refresh_ink(framebuffer, &rect);
+        }
+    }
 
-            wacom_stack.push_back((position.cast().unwrap(), pressure as i32));
+    let share = FRONT_SHARE.load(Ordering::Relaxed);
+    let next = FRONT_SHARES
+        .iter()
+        .copied()
+        .find(|&s| s > share)
+        .unwrap_or(FRONT_SHARES[0]);
+    FRONT_SHARE.store(next, Ordering::Relaxed);
 
-            while wacom_stack.len() >= 3 {
-                let framebuffer = app.get_framebuffer_ref();
-                let points = vec![
-                    wacom_stack.pop_front().unwrap(),
-                    *wacom_stack.get(0).unwrap(),
-                    *wacom_stack.get(1).unwrap(),
-                ];
-                let radii: Vec<f32> = points
-                    .iter()
-                    .map(|point| ((mult as f32 * (point.1 as f32) / 2048.) / 2.0))
-                    .collect();
-                // calculate control points
-                let start_point = points[2].0.midpoint(points[1].0);
-                let ctrl_point = points[1].0;
-                let end_point = points[1].0.midpoint(points[0].0);
-                // calculate diameters
-                let start_width = radii[2] + radii[1];
-                let ctrl_width = radii[1] * 2.0;
-                let end_width = radii[1] + radii[0];
-                let rect = framebuffer.draw_dynamic_bezier(
-                    (start_point, start_width),
-                    (ctrl_point, ctrl_width),
-                    (end_point, end_width),
-                    10,
-                    col,
-                );
+    app.remove_element("frontCanvasRegion");
+    app.remove_element("backCanvasRegion");
+    let framebuffer = app.get_framebuffer_ref();
+    framebuffer.fill_rect(
+        CARD_AREA.top_left().cast().unwrap(),
+        CARD_AREA.size(),
+        color::WHITE,
+    );
+    for (canvas, bounds) in CANVASES.iter().zip(card_sides()) {
+        canvas.lock().unwrap().set_bounds(framebuffer, bounds);
+    }
+    add_canvas_regions(app);
+    app.draw_element("frontCanvasRegion");
+    app.draw_element("backCanvasRegion");
+    refresh_view(app.get_framebuffer_ref(), &CARD_AREA);
+    show_zoom(app, 1.0);
 
-                framebuffer.partial_refresh(
-                    &rect,
-                    PartialRefreshMode::Async,
-                    waveform_mode::WAVEFORM_MODE_DU,
-                    display_temp::TEMP_USE_REMARKABLE_DRAW,
-                    dither_mode::EPDC_FLAG_EXP1,
-                    DRAWING_QUANT_BIT,
-                    false,
-                );
-            }
-        }
-        input::WacomEvent::InstrumentChange { pen, state } => {
-            match pen {
-                // Whether the pen is in range
-                input::WacomPen::ToolPen => {
-                    WACOM_IN_RANGE.store(state, Ordering::Relaxed);
-                    WACOM_RUBBER_SIDE.store(false, Ordering::Relaxed);
-                }
-                input::WacomPen::ToolRubber => {
-                    WACOM_IN_RANGE.store(state, Ordering::Relaxed);
-                    WACOM_RUBBER_SIDE.store(true, Ordering::Relaxed);
-                }
-                // Whether the pen is actually making contact
-                input::WacomPen::Touch => {
-                    // Stop drawing when instrument has left the vicinity of the screen
-                    if !state {
-                        let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
-                        wacom_stack.clear();
-                        println!( "lift" )
-                    }
+    set_button_label(app, "splitButton", split_label(next));
+}
+
+/// Writes both sides of the card out as PNGs at 1x, guide lines left out, as SVGs
+/// of their strokes, and together as a xochitl notebook page
+#[cfg(feature = "export")]
+fn on_export_card(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let dir = export::dir();
+    if let Err(err) = std::fs::create_dir_all(&dir) {
+        error!("Failed to create {0}: {1}", dir.display(), err);
+        return;
+    }
+    let number = export::next_number(&dir);
+    let framebuffer = app.get_framebuffer_ref();
+    let mut lines = Vec::new();
+    for (half, (canvas, side)) in CANVASES.iter().zip(["front", "back"]).enumerate() {
+        let mut canvas = canvas.lock().unwrap();
+        let inner = canvas.inner();
+        let path = export::path(&dir, number, side, "png");
+        match canvas.dump_unguided(framebuffer, inner) {
+            Err(err) => error!("Failed to dump buffer: {0}", err),
+            Ok(buff) => {
+                if let Err(err) = export::save_png(&buff, &inner, &path) {
+                    error!("Failed to write {0}: {1}", path.display(), err);
                 }
-                _ => unreachable!(),
             }
         }
-        input::WacomEvent::Hover {
-            position: _,
-            distance,
-            tilt: _,
-        } => {
-            // If the pen is hovering, don't record its coordinates as the origin of the next line
-            if distance > 1 {
-                let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
-                wacom_stack.clear();
-                UNPRESS_OBSERVED.store(true, Ordering::Relaxed);
-            }
+        let path = export::path(&dir, number, side, "svg");
+        if let Err(err) = std::fs::write(&path, canvas.svg()) {
+            error!("Failed to write {0}: {1}", path.display(), err);
         }
-        _ => {}
-    };
+        lines.extend(notebook::half_page_lines(canvas.stacked(), half, &inner));
+    }
+    // Each notebook is a few files named by id, so they get a folder apiece
+    // to be copied into xochitl's data directory from
+    let notebook_dir = dir.join(format!("card-{0:04}-notebook", number));
+    let written = std::fs::create_dir_all(&notebook_dir).and_then(|_| {
+        let name = format!("Card {0:04}", number);
+        notebook::write_notebook(&notebook_dir, &name, &lines)
+    });
+    if let Err(err) = written {
+        error!("Failed to write {0}: {1}", notebook_dir.display(), err);
+    }
+    set_button_label(app, "exportButton", format!("Exported {0:04}", number));
+    let card = format!("{0:04}", number);
+    hooks::run(app, Hook::Export, &[("card", &card), ("dir", &dir.to_string_lossy())]);
 }
 
-fn on_button_press(app: &mut appctx::ApplicationContext<'_>, input: input::GPIOEvent) {
-    let (btn, new_state) = match input {
-        input::GPIOEvent::Press { button } => (button, true),
-        input::GPIOEvent::Unpress { button } => (button, false),
-        _ => return,
-    };
-
-    // Ignoring the unpressed event
-    if !new_state {
+/// Adds the card to Anki on the desktop through AnkiConnect, a note with an
+/// image of each side. Only the sides are read here, rendered at 1x so that
+/// zooming in doesn't crop what gets sent. Encoding and sending happen on a
+/// thread of their own so a slow desktop doesn't hold up the pen, and
+/// `check_anki` puts how it went on the button.
+#[cfg(feature = "anki")]
+fn on_send_to_anki(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    if ANKI_SENDING.swap(true, Ordering::Relaxed) {
         return;
     }
-
-    // Simple but effective accidental button press filtering
-    if WACOM_IN_RANGE.load(Ordering::Relaxed) {
-        return;
+    set_button_label(app, "ankiButton", "Sending".to_owned());
+    let framebuffer = app.get_framebuffer_ref();
+    let mut sides = Vec::new();
+    for canvas in CANVASES.iter() {
+        let mut canvas = canvas.lock().unwrap();
+        let inner = canvas.inner();
+        match canvas.dump_unguided(framebuffer, inner) {
+            Ok(buff) => sides.push((buff, inner)),
+            Err(err) => {
+                error!("Failed to dump buffer: {0}", err);
+                ANKI_SENDING.store(false, Ordering::Relaxed);
+                set_button_label(app, "ankiButton", "Not sent".to_owned());
+                return;
+            }
+        }
     }
+    let name = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    std::thread::spawn(move || {
+        let label = send_to_anki(&name, &sides);
+        *ANKI_SENT.lock().unwrap() = Some(label);
+    });
+}
 
-    match btn {
-        input::PhysicalButton::LEFT => change_brush_width(app, -1),
-        input::PhysicalButton::MIDDLE => change_brush_width(app, 1),
-        input::PhysicalButton::RIGHT => on_toggle_eraser(app),
-        input::PhysicalButton::POWER => {
-            Command::new("systemctl")
-                .arg("start")
-                .arg("xochitl")
-                .spawn()
-                .unwrap();
-            std::process::exit(0);
+/// Encodes the dumped sides and sends them off as note `name`. Returns the
+/// label for the button.
+#[cfg(feature = "anki")]
+fn send_to_anki(name: &str, sides: &[(Vec<u8>, mxcfb_rect)]) -> &'static str {
+    let mut pngs = Vec::new();
+    for (buff, rect) in sides.iter() {
+        match export::png_bytes(buff, rect) {
+            Ok(png) => pngs.push(png),
+            Err(err) => {
+                error!("Failed to encode card: {0}", err);
+                return "Not sent";
+            }
         }
-        input::PhysicalButton::WAKEUP => {
-            println!("WAKEUP button(?) pressed(?)");
+    }
+    match anki::add_card(name, &pngs[0], &pngs[1]) {
+        Ok(()) => "Sent",
+        Err(err) => {
+            error!("Failed to send card to Anki: {0}", err);
+            "Not sent"
         }
+    }
+}
+
+/// Shows how sending a card to Anki went, once it's done
+#[cfg(feature = "anki")]
+fn check_anki(app: &mut appctx::ApplicationContext<'_>) {
+    let label = match ANKI_SENT.lock().unwrap().take() {
+        Some(label) => label,
+        None => return,
     };
+    ANKI_SENDING.store(false, Ordering::Relaxed);
+    set_button_label(app, "ankiButton", label.to_owned());
 }
 
-fn main() {
-    env_logger::init();
+fn on_toggle_zoom(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    match G_TOOL.load(Ordering::Relaxed) {
+        Tool::Zoom => select_tool(app, Tool::Brush),
+        _ => select_tool(app, Tool::Zoom),
+    }
+}
 
-    // Takes callback functions as arguments
-    // They are called with the event and the &mut framebuffer
-    let mut app: appctx::ApplicationContext<'_> = appctx::ApplicationContext::default();
+fn on_toggle_layer(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let layer = {
+        let framebuffer = app.get_framebuffer_ref();
+        let mut canvas = active_canvas();
+        if let Some(rect) = LASSO.lock().unwrap().cancel(framebuffer, &mut canvas) {
+            refresh_ink(framebuffer, &rect);
+        }
+        let layer = match canvas.layer() {
+            Layer::Diagram => Layer::Notes,
+            Layer::Notes => Layer::Diagram,
+        };
+        canvas.set_layer(layer);
+        layer
+    };
 
-    // Alternatively we could have called `app.execute_lua("fb.clear()")`
-    app.clear(true);
+    set_button_label(app, "layerButton", layer_label(layer));
+}
 
-    // Draw the borders for the canvas region
-    app.add_element(
-        "frontCanvasRegion",
-        UIElementWrapper {
-            position: FRONT_CANVAS.top_left().cast().unwrap() + cgmath::vec2(0,0),
-            refresh: UIConstraintRefresh::RefreshAndWait,
-            onclick: None,
-            inner: UIElement::Region {
-                size: FRONT_CANVAS.size().cast().unwrap(),
-                border_px: 2,
-                border_color: color::BLACK,
-            },
-            ..Default::default()
-        },
-    );
+fn on_toggle_text(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    match G_TOOL.load(Ordering::Relaxed) {
+        Tool::Text => select_tool(app, Tool::Brush),
+        _ => select_tool(app, Tool::Text),
+    }
+}
 
-    app.add_element(
-        "backCanvasRegion",
-        UIElementWrapper {
-            position: BACK_CANVAS.top_left().cast().unwrap() + cgmath::vec2(0,0),
-            refresh: UIConstraintRefresh::RefreshAndWait,
-            onclick: None,
-            inner: UIElement::Region {
-                size: BACK_CANVAS.size().cast().unwrap(),
-                border_px: 2,
-                border_color: color::BLACK,
-            },
-            ..Default::default()
-        },
-    );
+fn on_key(app: &mut appctx::ApplicationContext<'_>, element: UIElementHandle) {
+    let key = match element.read().inner {
+        UIElement::Text { ref text, .. } => text.clone(),
+        _ => return,
+    };
+    type_text(app, |entry| {
+        if std::mem::take(&mut entry.shift) {
+            entry.text.push_str(&key.to_uppercase());
+        } else {
+            entry.text.push_str(&key);
+        }
+    });
+}
 
-    // Create the top bar's time and battery labels. We can mutate these later.
-    // Draw the scene
-    app.draw_elements();
+fn on_space_key(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    type_text(app, |entry| entry.text.push(' '));
+}
 
-    // Get a &mut to the framebuffer object, exposing many convenience functions
-    let appref = app.upgrade_ref();
+fn on_delete_key(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    type_text(app, |entry| {
+        entry.text.pop();
+    });
+}
 
-    info!("Init complete. Beginning event dispatch...");
+fn on_shift_key(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    type_text(app, |entry| entry.shift = !entry.shift);
+}
 
-    // Blocking call to process events from digitizer + touchscreen + physical buttons
-    app.start_event_loop(true, true, true, |ctx, evt| match evt {
-        InputEvent::WacomEvent { event } => on_wacom_input(ctx, event),
-       // InputEvent::MultitouchEvent { event } => on_touch_handler(ctx, event),
-        InputEvent::GPIO { event } => on_button_press(ctx, event),
-        _ => {}
-    });
+fn on_cancel_text(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    close_keyboard(app);
+}
+
+fn on_done_text(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    finish_text(app);
+}
+
+/// Takes the keyboard down and puts what was typed on the card
+fn finish_text(app: &mut appctx::ApplicationContext<'_>) {
+    let entry = match close_keyboard(app) {
+        Some(entry) if !entry.text.trim().is_empty() => entry,
+        _ => return,
+    };
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = CANVASES[entry.side].lock().unwrap();
+    let size: u32 = CONFIG.get("text_size", 48);
+    let inner = canvas.inner();
+    // Keep the letters from poking out over the top or bottom of the card
+    let anchor = cgmath::Point2 {
+        x: entry.anchor.x,
+        y: entry
+            .anchor
+            .y
+            .max((inner.top + size) as f32)
+            .min((inner.top + inner.height - size / 3) as f32),
+    };
+    let label = Stroke::label(framebuffer, ink_color(), size, anchor, entry.text);
+    let rect = canvas.commit(framebuffer, label);
+    refresh_view(framebuffer, &rect);
+}
+
+fn on_next_toolbar_page(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let page = TOOLBAR_PAGE.load(Ordering::Relaxed);
+    show_toolbar_page(app, (page + 1) % TOOLBAR_PAGES.len());
+}
+
+/// Swaps the buttons of the current toolbar page for those of `page`
+fn show_toolbar_page(app: &mut appctx::ApplicationContext<'_>, next: usize) {
+    CONFIRMING_CLEAR.store(false, Ordering::Relaxed);
+    let page = TOOLBAR_PAGE.load(Ordering::Relaxed);
+    let cleared = remove_buttons(app, TOOLBAR_PAGES[page]);
+    refresh_chrome(app.get_framebuffer_ref(), &cleared);
+
+    TOOLBAR_PAGE.store(next, Ordering::Relaxed);
+    add_toolbar_page(app, next);
+    for name in TOOLBAR_PAGES[next].iter() {
+        app.draw_element(name);
+    }
+}
+
+fn on_copy_to_other_side(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let side = ACTIVE_SIDE.load(Ordering::Relaxed);
+    let from = CANVASES[side].lock().unwrap();
+    let mut to = CANVASES[1 - side].lock().unwrap();
+    let dx = to.bounds.left as i32 - from.bounds.left as i32;
+    let dy = to.bounds.top as i32 - from.bounds.top as i32;
+    let copies = from.copy_selection(dx, dy);
+    drop(from);
+
+    let framebuffer = app.get_framebuffer_ref();
+    if let Some(rect) = to.paste(framebuffer, copies) {
+        refresh_view(framebuffer, &to.on_screen(&rect));
+    }
+}
+
+fn on_delete_selection(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = active_canvas();
+    if let Some(rect) = LASSO.lock().unwrap().delete(framebuffer, &mut canvas) {
+        refresh_ink(framebuffer, &rect);
+    }
+}
+
+fn on_rotate_selection(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = active_canvas();
+    if let Some(rect) = LASSO.lock().unwrap().rotate(framebuffer, &mut canvas) {
+        refresh_ink(framebuffer, &rect);
+    }
+}
+
+fn on_mirror_selection(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = active_canvas();
+    if let Some(rect) = LASSO.lock().unwrap().mirror(framebuffer, &mut canvas) {
+        refresh_ink(framebuffer, &rect);
+    }
+}
+
+fn on_duplicate_selection(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = active_canvas();
+    if let Some(rect) = LASSO.lock().unwrap().duplicate(framebuffer, &mut canvas) {
+        refresh_ink(framebuffer, &rect);
+    }
+}
+
+fn on_undo(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    undo(app);
+}
+
+fn on_redo(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    redo(app);
+}
+
+fn undo(app: &mut appctx::ApplicationContext<'_>) {
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = active_canvas();
+    if let Some(rect) = LASSO.lock().unwrap().cancel(framebuffer, &mut canvas) {
+        refresh_ink(framebuffer, &rect);
+    }
+    if let Some(rect) = canvas.undo(framebuffer) {
+        refresh_view(framebuffer, &rect);
+    }
+}
+
+fn redo(app: &mut appctx::ApplicationContext<'_>) {
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = active_canvas();
+    if let Some(rect) = LASSO.lock().unwrap().cancel(framebuffer, &mut canvas) {
+        refresh_ink(framebuffer, &rect);
+    }
+    if let Some(rect) = canvas.redo(framebuffer) {
+        refresh_view(framebuffer, &rect);
+    }
+}
+
+fn on_take_over(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let holder = match LOCK_HOLDER_PID.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(pid),
+    };
+    match InstanceLock::take_over(holder) {
+        None => error!("Failed to take over from the running instance"),
+        Some(lock) => {
+            *INSTANCE_LOCK.lock().unwrap() = Some(lock);
+            // Only the prompt needs blanking, the rest of the screen is white
+            // already, so there's no call for a full flashing redraw
+            let prompt = ["instancePrompt", "takeOverButton", "quitButton"];
+            let cleared = remove_buttons(app, &prompt);
+            refresh_view(app.get_framebuffer_ref(), &cleared);
+            add_main_elements(app);
+            app.draw_elements();
+        }
+    }
+}
+
+/// Starts xochitl back up and quits. Stays running if xochitl can't be
+/// started, rather than leave the tablet with nothing on screen.
+fn exit_to_xochitl(app: &mut appctx::ApplicationContext<'_>) {
+    hooks::run(app, Hook::Exit, &[]);
+    let started = Command::new("systemctl")
+        .arg("start")
+        .arg("xochitl")
+        .spawn();
+    if let Err(err) = started {
+        error!("Failed to start xochitl: {0}", err);
+        return;
+    }
+    std::process::exit(0);
+}
+
+fn on_quit(_app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    // The other instance is still running, so xochitl stays stopped
+    std::process::exit(0);
+}
+
+// ####################
+// ## Miscellaneous
+// ####################
+
+/// Called whenever the pen stops drawing. Commits the stroke in progress, if
+/// any, so it can be undone.
+fn finish_stroke(app: &mut appctx::ApplicationContext<'_>) {
+    HOLD.lock().unwrap().reset();
+    if let Some(filter) = SMOOTHING.lock().unwrap().as_mut() {
+        filter.reset();
+    }
+    refresh::finish_ink(app.get_framebuffer_ref());
+    let labels = std::mem::take(&mut *DEFERRED_LABELS.lock().unwrap());
+    for (name, label) in labels {
+        set_button_label(app, &name, label);
+    }
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = active_canvas();
+    let drawn = canvas.pen_up(framebuffer).map(|s| (s.color, s.rect));
+    if let Some((col, rect)) = drawn {
+        // DU can only show black and white, so grays drawn while the pen
+        // was moving need a proper grayscale pass once it lifts
+        if let color::GRAY(_) = col {
+            refresh_gray(framebuffer, &canvas.on_screen(&rect));
+        } else if canvas.is_covered(&rect)
+            || (col == color::WHITE && canvas.guide() != Guide::None)
+        {
+            // Notes crossing the stroke were painted back over it, or the
+            // guide dotted back in where it was rubbed out
+            refresh_ink(framebuffer, &canvas.on_screen(&rect));
+        }
+    }
+    match G_TOOL.load(Ordering::Relaxed) {
+        Tool::Lasso => {
+            if let Some(rect) = LASSO.lock().unwrap().on_pen_up(framebuffer, &mut canvas) {
+                refresh_ink(framebuffer, &rect);
+            }
+        }
+        Tool::Shape(shape) => {
+            let col = ink_color();
+            let mult = G_DRAW_MODE.load(Ordering::Relaxed).get_size();
+            let drawn = SHAPE_TOOL
+                .lock()
+                .unwrap()
+                .on_pen_up(framebuffer, &mut canvas, shape, col, mult);
+            if let Some(rect) = drawn {
+                refresh_view(framebuffer, &rect);
+            }
+        }
+        Tool::Zoom => {
+            if let Some((start, end)) = TOOL_DRAG.lock().unwrap().take() {
+                let view = canvas.view();
+                let (zoom, center) = if (end - start).magnitude() < TAP_SLOP {
+                    let next = view.zoom.floor() + 1.0;
+                    let next = if next > canvas::MAX_ZOOM { 1.0 } else { next };
+                    (next, canvas.to_canvas(start))
+                } else {
+                    let inner = canvas.inner();
+                    let middle = canvas.to_canvas(cgmath::Point2 {
+                        x: (inner.left + inner.width / 2) as f32,
+                        y: (inner.top + inner.height / 2) as f32,
+                    });
+                    (view.zoom, middle - (end - start) / view.zoom)
+                };
+                let rect = canvas.set_view(framebuffer, zoom, center);
+                refresh_view(framebuffer, &rect);
+                drop(canvas);
+                show_zoom(app, zoom);
+            }
+        }
+        Tool::Stamp(stamp) => {
+            if let Some((start, end)) = TOOL_DRAG.lock().unwrap().take() {
+                let reach = (end - start).magnitude();
+                let size = if reach < TAP_SLOP {
+                    CONFIG.get("stamp_size", 80.0)
+                } else {
+                    reach * 2.0
+                };
+                let mult = G_DRAW_MODE.load(Ordering::Relaxed).get_size();
+                let strokes = stamp.strokes(start, size, ink_color(), mult);
+                if let Some(rect) = canvas.paste(framebuffer, strokes) {
+                    refresh_view(framebuffer, &rect);
+                }
+            }
+        }
+        Tool::Image => {
+            let (lifted, drag) = SHAPE_TOOL.lock().unwrap().take_drag(framebuffer);
+            refresh_ink(framebuffer, &lifted);
+            let path = IMAGE_CHOICE.lock().unwrap().clone();
+            if let (Some((start, end)), Some(path)) = (drag, path) {
+                let inner = canvas.inner();
+                let pasted = picture::load(&path, start, end, &inner)
+                    .and_then(|strokes| canvas.paste(framebuffer, strokes));
+                if let Some(rect) = pasted {
+                    refresh_view(framebuffer, &rect);
+                }
+            }
+        }
+        Tool::Brush | Tool::Fill | Tool::Text => {}
+    }
+}
+
+/// Takes buttons off the screen along with their active regions. Returns the
+/// area blanked, which still needs refreshing.
+fn remove_buttons<S: AsRef<str>>(
+    app: &mut appctx::ApplicationContext<'_>,
+    names: &[S],
+) -> mxcfb_rect {
+    let framebuffer = app.get_framebuffer_ref();
+    let mut cleared = mxcfb_rect::invalid();
+    for name in names.iter() {
+        let name = name.as_ref();
+        if let Some(element) = app.get_element_by_name(name) {
+            if let Some(rect) = element.read().last_drawn_rect {
+                // Relabelling a button leaves its old active region behind
+                let (y, x) = (rect.top + rect.height / 2, rect.left + rect.width / 2);
+                while app.remove_active_region_at_point(y as u16, x as u16) {}
+                framebuffer.fill_rect(rect.top_left().cast().unwrap(), rect.size(), color::WHITE);
+                cleared = cleared.merge_rect(&rect);
+            }
+        }
+        app.remove_element(name);
+    }
+    cleared
+}
+
+/// Puts the keyboard up over the other side of the card, for typing a label
+/// at `anchor` on this one
+fn open_keyboard(
+    app: &mut appctx::ApplicationContext<'_>,
+    side: usize,
+    anchor: cgmath::Point2<f32>,
+) {
+    *TEXT_ENTRY.lock().unwrap() = Some(TextEntry {
+        side,
+        anchor,
+        text: String::new(),
+        shift: false,
+    });
+    let framebuffer = app.get_framebuffer_ref();
+    let area = CANVASES[1 - side].lock().unwrap().inner();
+    framebuffer.fill_rect(area.top_left().cast().unwrap(), area.size(), color::WHITE);
+    refresh_ink(framebuffer, &area);
+
+    // Rows close up when the divider leaves the other side short
+    let top = area.top as i32;
+    let pitch = ((area.height as i32 - 100) / 6).min(120);
+    add_key(app, "textPreview", 114, top + pitch - 20, preview_label("", false), None);
+    for (row, keys) in KEYBOARD_ROWS.iter().enumerate() {
+        for (column, key) in keys.chars().enumerate() {
+            let (x, y) = (114 + column as i32 * 120, top + (2 + row as i32) * pitch);
+            add_key(app, &key_name(key), x, y, key.to_string(), Some(on_key));
+        }
+    }
+    let bottom = top + 6 * pitch + 20;
+    add_key(app, "shiftKey", 114, bottom, "Shift".to_owned(), Some(on_shift_key));
+    add_key(app, "spaceKey", 354, bottom, "Space".to_owned(), Some(on_space_key));
+    add_key(app, "deleteKey", 594, bottom, "Delete".to_owned(), Some(on_delete_key));
+    add_key(app, "cancelKey", 834, bottom, "Cancel".to_owned(), Some(on_cancel_text));
+    add_key(app, "doneKey", 1094, bottom, "Done".to_owned(), Some(on_done_text));
+    for name in keyboard_elements() {
+        app.draw_element(&name);
+    }
+    // Pick up a keyboard paired since the last time
+    KEYBOARDS.lock().unwrap().scan();
+}
+
+/// Takes the keyboard down and puts back the side of the card under it.
+/// Returns what was typed, if it was up.
+fn close_keyboard(app: &mut appctx::ApplicationContext<'_>) -> Option<TextEntry> {
+    let entry = TEXT_ENTRY.lock().unwrap().take()?;
+    remove_buttons(app, &keyboard_elements());
+    let framebuffer = app.get_framebuffer_ref();
+    let mut covered = CANVASES[1 - entry.side].lock().unwrap();
+    let area = covered.inner();
+    covered.repaint(framebuffer, area);
+    refresh_view(framebuffer, &area);
+    Some(entry)
+}
+
+/// Applies a key press to the label being typed and shows the result
+fn type_text<F: FnOnce(&mut TextEntry)>(app: &mut appctx::ApplicationContext<'_>, press: F) {
+    let (preview, shift) = match TEXT_ENTRY.lock().unwrap().as_mut() {
+        None => return,
+        Some(entry) => {
+            press(entry);
+            (preview_label(&entry.text, entry.shift), entry.shift)
+        }
+    };
+    let shift = if shift { "SHIFT" } else { "Shift" };
+    for (name, label) in [("textPreview", preview), ("shiftKey", shift.to_owned())] {
+        if let Some(element) = app.get_element_by_name(name) {
+            if let UIElement::Text { ref mut text, .. } = element.write().inner {
+                *text = label;
+            }
+            app.draw_element(name);
+        }
+    }
+}
+
+/// Types whatever came in from external keyboards into the open text entry.
+/// Keys typed with it closed are dropped.
+fn check_keyboards(app: &mut appctx::ApplicationContext<'_>) {
+    loop {
+        let press = match KEYBOARDS.lock().unwrap().try_recv() {
+            Some(press) => press,
+            None => return,
+        };
+        if TEXT_ENTRY.lock().unwrap().is_none() {
+            continue;
+        }
+        match press {
+            KeyPress::Char(c) => type_text(app, |entry| entry.text.push(c)),
+            KeyPress::Backspace => type_text(app, |entry| {
+                entry.text.pop();
+            }),
+            KeyPress::Enter => finish_text(app),
+            KeyPress::Escape => {
+                close_keyboard(app);
+            }
+        }
+    }
+}
+
+fn key_name(key: char) -> String {
+    format!("key{0}", key)
+}
+
+/// Names of everything drawn as part of the keyboard
+fn keyboard_elements() -> Vec<String> {
+    let mut names: Vec<String> = KEYBOARD_ROWS
+        .iter()
+        .flat_map(|keys| keys.chars().map(key_name))
+        .collect();
+    for name in ["textPreview", "shiftKey", "spaceKey", "deleteKey", "cancelKey", "doneKey"] {
+        names.push(name.to_owned());
+    }
+    names
+}
+
+/// Puts the clear button back to how it was before its first tap
+fn end_clear_confirmation(app: &mut appctx::ApplicationContext<'_>) {
+    CONFIRMING_CLEAR.store(false, Ordering::Relaxed);
+    let cleared = remove_buttons(app, &["cancelClearButton"]);
+    if cleared != mxcfb_rect::invalid() {
+        refresh_ink(app.get_framebuffer_ref(), &cleared);
+    }
+    set_button_label(app, "clearButton", clear_label(ACTIVE_SIDE.load(Ordering::Relaxed)));
+}
+
+/// Where the front and back of the card go with the divider where it is now
+fn card_sides() -> [mxcfb_rect; 2] {
+    let room = CARD_AREA.height - DIVIDER_PX;
+    let front = room * FRONT_SHARE.load(Ordering::Relaxed) / 100;
+    [
+        mxcfb_rect {
+            height: front,
+            ..CARD_AREA
+        },
+        mxcfb_rect {
+            top: CARD_AREA.top + front + DIVIDER_PX,
+            height: room - front,
+            ..CARD_AREA
+        },
+    ]
+}
+
+/// The side of the card at the screen point `pos`, if any
+fn side_at(pos: cgmath::Point2<f32>) -> Option<usize> {
+    card_sides()
+        .iter()
+        .position(|bounds| bounds.contains_point(&pos.cast().unwrap()))
+}
+
+fn active_canvas() -> MutexGuard<'static, Canvas> {
+    CANVASES[ACTIVE_SIDE.load(Ordering::Relaxed)].lock().unwrap()
+}
+
+/// Moves the pen over to the other side of the card, finishing up whatever
+/// it was doing on this one first
+fn switch_side(app: &mut appctx::ApplicationContext<'_>, side: usize) {
+    finish_stroke(app);
+    {
+        let framebuffer = app.get_framebuffer_ref();
+        let mut canvas = active_canvas();
+        if let Some(rect) = LASSO.lock().unwrap().cancel(framebuffer, &mut canvas) {
+            refresh_ink(framebuffer, &rect);
+        }
+    }
+    ACTIVE_SIDE.store(side, Ordering::Relaxed);
+
+    let (zoom, layer) = {
+        let canvas = active_canvas();
+        (canvas.view().zoom, canvas.layer())
+    };
+    show_zoom(app, zoom);
+    for (name, label) in [
+        ("layerButton", layer_label(layer)),
+        ("copyButton", copy_label(side)),
+    ] {
+        set_button_label(app, name, label);
+    }
+    end_clear_confirmation(app);
+}
+
+/// The pen's tilt in degrees along the screen axes. The digitizer is mounted
+/// turned a quarter turn from the display, as its positions are.
+fn screen_tilt(tilt: cgmath::Vector2<u16>) -> cgmath::Vector2<f32> {
+    // Each axis reports -9000..9000 hundredths of a degree
+    let (x, y) = (tilt.x as i16 as f32 / 100.0, tilt.y as i16 as f32 / 100.0);
+    cgmath::vec2(y, -x)
+}
+
+/// The eraser of the chosen kind, at brush size `size`
+fn eraser_mode(size: u32) -> DrawMode {
+    match ERASE_STROKES.load(Ordering::Relaxed) {
+        true => DrawMode::EraseStrokes(size),
+        false => DrawMode::Erase(size),
+    }
+}
+
+fn ink_color() -> color {
+    match G_INK_LEVEL.load(Ordering::Relaxed) {
+        255 => color::BLACK,
+        level => color::GRAY(level),
+    }
+}
+
+fn ink_level_label(level: u8) -> String {
+    format!("Ink {0}%", (level as u32 * 100 + 127) / 255)
+}
+
+fn select_tool(app: &mut appctx::ApplicationContext<'_>, tool: Tool) {
+    let previous = G_TOOL.swap(tool, Ordering::Relaxed);
+    if previous == Tool::Lasso && tool != Tool::Lasso {
+        let framebuffer = app.get_framebuffer_ref();
+        let mut canvas = active_canvas();
+        if let Some(rect) = LASSO.lock().unwrap().cancel(framebuffer, &mut canvas) {
+            refresh_ink(framebuffer, &rect);
+        }
+    }
+    if previous == Tool::Text && tool != Tool::Text {
+        close_keyboard(app);
+    }
+    if previous != tool {
+        for (name, label) in [
+            ("shapeButton", shape_label(tool)),
+            ("stampButton", stamp_label(tool)),
+            ("imageButton", image_label(tool)),
+        ] {
+            set_button_label(app, name, label);
+        }
+    }
+
+    // Only the brush works through a zoomed view
+    if !matches!(tool, Tool::Brush | Tool::Zoom) {
+        let framebuffer = app.get_framebuffer_ref();
+        let mut canvas = active_canvas();
+        if canvas.zoomed() {
+            let center = canvas.view().origin;
+            let rect = canvas.set_view(framebuffer, 1.0, center);
+            refresh_view(framebuffer, &rect);
+            drop(canvas);
+            show_zoom(app, 1.0);
+        }
+    }
+}
+
+/// Zooms the side of the card under the screen point `pos` from its current
+/// zoom to `zoom(current)`, keeping whatever is under `pos` where it is
+fn zoom_around<F: FnOnce(f32) -> f32>(
+    app: &mut appctx::ApplicationContext<'_>,
+    pos: cgmath::Point2<f32>,
+    zoom: F,
+) {
+    // Only the brush works through a zoomed view
+    if !matches!(G_TOOL.load(Ordering::Relaxed), Tool::Brush | Tool::Zoom) {
+        return;
+    }
+    let side = match side_at(pos) {
+        Some(side) => side,
+        None => return,
+    };
+    if side != ACTIVE_SIDE.load(Ordering::Relaxed) {
+        switch_side(app, side);
+    }
+
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = active_canvas();
+    let zoom = zoom(canvas.view().zoom).clamp(1.0, canvas::MAX_ZOOM);
+    let inner = canvas.inner();
+    let corner: cgmath::Point2<f32> = inner.top_left().cast().unwrap();
+    let half = cgmath::vec2(inner.width as f32, inner.height as f32) / (2.0 * zoom);
+    let center = canvas.to_canvas(pos) - (pos - corner) / zoom + half;
+    let rect = canvas.set_view(framebuffer, zoom, center);
+    refresh_view(framebuffer, &rect);
+    drop(canvas);
+    show_zoom(app, zoom);
+}
+
+fn show_zoom(app: &mut appctx::ApplicationContext<'_>, zoom: f32) {
+    set_button_label(app, "zoomButton", zoom_label(zoom));
+}
+
+fn preset_label(index: Option<usize>) -> String {
+    match index {
+        Some(i) => format!("Preset {0}", i + 1),
+        None => "Presets".to_owned(),
+    }
+}
+
+/// The typed text with a cursor after it, which also keeps the preview from
+/// being empty
+fn preview_label(text: &str, shift: bool) -> String {
+    format!("{0}{1}", text, if shift { "^" } else { "_" })
+}
+
+fn eraser_kind_label(strokes: bool) -> String {
+    match strokes {
+        true => "Erases: Strokes",
+        false => "Erases: Pixels",
+    }
+    .to_owned()
+}
+
+fn split_label(share: u32) -> String {
+    format!("Split: {0}/{1}", share, 100 - share)
+}
+
+fn guide_label(guide: Guide) -> String {
+    match guide {
+        Guide::None => "Guide: Off",
+        Guide::Grid => "Guide: Grid",
+        Guide::Ruled => "Guide: Ruled",
+    }
+    .to_owned()
+}
+
+fn clear_label(side: usize) -> String {
+    match side {
+        0 => "Clear front",
+        _ => "Clear back",
+    }
+    .to_owned()
+}
+
+fn copy_label(side: usize) -> String {
+    match side {
+        0 => "Copy to back",
+        _ => "Copy to front",
+    }
+    .to_owned()
+}
+
+fn layer_label(layer: Layer) -> String {
+    match layer {
+        Layer::Diagram => "Layer: Diagram",
+        Layer::Notes => "Layer: Notes",
+    }
+    .to_owned()
+}
+
+fn zoom_label(zoom: f32) -> String {
+    if zoom == 1.0 {
+        "Zoom".to_owned()
+    } else {
+        format!("Zoom {0}x", zoom)
+    }
+}
+
+fn shape_label(tool: Tool) -> String {
+    match tool {
+        Tool::Shape(Shape::Line) => "Line",
+        Tool::Shape(Shape::Rectangle) => "Rectangle",
+        Tool::Shape(Shape::Ellipse) => "Ellipse",
+        Tool::Shape(Shape::Arrow) => "Arrow",
+        Tool::Brush
+        | Tool::Lasso
+        | Tool::Fill
+        | Tool::Zoom
+        | Tool::Text
+        | Tool::Stamp(_)
+        | Tool::Image => "Shapes",
+    }
+    .to_owned()
+}
+
+fn image_label(tool: Tool) -> String {
+    let choice = IMAGE_CHOICE.lock().unwrap();
+    match (tool, choice.as_ref().and_then(|path| path.file_name())) {
+        (Tool::Image, Some(name)) => {
+            let name: String = name.to_string_lossy().chars().take(12).collect();
+            format!("Image: {0}", name)
+        }
+        _ => "Image".to_owned(),
+    }
+}
+
+fn stamp_label(tool: Tool) -> String {
+    match tool {
+        Tool::Stamp(stamp) => format!("Stamp: {0}", stamp.name()),
+        _ => "Stamps".to_owned(),
+    }
+}
+
+fn change_brush_width(app: &mut appctx::ApplicationContext<'_>, delta: i32) {
+    let current = G_DRAW_MODE.load(Ordering::Relaxed);
+    let current_size = current.get_size() as i32;
+    let proposed_size = current_size + delta;
+    let new_size = if proposed_size < 1 {
+        1
+    } else if proposed_size > 99 {
+        99
+    } else {
+        proposed_size
+    };
+    if new_size == current_size {
+        return;
+    }
+
+    G_DRAW_MODE.store(current.set_size(new_size as u32), Ordering::Relaxed);
+}
+
+// ####################
+// ## Input Handlers
+// ####################
+
+fn on_wacom_input(app: &mut appctx::ApplicationContext<'_>, input: input::WacomEvent) {
+    PALM.lock().unwrap().saw_pen();
+    match input {
+        input::WacomEvent::Draw {
+            position,
+            pressure,
+            tilt,
+        } => {
+            // Some pens report a faint touch while barely grazing the screen
+            if pressure < *MIN_PRESSURE {
+                pen_lifted(app);
+                show_brush_size(app, position);
+                return;
+            }
+
+            let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
+            *LAST_DRAW.lock().unwrap() = Some(Instant::now());
+
+            // This is so that we can click the buttons outside the canvas region
+            // normally meant to be touched with a finger using our stylus. The
+            // keyboard and context menu sit over a canvas, so while either is
+            // up the pen only presses buttons.
+            let side = side_at(position);
+            let typing = TEXT_ENTRY.lock().unwrap().is_some();
+            let menu = CONTEXT_MENU.lock().unwrap().is_some() || DRAWER_OPEN.load(Ordering::Relaxed);
+            if side.is_none() || INSTANCE_LOCK.lock().unwrap().is_none() || typing || menu {
+                wacom_stack.clear();
+                finish_stroke(app);
+                if UNPRESS_OBSERVED.fetch_and(false, Ordering::Relaxed) {
+                    let region = app
+                        .find_active_region(position.y.round() as u16, position.x.round() as u16);
+                    let element = region.map(|(region, _)| region.element.clone());
+                    if let Some(element) = element {
+                        (region.unwrap().0.handler)(app, element)
+                    }
+                }
+                return;
+            }
+            if side != Some(ACTIVE_SIDE.load(Ordering::Relaxed)) {
+                wacom_stack.clear();
+                switch_side(app, side.unwrap());
+            }
+
+            if G_TOOL.load(Ordering::Relaxed) == Tool::Lasso
+                && !WACOM_RUBBER_SIDE.load(Ordering::Relaxed)
+            {
+                let framebuffer = app.get_framebuffer_ref();
+                let mut canvas = active_canvas();
+                if let Some(rect) = LASSO.lock().unwrap().on_draw(framebuffer, &mut canvas, position) {
+                    refresh_ink(framebuffer, &rect);
+                }
+                return;
+            }
+
+            if let Tool::Shape(shape) = G_TOOL.load(Ordering::Relaxed) {
+                if !WACOM_RUBBER_SIDE.load(Ordering::Relaxed) {
+                    let framebuffer = app.get_framebuffer_ref();
+                    let canvas = active_canvas();
+                    let mult = G_DRAW_MODE.load(Ordering::Relaxed).get_size();
+                    let preview = SHAPE_TOOL
+                        .lock()
+                        .unwrap()
+                        .on_draw(framebuffer, &canvas, shape, mult, position);
+                    if let Some(rect) = preview {
+                        refresh_ink(framebuffer, &rect);
+                    }
+                    return;
+                }
+            }
+
+            if G_TOOL.load(Ordering::Relaxed) == Tool::Image
+                && !WACOM_RUBBER_SIDE.load(Ordering::Relaxed)
+            {
+                let framebuffer = app.get_framebuffer_ref();
+                let canvas = active_canvas();
+                let preview = SHAPE_TOOL.lock().unwrap().on_draw(
+                    framebuffer,
+                    &canvas,
+                    Shape::Rectangle,
+                    1,
+                    position,
+                );
+                if let Some(rect) = preview {
+                    refresh_ink(framebuffer, &rect);
+                }
+                return;
+            }
+
+            // Fills with the ink, grays coming out as a dither pattern, or
+            // with white from the rubber end. Once per tap, not per sample.
+            if G_TOOL.load(Ordering::Relaxed) == Tool::Fill {
+                if UNPRESS_OBSERVED.fetch_and(false, Ordering::Relaxed) {
+                    let col = match WACOM_RUBBER_SIDE.load(Ordering::Relaxed) {
+                        true => color::WHITE,
+                        false => ink_color(),
+                    };
+                    let framebuffer = app.get_framebuffer_ref();
+                    let filled = active_canvas().fill(framebuffer, position, col);
+                    if let Some(rect) = filled {
+                        refresh_ink(framebuffer, &rect);
+                    }
+                }
+                return;
+            }
+
+            if G_TOOL.load(Ordering::Relaxed) == Tool::Text {
+                if UNPRESS_OBSERVED.fetch_and(false, Ordering::Relaxed) {
+                    open_keyboard(app, side.unwrap(), position);
+                }
+                return;
+            }
+
+            let dragging = match G_TOOL.load(Ordering::Relaxed) {
+                Tool::Zoom => true,
+                Tool::Stamp(_) => !WACOM_RUBBER_SIDE.load(Ordering::Relaxed),
+                _ => false,
+            };
+            if dragging {
+                let mut drag = TOOL_DRAG.lock().unwrap();
+                let start = drag.map_or(position, |(start, _)| start);
+                *drag = Some((start, position));
+                return;
+            }
+
+            let rubber = WACOM_RUBBER_SIDE.load(Ordering::Relaxed);
+            let mut brush = Brush::Pen;
+            let (col, mult) = match G_DRAW_MODE.load(Ordering::Relaxed) {
+                // The rubber end erases in every mode
+                _ if rubber => (color::WHITE, *RUBBER_SIZE),
+                DrawMode::Draw(s) => (ink_color(), s),
+                DrawMode::Pencil(s) => {
+                    brush = Brush::Pencil;
+                    (ink_color(), s)
+                }
+                DrawMode::Erase(s) => (color::WHITE, s * 3),
+                DrawMode::Highlight(s) => {
+                    brush = Brush::Highlighter;
+                    (color::GRAY(HIGHLIGHTER_LEVEL), s * 12)
+                }
+                DrawMode::EraseStrokes(s) => {
+                    let framebuffer = app.get_framebuffer_ref();
+                    let erased = active_canvas().erase_strokes_at(
+                        framebuffer,
+                        position,
+                        s as f32 * 1.5,
+                    );
+                    if let Some(rect) = erased {
+                        refresh_ink(framebuffer, &rect);
+                    }
+                    return;
+                }
+            };
+
+            // White ink would wipe out the diagram under the notes too, so on
+            // the notes layer erasing takes whole strokes instead
+            let rubber_strokes = rubber && *RUBBER_STROKES;
+            if col == color::WHITE && (rubber_strokes || active_canvas().layer() == Layer::Notes) {
+                let framebuffer = app.get_framebuffer_ref();
+                let erased = active_canvas().erase_strokes_at(
+                    framebuffer,
+                    position,
+                    mult as f32 / 2.0,
+                );
+                if let Some(rect) = erased {
+                    refresh_ink(framebuffer, &rect);
+                }
+                return;
+            }
+
+            let mut hold = HOLD.lock().unwrap();
+            if hold.snapped() {
+                return;
+            }
+
+            let position = match SMOOTHING.lock().unwrap().as_mut() {
+                Some(filter) => filter.filter(position, PEN_REPORTS.load(Ordering::Relaxed)),
+                None => position,
+            };
+            let point = (position.cast().unwrap(), pressure as i32);
+            let speed = wacom_stack.back().map_or(Speed::Normal, |last| {
+                refresh::speed((point.0 - last.0).magnitude())
+            });
+            wacom_stack.push_back(point);
+
+            let tilt = screen_tilt(tilt);
+            let mut canvas = active_canvas();
+            let bounds = canvas.bounds;
+            let stroke = canvas.current_stroke(col, mult, brush);
+            stroke.points.push(point);
+            if brush == Brush::Pencil {
+                stroke.tilts.push(tilt);
+            }
+
+            while wacom_stack.len() >= 3 {
+                let framebuffer = app.get_framebuffer_ref();
+                let points = [
+                    wacom_stack.pop_front().unwrap(),
+                    *wacom_stack.get(0).unwrap(),
+                    *wacom_stack.get(1).unwrap(),
+                ];
+                let rect = latency::timed(Stage::Segment, || {
+                    stroke.draw_segment(framebuffer, &bounds, points, tilt)
+                });
+                refresh::queue_ink(framebuffer, rect, speed);
+            }
+
+            // Resting the pen at the end of a stroke snaps it to a shape
+            if col != color::WHITE
+                && !WACOM_RUBBER_SIDE.load(Ordering::Relaxed)
+                && hold.held(position)
+            {
+                let framebuffer = app.get_framebuffer_ref();
+                if let Some(rect) = canvas.straighten_current(framebuffer) {
+                    hold.set_snapped();
+                    wacom_stack.clear();
+                    refresh_ink(framebuffer, &rect);
+                }
+            }
+        }
+        input::WacomEvent::InstrumentChange { pen, state } => {
+            match pen {
+                // Whether the pen is in range
+                input::WacomPen::ToolPen => {
+                    WACOM_IN_RANGE.store(state, Ordering::Relaxed);
+                    WACOM_RUBBER_SIDE.store(false, Ordering::Relaxed);
+                }
+                input::WacomPen::ToolRubber => {
+                    WACOM_IN_RANGE.store(state, Ordering::Relaxed);
+                    WACOM_RUBBER_SIDE.store(true, Ordering::Relaxed);
+                }
+                // Whether the pen is actually making contact
+                input::WacomPen::Touch => {
+                    // Stop drawing when instrument has left the vicinity of the screen
+                    if !state {
+                        let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
+                        *LAST_DRAW.lock().unwrap() = None;
+                        wacom_stack.clear();
+                        finish_stroke(app);
+                    }
+                }
+                // Barrel buttons, on pens that have them
+                input::WacomPen::Stylus => on_stylus_button(app, STYLUS_ACTIONS[0], state),
+                input::WacomPen::Stylus2 => on_stylus_button(app, STYLUS_ACTIONS[1], state),
+            }
+        }
+        input::WacomEvent::Hover {
+            position,
+            distance,
+            tilt: _,
+        } => {
+            // If the pen is hovering, don't record its coordinates as the origin of the next line
+            if distance > 1 {
+                pen_lifted(app);
+            }
+            show_brush_size(app, position);
+        }
+        _ => {}
+    };
+}
+
+/// Ends the stroke in progress and readies the pen for its next tap. A
+/// hovering pen reports this over and over, but only the first report after
+/// drawing has a stroke to finish.
+fn pen_lifted(app: &mut appctx::ApplicationContext<'_>) {
+    let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
+    let drawing = LAST_DRAW.lock().unwrap().take().is_some();
+    wacom_stack.clear();
+    if drawing {
+        finish_stroke(app);
+    }
+    UNPRESS_OBSERVED.store(true, Ordering::Relaxed);
+}
+
+/// Outlines how wide the brush would draw under the hovering pen
+fn show_brush_size(app: &mut appctx::ApplicationContext<'_>, position: cgmath::Point2<f32>) {
+    let side = match side_at(position) {
+        Some(side) => side,
+        None => return,
+    };
+    // The rubber end erases whatever the tool, and gets a heavier outline so
+    // it's plain which end is down
+    let rubber = WACOM_RUBBER_SIDE.load(Ordering::Relaxed);
+    if (G_TOOL.load(Ordering::Relaxed) != Tool::Brush && !rubber)
+        || INSTANCE_LOCK.lock().unwrap().is_none()
+        || TEXT_ENTRY.lock().unwrap().is_some()
+        || CONTEXT_MENU.lock().unwrap().is_some()
+        || DRAWER_OPEN.load(Ordering::Relaxed)
+    {
+        return;
+    }
+    let framebuffer = app.get_framebuffer_ref();
+    let inner = CANVASES[side].lock().unwrap().inner();
+    let thickness = if rubber { 4 } else { 1 };
+    let shown = HOVER
+        .lock()
+        .unwrap()
+        .show(framebuffer, position, brush_diameter(), thickness, &inner);
+    if let Some(rect) = shown {
+        refresh_ink(framebuffer, &rect);
+    }
+}
+
+/// Takes the hover outline off before anything else is drawn. The refresh
+/// waits for `show_brush_size` to put it back up, or for
+/// `refresh_cleared_brush_size` if it doesn't.
+fn clear_brush_size(app: &mut appctx::ApplicationContext<'_>) {
+    HOVER.lock().unwrap().clear(app.get_framebuffer_ref());
+}
+
+/// Refreshes where the hover outline came off and wasn't put back up
+fn refresh_cleared_brush_size(app: &mut appctx::ApplicationContext<'_>) {
+    if let Some(rect) = HOVER.lock().unwrap().take_cleared() {
+        refresh_ink(app.get_framebuffer_ref(), &rect);
+    }
+}
+
+/// How wide the brush draws, or rubs out, in the current mode. Follows the
+/// sizes picked in the wacom Draw handler.
+fn brush_diameter() -> u32 {
+    if WACOM_RUBBER_SIDE.load(Ordering::Relaxed) {
+        return *RUBBER_SIZE;
+    }
+    match G_DRAW_MODE.load(Ordering::Relaxed) {
+        DrawMode::Draw(s) | DrawMode::Pencil(s) => s,
+        DrawMode::Erase(s) | DrawMode::EraseStrokes(s) => s * 3,
+        DrawMode::Highlight(s) => s * 12,
+    }
+}
+
+/// Ends the stroke in progress if the pen has stopped drawing without a
+/// pen-up. Otherwise the next stroke would be joined on to it.
+fn check_pen_up_timeout(app: &mut appctx::ApplicationContext<'_>) {
+    let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
+    {
+        let mut last_draw = LAST_DRAW.lock().unwrap();
+        match *last_draw {
+            Some(time) if time.elapsed() >= *PEN_UP_TIMEOUT => *last_draw = None,
+            _ => return,
+        }
+    }
+    wacom_stack.clear();
+    finish_stroke(app);
+}
+
+fn on_touch(app: &mut appctx::ApplicationContext<'_>, input: input::MultitouchEvent) {
+    if !PALM
+        .lock()
+        .unwrap()
+        .accept(&input, WACOM_IN_RANGE.load(Ordering::Relaxed))
+    {
+        return;
+    }
+    let gesture = GESTURES.lock().unwrap().on_event(&input);
+    if let Some(gesture) = gesture {
+        on_gesture(app, gesture);
+    }
+}
+
+/// Puts up a menu of things to do at the screen point `pos` on the canvas
+fn open_context_menu(app: &mut appctx::ApplicationContext<'_>, pos: cgmath::Point2<f32>) {
+    if side_at(pos).is_none() || TEXT_ENTRY.lock().unwrap().is_some() {
+        return;
+    }
+    {
+        let mut menu = CONTEXT_MENU.lock().unwrap();
+        if menu.is_some() {
+            return;
+        }
+        *menu = Some(pos);
+    }
+    let info = format!(
+        "Front: {0} strokes, back: {1}",
+        CANVASES[0].lock().unwrap().stroke_count(),
+        CANVASES[1].lock().unwrap().stroke_count()
+    );
+
+    // Kept on screen when opened near the bottom or right edge
+    let x = (pos.x as i32).min(CARD_AREA.left as i32 + CARD_AREA.width as i32 - 700);
+    let y = (pos.y as i32).min(CARD_AREA.top as i32 + CARD_AREA.height as i32 - 420) + 60;
+    add_key(app, "menuInfo", x, y, info, None);
+    add_key(app, "menuImage", x, y + 100, "Insert image here".to_owned(), Some(on_menu_image));
+    add_key(app, "menuPaste", x, y + 200, "Paste selection".to_owned(), Some(on_menu_paste));
+    add_key(app, "menuClose", x, y + 300, "Close".to_owned(), Some(on_menu_close));
+    for name in MENU_ELEMENTS.iter() {
+        app.draw_element(name);
+    }
+}
+
+/// Takes the context menu down and repaints the canvas under it. Returns
+/// where it was opened.
+fn close_context_menu(app: &mut appctx::ApplicationContext<'_>) -> Option<cgmath::Point2<f32>> {
+    let pos = CONTEXT_MENU.lock().unwrap().take()?;
+    let cleared = remove_buttons(app, &MENU_ELEMENTS);
+    let framebuffer = app.get_framebuffer_ref();
+    for canvas in CANVASES.iter() {
+        let mut canvas = canvas.lock().unwrap();
+        if let Some(rect) = canvas::intersection(&cleared, &canvas.inner()) {
+            let rect = canvas.repaint(framebuffer, rect);
+            refresh_view(framebuffer, &rect);
+        }
+    }
+    Some(pos)
+}
+
+fn on_menu_close(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    close_context_menu(app);
+}
+
+/// Puts the chosen image, or else the first one there is, down at its own
+/// size where the menu was opened
+fn on_menu_image(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let pos = match close_context_menu(app) {
+        Some(pos) => pos,
+        None => return,
+    };
+    let side = match side_at(pos) {
+        Some(side) => side,
+        None => return,
+    };
+    let choice = IMAGE_CHOICE.lock().unwrap().clone();
+    let path = match choice.or_else(|| picture::list(&picture::dir()).into_iter().next()) {
+        Some(path) => path,
+        None => {
+            warn!("No images to insert in {0}", picture::dir().display());
+            return;
+        }
+    };
+    let framebuffer = app.get_framebuffer_ref();
+    let mut canvas = CANVASES[side].lock().unwrap();
+    let at = canvas.to_canvas(pos);
+    let inner = canvas.inner();
+    let pasted = picture::load(&path, at, at, &inner)
+        .and_then(|strokes| canvas.paste(framebuffer, strokes));
+    if let Some(rect) = pasted {
+        refresh_view(framebuffer, &canvas.on_screen(&rect));
+    }
+}
+
+/// Pastes a copy of the lasso selection with its top left corner where the
+/// menu was opened, on whichever side that was
+fn on_menu_paste(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let pos = match close_context_menu(app) {
+        Some(pos) => pos,
+        None => return,
+    };
+    let side = match side_at(pos) {
+        Some(side) => side,
+        None => return,
+    };
+    let framebuffer = app.get_framebuffer_ref();
+    let active = ACTIVE_SIDE.load(Ordering::Relaxed);
+    let mut from = CANVASES[active].lock().unwrap();
+    let selection = match from.selection_rect() {
+        Some(selection) => selection,
+        None => return,
+    };
+    let mut to = match side == active {
+        true => None,
+        false => Some(CANVASES[side].lock().unwrap()),
+    };
+    let at = to.as_ref().unwrap_or(&from).to_canvas(pos);
+    let copies = from.copy_selection(
+        at.x as i32 - selection.left as i32,
+        at.y as i32 - selection.top as i32,
+    );
+    if let Some(rect) = LASSO.lock().unwrap().cancel(framebuffer, &mut from) {
+        refresh_ink(framebuffer, &rect);
+    }
+    let to = to.as_deref_mut().unwrap_or(&mut from);
+    if let Some(rect) = to.paste(framebuffer, copies) {
+        refresh_view(framebuffer, &to.on_screen(&rect));
+    }
+}
+
+/// Where the navigation drawer slides out over the card
+fn drawer_rect() -> mxcfb_rect {
+    let left = match *LEFT_HANDED {
+        true => DISPLAYWIDTH as u32 - DRAWER_WIDTH,
+        false => 0,
+    };
+    mxcfb_rect {
+        top: CARD_AREA.top,
+        left,
+        height: DISPLAYHEIGHT as u32 - CARD_AREA.top,
+        width: DRAWER_WIDTH,
+    }
+}
+
+/// Whether a swipe starting at `start` comes in from the drawer's edge
+fn from_drawer_edge(start: cgmath::Point2<f32>, direction: Direction) -> bool {
+    match *LEFT_HANDED {
+        true => direction == Direction::Left && start.x > DISPLAYWIDTH as f32 - EDGE_PX,
+        false => direction == Direction::Right && start.x < EDGE_PX,
+    }
+}
+
+/// Slides out the navigation drawer over the side of the card
+fn open_drawer(app: &mut appctx::ApplicationContext<'_>) {
+    if TEXT_ENTRY.lock().unwrap().is_some()
+        || CONTEXT_MENU.lock().unwrap().is_some()
+        || DRAWER_OPEN.swap(true, Ordering::Relaxed)
+    {
+        return;
+    }
+    let drawer = drawer_rect();
+    let framebuffer = app.get_framebuffer_ref();
+    framebuffer.fill_rect(drawer.top_left().cast().unwrap(), drawer.size(), color::WHITE);
+    app.add_element(
+        "drawerRegion",
+        UIElementWrapper {
+            position: drawer.top_left().cast().unwrap(),
+            refresh: UIConstraintRefresh::Refresh,
+            onclick: None,
+            inner: UIElement::Region {
+                size: drawer.size().cast().unwrap(),
+                border_px: 2,
+                border_color: color::BLACK,
+            },
+            ..Default::default()
+        },
+    );
+    let (left, top) = (drawer.left as i32 + 40, drawer.top as i32);
+    add_key(app, "drawerTitle", left, top + 100, "Flashcards".to_owned(), None);
+    #[cfg(feature = "import")]
+    add_key(app, "drawerImport", left, top + 240, "Import".to_owned(), Some(on_drawer_import));
+    add_key(app, "drawerExit", left, top + 340, "Exit".to_owned(), Some(on_drawer_exit));
+    add_key(app, "drawerClose", left, top + 440, "Close".to_owned(), Some(on_drawer_close));
+    for name in DRAWER_ELEMENTS.iter() {
+        app.draw_element(name);
+    }
+}
+
+fn on_drawer_close(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    close_drawer(app);
+}
+
+fn close_drawer(app: &mut appctx::ApplicationContext<'_>) {
+    if !DRAWER_OPEN.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    remove_buttons(app, &DRAWER_ELEMENTS);
+    let drawer = drawer_rect();
+    let framebuffer = app.get_framebuffer_ref();
+    for canvas in CANVASES.iter() {
+        let mut canvas = canvas.lock().unwrap();
+        if let Some(rect) = canvas::intersection(&drawer, &canvas.bounds) {
+            // The canvas border went under the drawer too
+            framebuffer.fill_rect(rect.top_left().cast().unwrap(), rect.size(), color::WHITE);
+            canvas.repaint(framebuffer, rect);
+        }
+    }
+    app.draw_element("frontCanvasRegion");
+    app.draw_element("backCanvasRegion");
+    refresh_view(app.get_framebuffer_ref(), &drawer);
+}
+
+/// Adds the next notebook page in the import directory to the card, its top
+/// half on the front and its bottom half on the back. Only pages xochitl
+/// wrote before 3.0 can be read. When a page can't be, the drawer stays
+/// open and says so.
+#[cfg(feature = "import")]
+fn on_drawer_import(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    let path = {
+        let files = notebook::list(&notebook::dir());
+        let mut imported = IMPORTED_PAGE.lock().unwrap();
+        let next = match imported.as_ref() {
+            Some(last) => files.iter().find(|f| *f > last).or_else(|| files.first()),
+            None => files.first(),
+        };
+        *imported = next.cloned();
+        match next {
+            Some(next) => next.clone(),
+            None => {
+                close_drawer(app);
+                return;
+            }
+        }
+    };
+    let lines = match notebook::read_page(&path) {
+        Ok(lines) => lines,
+        Err(err) => {
+            error!("Failed to read {0}: {1}", path.display(), err);
+            let label = match err.kind() {
+                std::io::ErrorKind::InvalidData => "Needs v3/v5 page",
+                _ => "Not imported",
+            };
+            set_button_label(app, "drawerImport", label.to_owned());
+            return;
+        }
+    };
+    close_drawer(app);
+    let framebuffer = app.get_framebuffer_ref();
+    for (half, canvas) in CANVASES.iter().enumerate() {
+        let mut canvas = canvas.lock().unwrap();
+        let strokes = notebook::half_page_strokes(&lines, half, &canvas.inner());
+        if let Some(rect) = canvas.paste(framebuffer, strokes) {
+            refresh_view(framebuffer, &canvas.on_screen(&rect));
+        }
+    }
+}
+
+fn on_drawer_exit(app: &mut appctx::ApplicationContext<'_>, _element: UIElementHandle) {
+    exit_to_xochitl(app);
+}
+
+/// Carries out a two-finger tap's undo once no second tap has followed it
+fn check_pending_undo(app: &mut appctx::ApplicationContext<'_>) {
+    let mut pending = PENDING_UNDO.lock().unwrap();
+    match *pending {
+        Some(time) if time.elapsed() >= DOUBLE_TAP => *pending = None,
+        _ => return,
+    }
+    drop(pending);
+    undo(app);
+}
+
+/// Acts on a finger gesture. Taps press buttons on every screen, the rest
+/// only do anything on the main one.
+fn on_gesture(app: &mut appctx::ApplicationContext<'_>, gesture: Gesture) {
+    match gesture {
+        Gesture::Tap { pos, fingers: 1 } => {
+            let region = app.find_active_region(pos.y as u16, pos.x as u16);
+            let element = region.map(|(region, _)| region.element.clone());
+            if let Some(element) = element {
+                (region.unwrap().0.handler)(app, element)
+            }
+        }
+        _ if INSTANCE_LOCK.lock().unwrap().is_none() => {}
+        Gesture::Pinch { center, scale } => zoom_around(app, center, |zoom| zoom * scale),
+        Gesture::Tap { pos, fingers: 2 } => {
+            let mut pending = PENDING_UNDO.lock().unwrap();
+            if pending.take().is_some() {
+                drop(pending);
+                zoom_around(app, pos, |_| 1.0);
+            } else if side_at(pos).is_some_and(|side| CANVASES[side].lock().unwrap().zoomed()) {
+                *pending = Some(Instant::now());
+            } else {
+                drop(pending);
+                undo(app);
+            }
+        }
+        Gesture::Tap { fingers: 3, .. } => redo(app),
+        Gesture::LongPress { pos } => open_context_menu(app, pos),
+        Gesture::Swipe {
+            start,
+            direction,
+            fingers: 1,
+            ..
+        } if from_drawer_edge(start, direction) => open_drawer(app),
+        // Swiping along the toolbar pages through it
+        Gesture::Swipe {
+            start,
+            direction,
+            fingers: 1,
+            ..
+        } if start.y < CARD_AREA.top as f32 => {
+            let page = TOOLBAR_PAGE.load(Ordering::Relaxed);
+            let pages = TOOLBAR_PAGES.len();
+            match direction {
+                Direction::Left => show_toolbar_page(app, (page + 1) % pages),
+                Direction::Right => show_toolbar_page(app, (page + pages - 1) % pages),
+                Direction::Up | Direction::Down => {}
+            }
+        }
+        _ => {}
+    }
+}
+
+fn on_stylus_button(app: &mut appctx::ApplicationContext<'_>, action: ButtonAction, pressed: bool) {
+    if let ButtonAction::HoldEraser = action {
+        // A stroke keeps the color it started with, so end it here
+        let mut wacom_stack = WACOM_HISTORY.lock().unwrap();
+        wacom_stack.clear();
+        finish_stroke(app);
+        WACOM_RUBBER_SIDE.store(pressed, Ordering::Relaxed);
+    } else if pressed {
+        run_button_action(app, action);
+    }
+}
+
+fn on_button_press(app: &mut appctx::ApplicationContext<'_>, input: input::GPIOEvent) {
+    let (btn, new_state) = match input {
+        input::GPIOEvent::Press { button } => (button, true),
+        input::GPIOEvent::Unpress { button } => (button, false),
+        _ => return,
+    };
+
+    // Ignoring the unpressed event
+    if !new_state {
+        return;
+    }
+
+    {
+        let mut last = LAST_BUTTON_PRESS.lock().unwrap();
+        if let Some((last_btn, time)) = *last {
+            if last_btn == btn && time.elapsed() < *BUTTON_DEBOUNCE {
+                return;
+            }
+        }
+        *last = Some((btn, Instant::now()));
+    }
+
+    // Simple but effective accidental button press filtering
+    if WACOM_IN_RANGE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let (action, name) = match btn {
+        input::PhysicalButton::LEFT => (BUTTON_ACTIONS[0], "left"),
+        input::PhysicalButton::MIDDLE => (BUTTON_ACTIONS[1], "middle"),
+        input::PhysicalButton::RIGHT => (BUTTON_ACTIONS[2], "right"),
+        input::PhysicalButton::POWER => (ButtonAction::Exit, "power"),
+        input::PhysicalButton::WAKEUP => {
+            debug!("WAKEUP button(?) pressed(?)");
+            return;
+        }
+    };
+    hooks::run(app, Hook::Button, &[("button", name)]);
+    run_button_action(app, action);
+}
+
+fn run_button_action(app: &mut appctx::ApplicationContext<'_>, action: ButtonAction) {
+    match action {
+        ButtonAction::SmallerBrush => change_brush_width(app, -1),
+        ButtonAction::BiggerBrush => change_brush_width(app, 1),
+        ButtonAction::Eraser => on_toggle_eraser(app),
+        ButtonAction::Highlighter => toggle_highlighter(app),
+        ButtonAction::Undo => undo(app),
+        ButtonAction::Redo => redo(app),
+        ButtonAction::Exit => exit_to_xochitl(app),
+        ButtonAction::HoldEraser | ButtonAction::None => {}
+    };
+}
+
+fn add_main_elements(app: &mut appctx::ApplicationContext<'_>) {
+    add_canvas_regions(app);
+    add_button(app, "undoButton", 20, "Undo".to_owned(), on_undo);
+    add_button(app, "redoButton", 130, "Redo".to_owned(), on_redo);
+    add_toolbar_page(app, TOOLBAR_PAGE.load(Ordering::Relaxed));
+}
+
+/// Adds the borders around the front and back of the card
+fn add_canvas_regions(app: &mut appctx::ApplicationContext<'_>) {
+    let [front, back] = card_sides();
+    app.add_element(
+        "frontCanvasRegion",
+        UIElementWrapper {
+            position: front.top_left().cast().unwrap() + cgmath::vec2(0,0),
+            refresh: UIConstraintRefresh::RefreshAndWait,
+            onclick: None,
+            inner: UIElement::Region {
+                size: front.size().cast().unwrap(),
+                border_px: 2,
+                border_color: color::BLACK,
+            },
+            ..Default::default()
+        },
+    );
+
+    app.add_element(
+        "backCanvasRegion",
+        UIElementWrapper {
+            position: back.top_left().cast().unwrap() + cgmath::vec2(0,0),
+            refresh: UIConstraintRefresh::RefreshAndWait,
+            onclick: None,
+            inner: UIElement::Region {
+                size: back.size().cast().unwrap(),
+                border_px: 2,
+                border_color: color::BLACK,
+            },
+            ..Default::default()
+        },
+    );
+}
+
+/// Adds the buttons that take turns in the top bar after undo and redo
+fn add_toolbar_page(app: &mut appctx::ApplicationContext<'_>, page: usize) {
+    match page {
+        0 => {
+            let eraser = "Eraser".to_owned();
+            let ink_level = ink_level_label(G_INK_LEVEL.load(Ordering::Relaxed));
+            let shape = shape_label(G_TOOL.load(Ordering::Relaxed));
+            add_button(app, "eraserButton", 243, eraser, on_toggle_eraser_button);
+            add_button(app, "lassoButton", 469, "Lasso".to_owned(), on_toggle_lasso);
+            add_button(app, "deleteSelectionButton", 585, "Delete".to_owned(), on_delete_selection);
+            add_button(app, "inkLevelButton", 705, ink_level, on_cycle_ink_level);
+            add_button(app, "highlighterButton", 865, "Highlighter".to_owned(), on_toggle_highlighter);
+            add_button(app, "shapeButton", 1055, shape, on_cycle_shape);
+            add_button(app, "fillButton", 1230, "Fill".to_owned(), on_toggle_fill);
+            add_button(app, "pageButton", 1298, "More".to_owned(), on_next_toolbar_page);
+        }
+        1 => {
+            let zoom = zoom_label(active_canvas().view().zoom);
+            let layer = layer_label(active_canvas().layer());
+            let copy = copy_label(ACTIVE_SIDE.load(Ordering::Relaxed));
+            let preset = preset_label(PRESET_INDEX.load(Ordering::Relaxed));
+            add_button(app, "zoomButton", 243, zoom, on_toggle_zoom);
+            add_button(app, "layerButton", 401, layer, on_toggle_layer);
+            add_button(app, "copyButton", 653, copy, on_copy_to_other_side);
+            add_button(app, "pencilButton", 900, "Pencil".to_owned(), on_toggle_pencil);
+            add_button(app, "presetButton", 1040, preset, on_next_preset);
+            add_button(app, "textButton", 1192, "Text".to_owned(), on_toggle_text);
+            add_button(app, "pageButton", 1298, "More".to_owned(), on_next_toolbar_page);
+        }
+        2 => {
+            let stamp = stamp_label(G_TOOL.load(Ordering::Relaxed));
+            let image = image_label(G_TOOL.load(Ordering::Relaxed));
+            add_button(app, "stampButton", 243, stamp, on_cycle_stamp);
+            let clear = clear_label(ACTIVE_SIDE.load(Ordering::Relaxed));
+            add_button(app, "imageButton", 520, image, on_cycle_image);
+            add_button(app, "clearButton", 860, clear, on_clear_side);
+            add_button(app, "pageButton", 1298, "More".to_owned(), on_next_toolbar_page);
+        }
+        3 => {
+            let guide = guide_label(CANVASES[0].lock().unwrap().guide());
+            let split = split_label(FRONT_SHARE.load(Ordering::Relaxed));
+            let eraser_kind = eraser_kind_label(ERASE_STROKES.load(Ordering::Relaxed));
+            add_button(app, "guideButton", 243, guide, on_cycle_guide);
+            add_button(app, "splitButton", 500, split, on_cycle_split);
+            add_button(app, "eraserKindButton", 730, eraser_kind, on_toggle_eraser_kind);
+            #[cfg(feature = "export")]
+            add_button(app, "exportButton", 1040, "Export".to_owned(), on_export_card);
+            add_button(app, "pageButton", 1298, "More".to_owned(), on_next_toolbar_page);
+        }
+        _ => {
+            add_button(app, "rotateButton", 243, "Rotate".to_owned(), on_rotate_selection);
+            add_button(app, "mirrorButton", 378, "Mirror".to_owned(), on_mirror_selection);
+            add_button(app, "duplicateButton", 505, "Duplicate".to_owned(), on_duplicate_selection);
+            #[cfg(feature = "anki")]
+            add_button(app, "ankiButton", 720, "Anki".to_owned(), on_send_to_anki);
+            add_button(app, "pageButton", 1298, "Back".to_owned(), on_next_toolbar_page);
+        }
+    }
+}
+
+fn add_button(
+    app: &mut appctx::ApplicationContext<'_>,
+    name: &str,
+    x: i32,
+    text: String,
+    onclick: ActiveRegionFunction,
+) {
+    let x = match *LEFT_HANDED {
+        true => DISPLAYWIDTH as i32 - x - button_width(app, &text),
+        false => x,
+    };
+    app.add_element(
+        name,
+        UIElementWrapper {
+            position: cgmath::Point2 { x, y: 52 },
+            refresh: UIConstraintRefresh::Refresh,
+            onclick: Some(onclick),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text,
+                scale: 40.0,
+                border_px: 3,
+            },
+            ..Default::default()
+        },
+    );
+}
+
+/// Relabels a toolbar button. Mirrored, a button keeps its right edge where it
+/// is, so a longer label grows into the gap on its left as it otherwise would
+/// on its right.
+fn set_button_label(app: &mut appctx::ApplicationContext<'_>, name: &str, label: String) {
+    if refresh::inking() {
+        DEFERRED_LABELS.lock().unwrap().push((name.to_owned(), label));
+        return;
+    }
+    let element = match app.get_element_by_name(name) {
+        Some(element) => element,
+        None => return,
+    };
+    let old = match element.read().inner {
+        UIElement::Text { ref text, .. } => text.clone(),
+        _ => return,
+    };
+    // Redrawing blanks the button before rasterizing the label again, which
+    // flickers for nothing when it reads the same
+    if old == label {
+        return;
+    }
+    if *LEFT_HANDED {
+        let grown = button_width(app, &label) - button_width(app, &old);
+        element.write().position.x -= grown;
+    }
+    if let UIElement::Text { ref mut text, .. } = element.write().inner {
+        *text = label;
+    }
+    app.draw_element(name);
+}
+
+/// How wide a toolbar button's label comes out
+fn button_width(app: &mut appctx::ApplicationContext<'_>, text: &str) -> i32 {
+    let origin = cgmath::Point2 { x: 0.0, y: 0.0 };
+    let framebuffer = app.get_framebuffer_ref();
+    framebuffer.draw_text(origin, text, 40.0, color::BLACK, true).width as i32
+}
+
+/// Adds one key of the on-screen keyboard, or with no `onclick` the preview of
+/// what's been typed
+fn add_key(
+    app: &mut appctx::ApplicationContext<'_>,
+    name: &str,
+    x: i32,
+    y: i32,
+    text: String,
+    onclick: Option<ActiveRegionFunction>,
+) {
+    app.add_element(
+        name,
+        UIElementWrapper {
+            position: cgmath::Point2 { x, y },
+            refresh: UIConstraintRefresh::Refresh,
+            onclick,
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text,
+                scale: 60.0,
+                border_px: if onclick.is_some() { 3 } else { 0 },
+            },
+            ..Default::default()
+        },
+    );
+}
+
+/// Shown instead of the canvases when another instance already holds the lock
+fn add_instance_prompt(app: &mut appctx::ApplicationContext<'_>) {
+    app.add_element(
+        "instancePrompt",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 180, y: 800 },
+            refresh: UIConstraintRefresh::Refresh,
+            onclick: None,
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "flashcards is already running".to_owned(),
+                scale: 70.0,
+                border_px: 0,
+            },
+            ..Default::default()
+        },
+    );
+    app.add_element(
+        "takeOverButton",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 300, y: 960 },
+            refresh: UIConstraintRefresh::Refresh,
+            onclick: Some(on_take_over),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "Take over".to_owned(),
+                scale: 60.0,
+                border_px: 5,
+            },
+            ..Default::default()
+        },
+    );
+    app.add_element(
+        "quitButton",
+        UIElementWrapper {
+            position: cgmath::Point2 { x: 850, y: 960 },
+            refresh: UIConstraintRefresh::Refresh,
+            onclick: Some(on_quit),
+            inner: UIElement::Text {
+                foreground: color::BLACK,
+                text: "Quit".to_owned(),
+                scale: 60.0,
+                border_px: 5,
+            },
+            ..Default::default()
+        },
+    );
+}
+
+fn activate_input_devices(app: &mut appctx::ApplicationContext<'_>) {
+    app.activate_input_device(InputDevice::Wacom);
+    app.activate_input_device(InputDevice::Multitouch);
+    app.activate_input_device(InputDevice::GPIO);
+}
+
+/// Draws a synthetic stroke across the front of the card a report at a time,
+/// as the digitizer would, and prints how long each stage took
+fn bench_latency(app: &mut appctx::ApplicationContext<'_>) {
+    let inner = CANVASES[0].lock().unwrap().inner();
+    let (left, middle) = (inner.left as f32, (inner.top + inner.height / 2) as f32);
+    let (width, height) = (inner.width as f32, inner.height as f32);
+    latency::run(|| {
+        for (pen, state) in [(input::WacomPen::ToolPen, true), (input::WacomPen::Touch, true)] {
+            on_wacom_input(app, input::WacomEvent::InstrumentChange { pen, state });
+        }
+        for i in 0..BENCH_REPORTS {
+            let t = i as f32 / BENCH_REPORTS as f32;
+            let wave = (t * 6.0 * std::f32::consts::PI).sin();
+            let event = input::WacomEvent::Draw {
+                position: cgmath::Point2 {
+                    x: left + width * (0.1 + 0.8 * t),
+                    y: middle + wave * height * 0.3,
+                },
+                pressure: (REFERENCE_PRESSURE * (1.0 + 0.5 * wave)) as u16,
+                tilt: cgmath::vec2(0, 0),
+            };
+            latency::timed(Stage::Event, || on_wacom_input(app, event));
+            refresh::flush(app.get_framebuffer_ref());
+            sleep(BENCH_REPORT_INTERVAL);
+        }
+        let lift = input::WacomEvent::InstrumentChange {
+            pen: input::WacomPen::Touch,
+            state: false,
+        };
+        on_wacom_input(app, lift);
+    });
+}
+
+/// The file named after `flag` on the command line, if it's there
+fn input_log_arg(flag: &str) -> Option<PathBuf> {
+    let mut args = std::env::args().skip_while(|arg| arg != flag);
+    args.next()?;
+    args.next().map(PathBuf::from)
+}
+
+fn main() {
+    logfile::init();
+
+    // The screen geometry, input devices and refresh calls all assume the
+    // rM1 and rM2. libremarkable would panic on anything else, such as the
+    // Paper Pro, so say why and stop before it gets the chance.
+    if let Err(err) = device::Model::current_model() {
+        error!("Can't run on this device: {0}", err);
+        std::process::exit(1);
+    }
+
+    // Takes callback functions as arguments
+    // They are called with the event and the &mut framebuffer
+    let mut app: appctx::ApplicationContext<'_> = appctx::ApplicationContext::default();
+
+    // Alternatively we could have called `app.execute_lua("fb.clear()")`
+    app.clear(true);
+
+    match InstanceLock::acquire() {
+        Ok(lock) => {
+            *INSTANCE_LOCK.lock().unwrap() = Some(lock);
+            add_main_elements(&mut app);
+        }
+        Err(holder) => {
+            LOCK_HOLDER_PID.store(holder.unwrap_or(0), Ordering::Relaxed);
+            add_instance_prompt(&mut app);
+        }
+    }
+
+    // Create the top bar's time and battery labels. We can mutate these later.
+    // Draw the scene
+    app.draw_elements();
+
+    // Get a &mut to the framebuffer object, exposing many convenience functions
+    let appref = app.upgrade_ref();
+
+    info!("Init complete. Beginning event dispatch...");
+
+    if std::env::args().any(|arg| arg == "--bench-latency") {
+        bench_latency(appref);
+        return;
+    }
+
+    // `--record-input FILE` logs every input event, and `--replay-input FILE`
+    // feeds a log back in place of the devices until it runs out
+    let mut recorder = input_log_arg("--record-input").and_then(|path| {
+        Recorder::create(&path)
+            .map_err(|err| error!("Failed to create {0}: {1}", path.display(), err))
+            .ok()
+    });
+    let mut replay = input_log_arg("--replay-input").and_then(|path| {
+        Replay::open(&path)
+            .map_err(|err| error!("Failed to read {0}: {1}", path.display(), err))
+            .ok()
+    });
+
+    // Process events from digitizer + touchscreen + physical buttons. This
+    // loop stands in for the app context's own so that touches only press
+    // buttons once they're past palm rejection.
+    if replay.is_none() {
+        activate_input_devices(&mut app);
+    }
+    KEYBOARDS.lock().unwrap().scan();
+    let mut backlog = Backlog::new();
+    loop {
+        let typing = TEXT_ENTRY.lock().unwrap().is_some() && KEYBOARDS.lock().unwrap().connected();
+        let timeout = if typing { KEY_POLL } else { *PEN_UP_TIMEOUT };
+        // Wake up in time to refresh ink waiting on a batch, or chrome held
+        // back behind it
+        let timeout = refresh::due().map_or(timeout, |due| timeout.min(due));
+        let received = match replay.as_mut() {
+            Some(replay) => replay.recv_timeout(timeout),
+            None => backlog.recv_timeout(app.event_receiver(), timeout),
+        };
+        let reports = if replay.is_some() { 1 } else { backlog.reports() };
+        PEN_REPORTS.store(reports, Ordering::Relaxed);
+        let event = match received {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) if replay.is_some() => {
+                info!("Replay finished, taking input from the devices");
+                replay = None;
+                activate_input_devices(&mut app);
+                None
+            }
+            Err(e) => {
+                error!("Error in input event consumer: {0}", e);
+                break;
+            }
+        };
+        if let (Some(recorder), Some(event)) = (recorder.as_mut(), event.as_ref()) {
+            recorder.record(event);
+        }
+        if event.is_some() {
+            clear_brush_size(appref);
+        }
+        match event {
+            Some(InputEvent::WacomEvent { event }) => on_wacom_input(appref, event),
+            Some(InputEvent::MultitouchEvent { event }) => on_touch(appref, event),
+            Some(InputEvent::GPIO { event }) => on_button_press(appref, event),
+            _ => {}
+        }
+        refresh_cleared_brush_size(appref);
+        refresh::flush(appref.get_framebuffer_ref());
+        check_pen_up_timeout(appref);
+        check_pending_undo(appref);
+        check_keyboards(appref);
+        #[cfg(feature = "anki")]
+        check_anki(appref);
+        let held = GESTURES.lock().unwrap().poll();
+        if let Some(gesture) = held {
+            on_gesture(appref, gesture);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_give_brush_size_and_ink() {
+        let preset: Preset = "pencil 4 75".parse().unwrap();
+        assert!(preset.mode == DrawMode::Pencil(4));
+        assert_eq!(preset.ink_level, 191);
+        let preset: Preset = "  stroke_eraser 12 100 ".parse().unwrap();
+        assert!(preset.mode == DrawMode::EraseStrokes(12));
+        assert_eq!(preset.ink_level, 255);
+    }
+
+    #[test]
+    fn presets_out_of_range_or_malformed_are_rejected() {
+        for text in [
+            "pen 0 50",
+            "pen 100 50",
+            "pen 4 0",
+            "pen 4 101",
+            "pen 4",
+            "pen 4 50 x",
+            "crayon 4 50",
+            "pen four 50",
+        ] {
+            assert!(text.parse::<Preset>().is_err(), "{0} parsed", text);
+        }
+    }
+
+    #[test]
+    fn button_actions_parse_by_name() {
+        assert!(matches!("undo".parse(), Ok(ButtonAction::Undo)));
+        assert!(matches!("hold_eraser".parse(), Ok(ButtonAction::HoldEraser)));
+        assert!(matches!("none".parse(), Ok(ButtonAction::None)));
+        assert!("Undo".parse::<ButtonAction>().is_err());
+        assert!("".parse::<ButtonAction>().is_err());
+    }
 }
@@ -0,0 +1,377 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+
+use log::error;
+
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::stroke::{Brush, Stroke, REFERENCE_PRESSURE};
+use crate::CONFIG;
+
+// Pages are laid out at the rM screen size. Half a page becomes a side of
+// the card.
+const PAGE_WIDTH: f32 = 1404.0;
+const PAGE_HEIGHT: f32 = 1872.0;
+const HEADER_LEN: usize = 43;
+
+// Brush numbers xochitl gives its tools
+const PENCIL: i32 = 1;
+const FINELINER: i32 = 4;
+const HIGHLIGHTER: i32 = 5;
+const ERASER: i32 = 6;
+const ERASE_AREA: i32 = 8;
+const HIGHLIGHTERS: [i32; 2] = [HIGHLIGHTER, 18];
+const HIGHLIGHTER_LEVEL: u8 = 80;
+
+/// One line drawn in xochitl: brush and color as xochitl numbers them, then
+/// each point's position and width in pixels
+pub struct Line {
+    pub brush: i32,
+    pub color: i32,
+    pub points: Vec<(cgmath::Point2<f32>, f32)>,
+}
+
+/// Where notebook pages to import are picked up from, e.g. after copying
+/// them out of xochitl's data directory. Set with `import_dir` in the config.
+pub fn dir() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_else(|| "/home/root".into());
+    CONFIG.get("import_dir", PathBuf::from(home).join("flashcards/import"))
+}
+
+/// .rm page files in `dir`, sorted by name
+pub fn list(dir: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Failed to list {0}: {1}", dir.display(), err);
+            return Vec::new();
+        }
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rm"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Reads the lines off a page in the .rm format xochitl wrote up to
+/// version 5. Version 6 pages, from xochitl 3.0 on, are a different format
+/// altogether and aren't read.
+pub fn read_page(path: &Path) -> std::io::Result<Vec<Line>> {
+    parse_page(&std::fs::read(path)?)
+}
+
+fn parse_page(data: &[u8]) -> std::io::Result<Vec<Line>> {
+    let header = String::from_utf8_lossy(&data[..HEADER_LEN.min(data.len())]);
+    let version = match header.trim_end() {
+        "reMarkable .lines file, version=3" => 3,
+        "reMarkable .lines file, version=5" => 5,
+        other => {
+            let msg = format!("unsupported page format \"{0}\"", other);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+    };
+    let mut reader = Reader {
+        data,
+        pos: HEADER_LEN,
+    };
+    let mut lines = Vec::new();
+    for _ in 0..reader.i32()? {
+        for _ in 0..reader.i32()? {
+            let brush = reader.i32()?;
+            let color = reader.i32()?;
+            reader.i32()?;
+            reader.f32()?; // Base brush size, already folded into the widths
+            if version == 5 {
+                reader.i32()?;
+            }
+            let mut points = Vec::new();
+            for _ in 0..reader.i32()? {
+                let (x, y) = (reader.f32()?, reader.f32()?);
+                reader.f32()?; // Speed
+                reader.f32()?; // Direction
+                let width = reader.f32()?;
+                reader.f32()?; // Pressure, already folded into the width
+                points.push((cgmath::Point2 { x, y }, width));
+            }
+            lines.push(Line {
+                brush,
+                color,
+                points,
+            });
+        }
+    }
+    Ok(lines)
+}
+
+/// Turns the top (`half` 0) or bottom (`half` 1) of a page into strokes
+/// scaled to fit `area`. Lines are kept in whichever half they start in.
+/// Each stroke is drawn at its line's average width.
+pub fn half_page_strokes(lines: &[Line], half: usize, area: &mxcfb_rect) -> Vec<Stroke> {
+    let top = half as f32 * PAGE_HEIGHT / 2.0;
+    let scale = (area.width as f32 / PAGE_WIDTH).min(area.height as f32 / (PAGE_HEIGHT / 2.0));
+    let offset = cgmath::vec2(
+        area.left as f32 + (area.width as f32 - PAGE_WIDTH * scale) / 2.0,
+        area.top as f32,
+    );
+    lines
+        .iter()
+        .filter(|line| line.brush != ERASE_AREA && !line.points.is_empty())
+        .filter(|line| {
+            let y = line.points[0].0.y;
+            y >= top && y < top + PAGE_HEIGHT / 2.0
+        })
+        .map(|line| {
+            let (brush, col) = match line.brush {
+                ERASER => (Brush::Pen, color::WHITE),
+                b if HIGHLIGHTERS.contains(&b) => {
+                    (Brush::Highlighter, color::GRAY(HIGHLIGHTER_LEVEL))
+                }
+                _ => (Brush::Pen, line_color(line.color)),
+            };
+            let width = line.points.iter().map(|p| p.1).sum::<f32>() / line.points.len() as f32;
+            let mult = ((width * scale).round() as u32).max(1);
+            let mut stroke = Stroke::new(col, mult, brush);
+            stroke.points = line
+                .points
+                .iter()
+                .map(|(pos, _)| {
+                    let pos = cgmath::Point2 {
+                        x: pos.x * scale,
+                        y: (pos.y - top) * scale,
+                    } + offset;
+                    (pos, REFERENCE_PRESSURE as i32)
+                })
+                .collect();
+            // A segment takes three points. Dots and short ticks get their
+            // ends doubled up so that they still draw from end to end.
+            if stroke.points.len() < 3 {
+                let (first, last) = (stroke.points[0], stroke.points[stroke.points.len() - 1]);
+                stroke.points.insert(0, first);
+                stroke.points.push(last);
+            }
+            stroke.rect = stroke.bounds();
+            stroke
+        })
+        .collect()
+}
+
+/// The other way round from `half_page_strokes`: lays `strokes`, drawn in
+/// `area`, out over the top (`half` 0) or bottom (`half` 1) of a page. Fills
+/// and labels have no counterpart among xochitl's lines and are left out.
+pub fn half_page_lines<'a, I>(strokes: I, half: usize, area: &mxcfb_rect) -> Vec<Line>
+where
+    I: Iterator<Item = &'a Stroke>,
+{
+    let top = half as f32 * PAGE_HEIGHT / 2.0;
+    let scale = (area.width as f32 / PAGE_WIDTH).min(area.height as f32 / (PAGE_HEIGHT / 2.0));
+    let offset = cgmath::vec2(
+        area.left as f32 + (area.width as f32 - PAGE_WIDTH * scale) / 2.0,
+        area.top as f32,
+    );
+    strokes
+        .filter_map(|stroke| {
+            let [shade, _, _] = stroke.color.to_rgb8();
+            let brush = match stroke.brush {
+                Brush::Fill | Brush::Text => return None,
+                _ if shade == 255 => ERASER,
+                Brush::Pen => FINELINER,
+                Brush::Pencil => PENCIL,
+                Brush::Highlighter => HIGHLIGHTER,
+            };
+            let color = match shade {
+                0..=63 => 0,
+                255 => 2,
+                _ => 1,
+            };
+            let points = stroke
+                .points
+                .iter()
+                .map(|&(pos, pressure)| {
+                    let pos = (pos - offset) / scale;
+                    let pos = cgmath::Point2 {
+                        x: pos.x,
+                        y: pos.y + top,
+                    };
+                    (pos, stroke.width_at(pressure) / scale)
+                })
+                .collect();
+            Some(Line {
+                brush,
+                color,
+                points,
+            })
+        })
+        .collect()
+}
+
+/// Writes `lines` out as a one-page notebook named `name` in `dir`, laid out
+/// the way xochitl keeps notebooks in its data directory. Returns the
+/// notebook's id.
+pub fn write_notebook(dir: &Path, name: &str, lines: &[Line]) -> std::io::Result<String> {
+    let (id, page) = (new_uuid()?, new_uuid()?);
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    let metadata = format!(
+        concat!(
+            "{{\n",
+            "    \"deleted\": false,\n",
+            "    \"lastModified\": \"{0}\",\n",
+            "    \"metadatamodified\": false,\n",
+            "    \"modified\": false,\n",
+            "    \"parent\": \"\",\n",
+            "    \"pinned\": false,\n",
+            "    \"synced\": false,\n",
+            "    \"type\": \"DocumentType\",\n",
+            "    \"version\": 0,\n",
+            "    \"visibleName\": \"{1}\"\n",
+            "}}\n"
+        ),
+        millis,
+        name.replace('\\', "\\\\").replace('"', "\\\""),
+    );
+    let content = format!(
+        concat!(
+            "{{\n",
+            "    \"fileType\": \"notebook\",\n",
+            "    \"orientation\": \"portrait\",\n",
+            "    \"pageCount\": 1,\n",
+            "    \"pages\": [\"{0}\"]\n",
+            "}}\n"
+        ),
+        page
+    );
+    std::fs::write(dir.join(format!("{0}.metadata", id)), metadata)?;
+    std::fs::write(dir.join(format!("{0}.content", id)), content)?;
+    std::fs::write(dir.join(format!("{0}.pagedata", id)), "Blank\n")?;
+    std::fs::create_dir_all(dir.join(&id))?;
+    let mut file = std::fs::File::create(dir.join(&id).join(format!("{0}.rm", page)))?;
+    file.write_all(&page_bytes(lines))?;
+    Ok(id)
+}
+
+/// A page in the version 5 .lines format, all on one layer
+fn page_bytes(lines: &[Line]) -> Vec<u8> {
+    let mut out = format!("{0:1$}", "reMarkable .lines file, version=5", HEADER_LEN).into_bytes();
+    let mut push = |bytes: [u8; 4]| out.extend_from_slice(&bytes);
+    push(1i32.to_le_bytes());
+    push((lines.len() as i32).to_le_bytes());
+    for line in lines {
+        push(line.brush.to_le_bytes());
+        push(line.color.to_le_bytes());
+        push(0i32.to_le_bytes());
+        push(2.0f32.to_le_bytes()); // Medium base size
+        push(0i32.to_le_bytes());
+        push((line.points.len() as i32).to_le_bytes());
+        for &(pos, width) in line.points.iter() {
+            for value in [pos.x, pos.y, 0.0, 0.0, width, 1.0] {
+                push(value.to_le_bytes());
+            }
+        }
+    }
+    out
+}
+
+/// A random id in the form xochitl names documents and pages with
+fn new_uuid() -> std::io::Result<String> {
+    let uuid = std::fs::read_to_string("/proc/sys/kernel/random/uuid")?;
+    Ok(uuid.trim().to_owned())
+}
+
+fn line_color(color: i32) -> color {
+    match color {
+        1 => color::GRAY(128),
+        2 => color::WHITE,
+        _ => color::BLACK,
+    }
+}
+
+/// Little-endian numbers read off the front of the file
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self) -> std::io::Result<[u8; 4]> {
+        let end = self.pos + 4;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "page ends early"))?;
+        self.pos = end;
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn i32(&mut self) -> std::io::Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes()?))
+    }
+
+    fn f32(&mut self) -> std::io::Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(brush: i32, color: i32, points: &[(f32, f32, f32)]) -> Line {
+        Line {
+            brush,
+            color,
+            points: points
+                .iter()
+                .map(|&(x, y, width)| (cgmath::Point2 { x, y }, width))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn written_pages_read_back() {
+        let lines = vec![
+            line(FINELINER, 0, &[(10.0, 20.0, 2.0), (30.0, 40.0, 2.5)]),
+            line(HIGHLIGHTER, 1, &[(500.0, 900.0, 30.0)]),
+        ];
+        let read = parse_page(&page_bytes(&lines)).unwrap();
+        assert_eq!(read.len(), lines.len());
+        for (read, written) in read.iter().zip(lines.iter()) {
+            assert_eq!((read.brush, read.color), (written.brush, written.color));
+            assert_eq!(read.points, written.points);
+        }
+    }
+
+    #[test]
+    fn version_6_pages_are_refused() {
+        let mut data =
+            format!("{0:1$}", "reMarkable .lines file, version=6", HEADER_LEN).into_bytes();
+        data.extend_from_slice(&[0; 16]);
+        let err = parse_page(&data).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn cut_off_pages_are_refused() {
+        let bytes = page_bytes(&[line(FINELINER, 0, &[(10.0, 20.0, 2.0)])]);
+        let err = parse_page(&bytes[..bytes.len() - 3]).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn dots_are_kept() {
+        let area = mxcfb_rect {
+            top: 0,
+            left: 0,
+            width: 1404,
+            height: 936,
+        };
+        let lines = [line(FINELINER, 0, &[(100.0, 100.0, 3.0)])];
+        let strokes = half_page_strokes(&lines, 0, &area);
+        assert_eq!(strokes.len(), 1);
+        assert_eq!(strokes[0].points.len(), 3);
+        assert!(half_page_strokes(&lines, 1, &area).is_empty());
+    }
+}
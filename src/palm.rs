@@ -0,0 +1,61 @@
+use libremarkable::input::MultitouchEvent;
+
+use once_cell::sync::Lazy;
+use std::time::{Duration, Instant};
+
+use crate::CONFIG;
+
+// How long after the pen was last seen a new contact still counts as the
+// writing hand, set with `palm_guard_ms` in the config
+static GUARD: Lazy<Duration> =
+    Lazy::new(|| Duration::from_millis(CONFIG.get("palm_guard_ms", 500)));
+
+/// Tells fingers from the side of the hand resting on the screen while
+/// writing. The touchscreen doesn't report contact sizes, so it goes by
+/// timing: anything that lands while the pen is in range, or shortly after,
+/// is taken for a palm and ignored until it lifts.
+pub struct PalmFilter {
+    /// When the digitizer last reported the pen
+    last_pen: Option<Instant>,
+    /// Tracking ids of the contacts taken for palms
+    palms: Vec<i32>,
+}
+
+impl PalmFilter {
+    pub fn new() -> Self {
+        PalmFilter {
+            last_pen: None,
+            palms: Vec::new(),
+        }
+    }
+
+    /// The digitizer reported something about the pen
+    pub fn saw_pen(&mut self) {
+        self.last_pen = Some(Instant::now());
+    }
+
+    /// Whether `event` should be acted on, given whether the pen is in range
+    pub fn accept(&mut self, event: &MultitouchEvent, pen_in_range: bool) -> bool {
+        let id = match event.finger() {
+            Some(finger) => finger.tracking_id,
+            None => return false,
+        };
+        match event {
+            MultitouchEvent::Press { .. } => {
+                let recent = self.last_pen.is_some_and(|t| t.elapsed() < *GUARD);
+                if pen_in_range || recent {
+                    self.palms.push(id);
+                    return false;
+                }
+                !self.palms.contains(&id)
+            }
+            MultitouchEvent::Move { .. } => !self.palms.contains(&id),
+            MultitouchEvent::Release { .. } => {
+                let before = self.palms.len();
+                self.palms.retain(|&palm| palm != id);
+                self.palms.len() == before
+            }
+            MultitouchEvent::Unknown => false,
+        }
+    }
+}
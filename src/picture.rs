@@ -0,0 +1,234 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::common::*;
+use libremarkable::image;
+use libremarkable::image::imageops::FilterType;
+use libremarkable::image::GenericImageView;
+
+use log::error;
+use once_cell::sync::Lazy;
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::stroke::{Span, Stroke, BAYER};
+use crate::CONFIG;
+
+/// How images are brought down to black and white
+#[derive(Copy, Clone, PartialEq)]
+enum Dither {
+    /// Error diffusion, best for photos
+    FloydSteinberg,
+    /// A fixed 4x4 pattern, which keeps flat areas even and lines steady
+    Ordered,
+    /// Plain cut off at mid gray, for line drawings and scanned text
+    Threshold,
+}
+
+impl FromStr for Dither {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "floyd_steinberg" => Ok(Dither::FloydSteinberg),
+            "ordered" => Ok(Dither::Ordered),
+            "threshold" => Ok(Dither::Threshold),
+            _ => Err(()),
+        }
+    }
+}
+
+// Set with image_dither in the config
+static DITHER: Lazy<Dither> = Lazy::new(|| CONFIG.get("image_dither", Dither::FloydSteinberg));
+// How far grays are pushed away from the middle before dithering. Above 1
+// livens up washed out photos, below 1 softens harsh ones. Set with
+// image_contrast in the config.
+static CONTRAST: Lazy<f32> = Lazy::new(|| CONFIG.get("image_contrast", 1.0f32).max(0.0));
+
+/// Where images to insert are picked up from, e.g. after copying them over
+/// SSH. Set with `image_dir` in the config.
+pub fn dir() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_else(|| "/home/root".into());
+    CONFIG.get("image_dir", PathBuf::from(home).join("flashcards/images"))
+}
+
+/// PNG and JPEG files in `dir`, sorted by name. Read afresh on every call so
+/// newly copied files show up.
+pub fn list(dir: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Failed to list {0}: {1}", dir.display(), err);
+            return Vec::new();
+        }
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            matches!(ext.to_ascii_lowercase().as_str(), "png" | "jpg" | "jpeg")
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Loads the image at `path`, adjusts its contrast and dithers it to black and
+/// white as the config says, scaled to fit between `start` and `end` without
+/// stretching. A drag too small to go by places it at its own size, shrunk if
+/// it would run past `area`. Comes back as a white fill under a black one,
+/// ready to paste.
+pub fn load(
+    path: &Path,
+    start: cgmath::Point2<f32>,
+    end: cgmath::Point2<f32>,
+    area: &mxcfb_rect,
+) -> Option<Vec<Stroke>> {
+    let img = match image::open(path) {
+        Ok(img) => img,
+        Err(err) => {
+            error!("Failed to load {0}: {1}", path.display(), err);
+            return None;
+        }
+    };
+    let (img_w, img_h) = (img.width() as f32, img.height() as f32);
+    if img_w < 1.0 || img_h < 1.0 {
+        return None;
+    }
+    let left = start.x.min(end.x).max(area.left as f32);
+    let top = start.y.min(end.y).max(area.top as f32);
+    let room_w = (area.left + area.width) as f32 - left;
+    let room_h = (area.top + area.height) as f32 - top;
+    let (box_w, box_h) = ((end.x - start.x).abs(), (end.y - start.y).abs());
+    let scale = if box_w < 2.0 || box_h < 2.0 {
+        (room_w / img_w).min(room_h / img_h).min(1.0)
+    } else {
+        (box_w.min(room_w) / img_w).min(box_h.min(room_h) / img_h)
+    };
+    let (width, height) = ((img_w * scale) as u32, (img_h * scale) as u32);
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let gray = img
+        .resize_exact(width, height, FilterType::Triangle)
+        .to_luma8();
+    let gray: Vec<u8> = gray.as_raw().iter().map(|&g| contrast(g)).collect();
+    let (w, h) = (width as usize, height as usize);
+    let black = match *DITHER {
+        Dither::FloydSteinberg => dither(&gray, w, h),
+        Dither::Ordered => ordered(&gray, w),
+        Dither::Threshold => gray.iter().map(|&g| g < 128).collect(),
+    };
+    let (left, top) = (left as u32, top as u32);
+    let mut ink = Vec::new();
+    let mut paper = Vec::new();
+    for y in 0..height as usize {
+        let row = &black[y * width as usize..(y + 1) * width as usize];
+        let mut x = 0;
+        while x < row.len() {
+            let run = row[x..].iter().take_while(|&&b| b == row[x]).count();
+            let span: Span = (top + y as u32, left + x as u32, left + (x + run - 1) as u32);
+            if row[x] {
+                ink.push(span);
+            } else {
+                paper.push(span);
+            }
+            x += run;
+        }
+    }
+
+    let strokes = [(color::WHITE, paper), (color::BLACK, ink)]
+        .into_iter()
+        .filter(|(_, spans)| !spans.is_empty())
+        .map(|(col, spans)| {
+            let mut stroke = Stroke::fill(col, spans);
+            stroke.rect = stroke.bounds();
+            stroke
+        })
+        .collect();
+    Some(strokes)
+}
+
+fn contrast(gray: u8) -> u8 {
+    ((gray as f32 - 127.5) * *CONTRAST + 127.5).clamp(0.0, 255.0) as u8
+}
+
+/// Black (true) wherever the gray is darker than its spot in the Bayer
+/// matrix
+fn ordered(gray: &[u8], width: usize) -> Vec<bool> {
+    gray.iter()
+        .enumerate()
+        .map(|(i, &g)| {
+            let level = BAYER[(i / width) % 4][(i % width) % 4];
+            (g as u32) < level as u32 * 16 + 8
+        })
+        .collect()
+}
+
+/// Floyd-Steinberg error diffusion of 8 bit grays down to black (true) and
+/// white (false)
+fn dither(gray: &[u8], width: usize, height: usize) -> Vec<bool> {
+    let mut levels: Vec<f32> = gray.iter().map(|&g| g as f32).collect();
+    let mut black = vec![false; levels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let old = levels[i];
+            let new = if old < 128.0 { 0.0 } else { 255.0 };
+            black[i] = new == 0.0;
+            let err = old - new;
+            if x + 1 < width {
+                levels[i + 1] += err * 7.0 / 16.0;
+            }
+            if y + 1 < height {
+                if x > 0 {
+                    levels[i + width - 1] += err * 3.0 / 16.0;
+                }
+                levels[i + width] += err * 5.0 / 16.0;
+                if x + 1 < width {
+                    levels[i + width + 1] += err / 16.0;
+                }
+            }
+        }
+    }
+    black
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffusion_keeps_black_and_white_as_they_are() {
+        assert!(dither(&[0; 64], 8, 8).iter().all(|&black| black));
+        assert!(dither(&[255; 64], 8, 8).iter().all(|&black| !black));
+    }
+
+    #[test]
+    fn diffusion_inks_grays_in_proportion() {
+        let black = dither(&[64; 256], 16, 16).iter().filter(|&&b| b).count();
+        // A quarter of the way to black leaves three quarters inked
+        assert!((184..=200).contains(&black), "{0} of 256 inked", black);
+    }
+
+    #[test]
+    fn ordered_inks_a_gray_through_the_bayer_pattern() {
+        let black = ordered(&[128; 16], 4);
+        assert_eq!(black.iter().filter(|&&b| b).count(), 8);
+        // Every 4x4 tile comes out the same
+        let wide = ordered(&[128; 64], 8);
+        for (i, &b) in wide.iter().enumerate() {
+            assert_eq!(b, black[(i / 8 % 4) * 4 + i % 4]);
+        }
+        assert!(ordered(&[255; 16], 4).iter().all(|&b| !b));
+        assert!(ordered(&[0; 16], 4).iter().all(|&b| b));
+    }
+
+    #[test]
+    fn dither_names_parse() {
+        assert!("floyd_steinberg".parse() == Ok(Dither::FloydSteinberg));
+        assert!("ordered".parse() == Ok(Dither::Ordered));
+        assert!("threshold".parse() == Ok(Dither::Threshold));
+        assert!("bayer".parse::<Dither>().is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::input::{
+    Finger, GPIOEvent, InputEvent, MultitouchEvent, PhysicalButton, WacomEvent, WacomPen,
+};
+
+use log::{error, warn};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// Input logs have one event per line: microseconds since recording started,
+// then the event. For example
+//   1520344 wacom draw 702.5 1310.25 2011 1200 980
+//   1620011 touch press 14 380 922
+//   2400130 button press left
+
+/// Writes every input event to a file as it comes in
+pub struct Recorder {
+    file: LineWriter<File>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Recorder {
+            file: LineWriter::new(File::create(path)?),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, event: &InputEvent) {
+        let text = match format(event) {
+            Some(text) => text,
+            None => return,
+        };
+        let micros = self.started.elapsed().as_micros();
+        if let Err(err) = writeln!(self.file, "{0} {1}", micros, text) {
+            error!("Failed to record input: {0}", err);
+        }
+    }
+}
+
+/// Plays back a recorded input log with its original timing
+pub struct Replay {
+    events: VecDeque<(Duration, InputEvent)>,
+    started: Instant,
+}
+
+impl Replay {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let mut events = VecDeque::new();
+        for (number, line) in text.lines().enumerate() {
+            match parse(line) {
+                Some(event) => events.push_back(event),
+                None => warn!("Ignoring line {0} of {1}", number + 1, path.display()),
+            }
+        }
+        Ok(Replay {
+            events,
+            started: Instant::now(),
+        })
+    }
+
+    /// Waits up to `timeout` for the next event to come due, like the
+    /// receiver of live events. Disconnected once the log runs out.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<InputEvent, RecvTimeoutError> {
+        let due = match self.events.front() {
+            Some((due, _)) => *due,
+            None => return Err(RecvTimeoutError::Disconnected),
+        };
+        let wait = due.saturating_sub(self.started.elapsed());
+        if wait > timeout {
+            sleep(timeout);
+            return Err(RecvTimeoutError::Timeout);
+        }
+        sleep(wait);
+        Ok(self.events.pop_front().unwrap().1)
+    }
+}
+
+fn format(event: &InputEvent) -> Option<String> {
+    let text = match event {
+        InputEvent::WacomEvent { event } => match event {
+            WacomEvent::InstrumentChange { pen, state } => {
+                format!("wacom pen {0} {1}", pen_name(*pen), *state as u8)
+            }
+            WacomEvent::Hover {
+                position,
+                distance,
+                tilt,
+            } => format!(
+                "wacom hover {0} {1} {2} {3} {4}",
+                position.x, position.y, distance, tilt.x, tilt.y
+            ),
+            WacomEvent::Draw {
+                position,
+                pressure,
+                tilt,
+            } => format!(
+                "wacom draw {0} {1} {2} {3} {4}",
+                position.x, position.y, pressure, tilt.x, tilt.y
+            ),
+            WacomEvent::Unknown => return None,
+        },
+        InputEvent::MultitouchEvent { event } => {
+            let kind = match event {
+                MultitouchEvent::Press { .. } => "press",
+                MultitouchEvent::Release { .. } => "release",
+                MultitouchEvent::Move { .. } => "move",
+                MultitouchEvent::Unknown => return None,
+            };
+            let finger = event.finger()?;
+            format!(
+                "touch {0} {1} {2} {3}",
+                kind, finger.tracking_id, finger.pos.x, finger.pos.y
+            )
+        }
+        InputEvent::GPIO { event } => match event {
+            GPIOEvent::Press { button } => format!("button press {0}", button_name(*button)),
+            GPIOEvent::Unpress { button } => format!("button unpress {0}", button_name(*button)),
+            GPIOEvent::Unknown => return None,
+        },
+        InputEvent::Unknown {} => return None,
+    };
+    Some(text)
+}
+
+fn parse(line: &str) -> Option<(Duration, InputEvent)> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let due = Duration::from_micros(words.first()?.parse().ok()?);
+    let event = match words[1..] {
+        ["wacom", "pen", pen, state] => InputEvent::WacomEvent {
+            event: WacomEvent::InstrumentChange {
+                pen: pen_named(pen)?,
+                state: state == "1",
+            },
+        },
+        ["wacom", "hover", x, y, distance, tx, ty] => InputEvent::WacomEvent {
+            event: WacomEvent::Hover {
+                position: cgmath::Point2 {
+                    x: x.parse().ok()?,
+                    y: y.parse().ok()?,
+                },
+                distance: distance.parse().ok()?,
+                tilt: cgmath::vec2(tx.parse().ok()?, ty.parse().ok()?),
+            },
+        },
+        ["wacom", "draw", x, y, pressure, tx, ty] => InputEvent::WacomEvent {
+            event: WacomEvent::Draw {
+                position: cgmath::Point2 {
+                    x: x.parse().ok()?,
+                    y: y.parse().ok()?,
+                },
+                pressure: pressure.parse().ok()?,
+                tilt: cgmath::vec2(tx.parse().ok()?, ty.parse().ok()?),
+            },
+        },
+        ["touch", kind, id, x, y] => {
+            let mut finger = Finger::default();
+            finger.tracking_id = id.parse().ok()?;
+            finger.pos = cgmath::Point2 {
+                x: x.parse().ok()?,
+                y: y.parse().ok()?,
+            };
+            finger.pressed = kind != "release";
+            let event = match kind {
+                "press" => MultitouchEvent::Press { finger },
+                "release" => MultitouchEvent::Release { finger },
+                "move" => MultitouchEvent::Move { finger },
+                _ => return None,
+            };
+            InputEvent::MultitouchEvent { event }
+        }
+        ["button", kind, button] => {
+            let button = button_named(button)?;
+            let event = match kind {
+                "press" => GPIOEvent::Press { button },
+                "unpress" => GPIOEvent::Unpress { button },
+                _ => return None,
+            };
+            InputEvent::GPIO { event }
+        }
+        _ => return None,
+    };
+    Some((due, event))
+}
+
+const PENS: [(WacomPen, &str); 5] = [
+    (WacomPen::ToolPen, "tool_pen"),
+    (WacomPen::ToolRubber, "tool_rubber"),
+    (WacomPen::Touch, "touch"),
+    (WacomPen::Stylus, "stylus"),
+    (WacomPen::Stylus2, "stylus2"),
+];
+
+const BUTTONS: [(PhysicalButton, &str); 5] = [
+    (PhysicalButton::LEFT, "left"),
+    (PhysicalButton::MIDDLE, "middle"),
+    (PhysicalButton::RIGHT, "right"),
+    (PhysicalButton::POWER, "power"),
+    (PhysicalButton::WAKEUP, "wakeup"),
+];
+
+fn pen_name(pen: WacomPen) -> &'static str {
+    PENS.iter().find(|(p, _)| *p == pen).unwrap().1
+}
+
+fn pen_named(name: &str) -> Option<WacomPen> {
+    PENS.iter().find(|(_, n)| *n == name).map(|(p, _)| *p)
+}
+
+fn button_name(button: PhysicalButton) -> &'static str {
+    BUTTONS.iter().find(|(b, _)| *b == button).unwrap().1
+}
+
+fn button_named(name: &str) -> Option<PhysicalButton> {
+    BUTTONS.iter().find(|(_, n)| *n == name).map(|(b, _)| *b)
+}
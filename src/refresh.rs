@@ -0,0 +1,277 @@
+use libremarkable::device::{Model, CURRENT_DEVICE};
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::{FramebufferRefresh, PartialRefreshMode};
+
+use log::warn;
+use once_cell::sync::Lazy;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::latency::{self, Stage};
+use crate::CONFIG;
+
+/// Display settings that each model's panel may want differently
+#[derive(Copy, Clone)]
+pub struct Profile {
+    /// The temperature waveforms are picked for
+    pub temp: display_temp,
+    /// Dithering for ink refreshed in black and white
+    pub ink_dither: dither_mode,
+}
+
+// Both models start from what xochitl draws with. Set with
+// `rm1_display_temp` and `rm1_ink_dither` in the config, or the `rm2_`
+// equivalents, to try others on one model without touching the other.
+pub static PROFILE: Lazy<Profile> = Lazy::new(|| {
+    let model = match CURRENT_DEVICE.model {
+        Model::Gen1 => "rm1",
+        Model::Gen2 => "rm2",
+    };
+    let key = format!("{0}_display_temp", model);
+    let name = CONFIG.get(&key, "remarkable_draw".to_owned());
+    let temp = temp_named(&name).unwrap_or_else(|| {
+        warn!("Ignoring invalid value for {0}: {1}", key, name);
+        display_temp::TEMP_USE_REMARKABLE_DRAW
+    });
+    let key = format!("{0}_ink_dither", model);
+    let name = CONFIG.get(&key, "exp1".to_owned());
+    let ink_dither = dither_named(&name).unwrap_or_else(|| {
+        warn!("Ignoring invalid value for {0}: {1}", key, name);
+        dither_mode::EPDC_FLAG_EXP1
+    });
+    Profile { temp, ink_dither }
+});
+
+// How far the pen moves between digitizer reports, in pixels, picks how its
+// ink is refreshed. Below `careful_draw_px` it gets a grayscale GL16 pass
+// that shows pencil grain and gray ink as drawn. From `fast_draw_px` on it
+// gets GLR16, quicker than DU but leaving ghosting that's cleaned up when the
+// pen lifts. DU covers everything between. 0 turns either end off.
+static CAREFUL_DRAW_PX: Lazy<f32> = Lazy::new(|| CONFIG.get("careful_draw_px", 3.0f32));
+static FAST_DRAW_PX: Lazy<f32> = Lazy::new(|| CONFIG.get("fast_draw_px", 12.0f32));
+
+// Stroke segments drawn but not yet refreshed, when the first of them was,
+// and the fastest speed among them. One refresh per segment floods the EPDC
+// when writing fast, so they're gathered up for INK_BATCH and refreshed
+// together.
+static PENDING_INK: Lazy<Mutex<Option<(mxcfb_rect, Instant, Speed)>>> =
+    Lazy::new(|| Mutex::new(None));
+const INK_BATCH: Duration = Duration::from_millis(10);
+// Where the current stroke was refreshed in GLR16, to clean up on pen lift
+static GHOSTED: Lazy<Mutex<Option<mxcfb_rect>>> = Lazy::new(|| Mutex::new(None));
+// Whether ink has been queued since the pen last lifted
+static INKING: AtomicBool = AtomicBool::new(false);
+
+// Toolbar and other chrome waiting on a refresh, and since when. Ink under
+// the pen goes first, so while a stroke is being inked these are held back
+// and merged, for CHROME_WAIT at most.
+static PENDING_CHROME: Lazy<Mutex<Option<(mxcfb_rect, Instant)>>> = Lazy::new(|| Mutex::new(None));
+const CHROME_WAIT: Duration = Duration::from_millis(150);
+
+/// How fast the pen was moving when a segment was drawn, slowest first
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Speed {
+    Careful,
+    Normal,
+    Fast,
+}
+
+/// The speed of a pen that moved `step` pixels since its last report
+pub fn speed(step: f32) -> Speed {
+    if *FAST_DRAW_PX > 0.0 && step >= *FAST_DRAW_PX {
+        Speed::Fast
+    } else if step < *CAREFUL_DRAW_PX {
+        Speed::Careful
+    } else {
+        Speed::Normal
+    }
+}
+
+/// Fast black and white refresh, for ink and tool feedback under the pen
+pub fn refresh_ink(framebuffer: &mut Framebuffer, rect: &mxcfb_rect) {
+    framebuffer.partial_refresh(
+        rect,
+        PartialRefreshMode::Async,
+        waveform_mode::WAVEFORM_MODE_DU,
+        PROFILE.temp,
+        PROFILE.ink_dither,
+        DRAWING_QUANT_BIT,
+        false,
+    );
+}
+
+/// Grayscale refresh for when the canvas was redrawn wholesale
+pub fn refresh_view(framebuffer: &mut Framebuffer, rect: &mxcfb_rect) {
+    framebuffer.partial_refresh(
+        rect,
+        PartialRefreshMode::Async,
+        waveform_mode::WAVEFORM_MODE_GC16_FAST,
+        PROFILE.temp,
+        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+        0,
+        false,
+    );
+}
+
+/// Full grayscale pass, for gray ink that DU could only show in black and
+/// white
+pub fn refresh_gray(framebuffer: &mut Framebuffer, rect: &mxcfb_rect) {
+    framebuffer.partial_refresh(
+        rect,
+        PartialRefreshMode::Async,
+        waveform_mode::WAVEFORM_MODE_GC16,
+        PROFILE.temp,
+        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+        0,
+        false,
+    );
+}
+
+/// Black and white refresh for the toolbar and the rest of the UI around the
+/// card. Held back while a stroke is being inked.
+pub fn refresh_chrome(framebuffer: &mut Framebuffer, rect: &mxcfb_rect) {
+    let mut pending = PENDING_CHROME.lock().unwrap();
+    *pending = match *pending {
+        Some((queued, since)) => Some((queued.merge_rect(rect), since)),
+        None => Some((*rect, Instant::now())),
+    };
+    drop(pending);
+    flush_chrome(framebuffer, false);
+}
+
+/// Whether a stroke is being inked, so the UI should keep out of its way
+pub fn inking() -> bool {
+    INKING.load(Ordering::Relaxed)
+}
+
+/// Adds a freshly drawn segment to the next batched ink refresh. A batch is
+/// refreshed for the fastest segment in it.
+pub fn queue_ink(framebuffer: &mut Framebuffer, rect: mxcfb_rect, speed: Speed) {
+    let mut pending = PENDING_INK.lock().unwrap();
+    *pending = match *pending {
+        Some((queued, since, fastest)) => {
+            Some((queued.merge_rect(&rect), since, fastest.max(speed)))
+        }
+        None => Some((rect, Instant::now(), speed)),
+    };
+    drop(pending);
+    INKING.store(true, Ordering::Relaxed);
+    flush_ink(framebuffer, false);
+}
+
+/// How long until batched ink or held back chrome is due, if there's any
+pub fn due() -> Option<Duration> {
+    let ink = PENDING_INK.lock().unwrap();
+    let ink = ink.map(|(_, since, _)| INK_BATCH.saturating_sub(since.elapsed()));
+    let chrome = PENDING_CHROME.lock().unwrap();
+    let chrome = chrome.map(|(_, since)| CHROME_WAIT.saturating_sub(since.elapsed()));
+    match (ink, chrome) {
+        (Some(ink), Some(chrome)) => Some(ink.min(chrome)),
+        (ink, chrome) => ink.or(chrome),
+    }
+}
+
+/// Refreshes whatever has waited long enough, ink before chrome
+pub fn flush(framebuffer: &mut Framebuffer) {
+    flush_ink(framebuffer, false);
+    flush_chrome(framebuffer, false);
+}
+
+/// Refreshes the batched segments once the first has waited INK_BATCH, or
+/// straight away with `now`
+fn flush_ink(framebuffer: &mut Framebuffer, now: bool) {
+    let mut pending = PENDING_INK.lock().unwrap();
+    match *pending {
+        Some((_, since, _)) if now || since.elapsed() >= INK_BATCH => {}
+        _ => return,
+    }
+    let (rect, _, speed) = pending.take().unwrap();
+    let (waveform, dither, quant) = match speed {
+        Speed::Careful => (
+            waveform_mode::WAVEFORM_MODE_GL16_FAST,
+            dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+            0,
+        ),
+        Speed::Normal => (
+            waveform_mode::WAVEFORM_MODE_DU,
+            PROFILE.ink_dither,
+            DRAWING_QUANT_BIT,
+        ),
+        Speed::Fast => (
+            waveform_mode::WAVEFORM_MODE_GLR16,
+            PROFILE.ink_dither,
+            DRAWING_QUANT_BIT,
+        ),
+    };
+    latency::timed(Stage::Refresh, || {
+        framebuffer.partial_refresh(
+            &rect,
+            PartialRefreshMode::Async,
+            waveform,
+            PROFILE.temp,
+            dither,
+            quant,
+            false,
+        )
+    });
+    if speed == Speed::Fast {
+        let mut ghosted = GHOSTED.lock().unwrap();
+        *ghosted = Some(ghosted.map_or(rect, |seen| seen.merge_rect(&rect)));
+    }
+}
+
+/// Refreshes what's left of the stroke once the pen lifts, and clears the
+/// ghosting GLR16 left behind it. Chrome held back for the stroke follows.
+pub fn finish_ink(framebuffer: &mut Framebuffer) {
+    flush_ink(framebuffer, true);
+    if let Some(rect) = GHOSTED.lock().unwrap().take() {
+        refresh_view(framebuffer, &rect);
+    }
+    INKING.store(false, Ordering::Relaxed);
+    flush_chrome(framebuffer, true);
+}
+
+/// Refreshes held back chrome once no stroke is being inked or it has waited
+/// CHROME_WAIT, or straight away with `now`
+fn flush_chrome(framebuffer: &mut Framebuffer, now: bool) {
+    let mut pending = PENDING_CHROME.lock().unwrap();
+    match *pending {
+        Some((_, since)) if now || !inking() || since.elapsed() >= CHROME_WAIT => {}
+        _ => return,
+    }
+    let (rect, _) = pending.take().unwrap();
+    framebuffer.partial_refresh(
+        &rect,
+        PartialRefreshMode::Async,
+        waveform_mode::WAVEFORM_MODE_DU,
+        PROFILE.temp,
+        dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH,
+        0,
+        false,
+    );
+}
+
+fn temp_named(name: &str) -> Option<display_temp> {
+    match name {
+        "remarkable_draw" => Some(display_temp::TEMP_USE_REMARKABLE_DRAW),
+        "ambient" => Some(display_temp::TEMP_USE_AMBIENT),
+        "papyrus" => Some(display_temp::TEMP_USE_PAPYRUS),
+        "max" => Some(display_temp::TEMP_USE_MAX),
+        _ => None,
+    }
+}
+
+fn dither_named(name: &str) -> Option<dither_mode> {
+    match name {
+        "passthrough" => Some(dither_mode::EPDC_FLAG_USE_DITHERING_PASSTHROUGH),
+        "drawing" => Some(dither_mode::EPDC_FLAG_USE_DITHERING_DRAWING),
+        "y1" => Some(dither_mode::EPDC_FLAG_USE_DITHERING_Y1),
+        "y4" => Some(dither_mode::EPDC_FLAG_USE_DITHERING_Y4),
+        "remarkable" => Some(dither_mode::EPDC_FLAG_USE_REMARKABLE_DITHER),
+        "exp1" => Some(dither_mode::EPDC_FLAG_EXP1),
+        _ => None,
+    }
+}
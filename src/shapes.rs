@@ -0,0 +1,463 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::{Angle, InnerSpace};
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::{FramebufferDraw, FramebufferIO};
+
+use log::error;
+
+use std::time::{Duration, Instant};
+
+use crate::canvas::{self, Canvas};
+use crate::stroke::{Brush, Stroke, StrokePoint, REFERENCE_PRESSURE};
+
+/// Where a drag started and ended
+pub type Drag = (cgmath::Point2<f32>, cgmath::Point2<f32>);
+
+// Committed shapes go through the same bezier pipeline as handwriting, which
+// rounds off corners between samples. Sampling the outline this densely
+// keeps them sharp.
+const SAMPLE_SPACING: f32 = 4.0;
+const ARROW_HEAD_LENGTH: f32 = 30.0;
+
+// How long the pen has to rest, and how far it may wander meanwhile, for a
+// freehand stroke to snap to a shape
+const HOLD_TIME: Duration = Duration::from_millis(500);
+const HOLD_RADIUS: f32 = 6.0;
+// Strokes smaller than this are left alone, they're likely handwriting
+const MIN_FIT_SIZE: f32 = 20.0;
+// Worst deviation from the chord a line may have, relative to its length
+const LINE_TOLERANCE: f32 = 0.06;
+// Mean deviation from a closed shape's outline, relative to its radius
+const CLOSED_TOLERANCE: f32 = 0.12;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Shape {
+    Line,
+    Rectangle,
+    Ellipse,
+    Arrow,
+}
+
+impl Shape {
+    /// The shape after this one in the toolbar cycle, None after the last
+    pub fn next(self) -> Option<Shape> {
+        match self {
+            Shape::Line => Some(Shape::Rectangle),
+            Shape::Rectangle => Some(Shape::Ellipse),
+            Shape::Ellipse => Some(Shape::Arrow),
+            Shape::Arrow => None,
+        }
+    }
+
+    /// Corners of the shape dragged out from `start` to `end`, in drawing
+    /// order. Ellipses come back as a fine polygon.
+    pub fn outline(
+        self,
+        start: cgmath::Point2<f32>,
+        end: cgmath::Point2<f32>,
+    ) -> Vec<cgmath::Point2<f32>> {
+        match self {
+            Shape::Line => vec![start, end],
+            Shape::Rectangle => vec![
+                start,
+                cgmath::Point2 {
+                    x: end.x,
+                    y: start.y,
+                },
+                end,
+                cgmath::Point2 {
+                    x: start.x,
+                    y: end.y,
+                },
+                start,
+            ],
+            Shape::Ellipse => {
+                let center = cgmath::Point2 {
+                    x: (start.x + end.x) / 2.0,
+                    y: (start.y + end.y) / 2.0,
+                };
+                let rx = (end.x - start.x).abs() / 2.0;
+                let ry = (end.y - start.y).abs() / 2.0;
+                // Ramanujan's approximation is plenty to pick a sample count
+                let circumference = std::f32::consts::PI
+                    * (3.0 * (rx + ry) - ((3.0 * rx + ry) * (rx + 3.0 * ry)).sqrt());
+                let n = ((circumference / SAMPLE_SPACING) as usize).max(16);
+                (0..=n)
+                    .map(|i| {
+                        let theta = cgmath::Rad(2.0 * std::f32::consts::PI * i as f32 / n as f32);
+                        cgmath::Point2 {
+                            x: center.x + rx * theta.cos(),
+                            y: center.y + ry * theta.sin(),
+                        }
+                    })
+                    .collect()
+            }
+            Shape::Arrow => {
+                let shaft = end - start;
+                if shaft.magnitude() < 1.0 {
+                    return vec![start, end];
+                }
+                let back = -shaft.normalize() * ARROW_HEAD_LENGTH.min(shaft.magnitude() / 3.0);
+                let rotate = |v: cgmath::Vector2<f32>, deg: f32| {
+                    let (sin, cos) = cgmath::Deg(deg).sin_cos();
+                    cgmath::vec2(v.x * cos - v.y * sin, v.x * sin + v.y * cos)
+                };
+                vec![
+                    start,
+                    end,
+                    end + rotate(back, 30.0),
+                    end,
+                    end + rotate(back, -30.0),
+                ]
+            }
+        }
+    }
+}
+
+/// Drag-to-draw state for the shape tools. Pen-down anchors the shape, a
+/// preview follows the pen and pen-up commits it to the canvas.
+pub struct ShapeTool {
+    start: Option<cgmath::Point2<f32>>,
+    end: cgmath::Point2<f32>,
+    /// Pixels under the current preview
+    preview: Option<(mxcfb_rect, Vec<u8>)>,
+}
+
+impl ShapeTool {
+    pub fn new() -> Self {
+        ShapeTool {
+            start: None,
+            end: cgmath::Point2 { x: 0.0, y: 0.0 },
+            preview: None,
+        }
+    }
+
+    /// Pen touching the canvas at `pos`. Returns the area to refresh.
+    pub fn on_draw(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &Canvas,
+        shape: Shape,
+        mult: u32,
+        pos: cgmath::Point2<f32>,
+    ) -> Option<mxcfb_rect> {
+        let start = match self.start {
+            None => {
+                self.start = Some(pos);
+                self.end = pos;
+                return None;
+            }
+            Some(start) => start,
+        };
+        self.end = pos;
+
+        let lifted = self.clear_preview(framebuffer);
+        let corners = shape.outline(start, pos);
+        let area = match canvas::intersection(&points_rect(&corners, mult), &canvas.inner()) {
+            Some(area) => area,
+            None => return Some(lifted),
+        };
+        match framebuffer.dump_region(area) {
+            Err(err) => error!("Failed to dump shape preview region: {0}", err),
+            Ok(buff) => self.preview = Some((area, buff)),
+        }
+        for pair in corners.windows(2) {
+            framebuffer.draw_line(
+                pair[0].cast().unwrap(),
+                pair[1].cast().unwrap(),
+                mult,
+                color::BLACK,
+            );
+        }
+        Some(lifted.merge_rect(&area))
+    }
+
+    /// Pen lifted: swaps the preview for the real shape
+    pub fn on_pen_up(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        canvas: &mut Canvas,
+        shape: Shape,
+        col: color,
+        mult: u32,
+    ) -> Option<mxcfb_rect> {
+        let lifted = self.clear_preview(framebuffer);
+        let start = self.start.take()?;
+        if (self.end - start).magnitude() < 2.0 {
+            return Some(lifted);
+        }
+
+        let mut stroke = Stroke::new(col, mult, Brush::Pen);
+        stroke.points = trace(shape, start, self.end, REFERENCE_PRESSURE as i32);
+        let drawn = canvas.commit(framebuffer, stroke);
+        Some(lifted.merge_rect(&drawn))
+    }
+
+    /// Pen lifted without a shape to commit, as when the drag marks out
+    /// where something else goes. Clears the preview and returns the area to
+    /// refresh along with where the drag started and ended.
+    pub fn take_drag(&mut self, framebuffer: &mut Framebuffer) -> (mxcfb_rect, Option<Drag>) {
+        let lifted = self.clear_preview(framebuffer);
+        (lifted, self.start.take().map(|start| (start, self.end)))
+    }
+
+    fn clear_preview(&mut self, framebuffer: &mut Framebuffer) -> mxcfb_rect {
+        match self.preview.take() {
+            None => mxcfb_rect::invalid(),
+            Some((rect, buff)) => {
+                if let Err(e) = framebuffer.restore_region(rect, &buff) {
+                    error!("Error while restoring region: {0}", e);
+                }
+                rect
+            }
+        }
+    }
+}
+
+impl Default for ShapeTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Notices the pen resting at the end of a freehand stroke
+pub struct Hold {
+    anchor: Option<(cgmath::Point2<f32>, Instant)>,
+    snapped: bool,
+}
+
+impl Hold {
+    pub fn new() -> Self {
+        Hold {
+            anchor: None,
+            snapped: false,
+        }
+    }
+
+    /// True once the pen has stayed within HOLD_RADIUS of one spot for
+    /// HOLD_TIME
+    pub fn held(&mut self, pos: cgmath::Point2<f32>) -> bool {
+        match self.anchor {
+            Some((anchor, since)) if (pos - anchor).magnitude() <= HOLD_RADIUS => {
+                since.elapsed() >= HOLD_TIME
+            }
+            _ => {
+                self.anchor = Some((pos, Instant::now()));
+                false
+            }
+        }
+    }
+
+    /// Whether the current stroke was already snapped, after which the pen
+    /// draws nothing more until it lifts
+    pub fn snapped(&self) -> bool {
+        self.snapped
+    }
+
+    pub fn set_snapped(&mut self) {
+        self.snapped = true;
+    }
+
+    /// Called on pen-up
+    pub fn reset(&mut self) {
+        self.anchor = None;
+        self.snapped = false;
+    }
+}
+
+impl Default for Hold {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Replaces freehand `points` by the line, ellipse or rectangle they best
+/// resemble, at their average pressure. None if nothing fits well.
+pub fn straighten(points: &[StrokePoint]) -> Option<Vec<StrokePoint>> {
+    let positions: Vec<_> = points.iter().map(|p| p.0).collect();
+    let (shape, start, end) = fit(&positions)?;
+    let pressure = points.iter().map(|p| p.1 as i64).sum::<i64>() / points.len() as i64;
+    Some(trace(shape, start, end, pressure as i32))
+}
+
+/// Best fitting shape, along with the start and end points to drag it out
+/// between
+fn fit(
+    points: &[cgmath::Point2<f32>],
+) -> Option<(Shape, cgmath::Point2<f32>, cgmath::Point2<f32>)> {
+    let first = *points.first()?;
+    let last = *points.last()?;
+    let min = cgmath::Point2 {
+        x: points.iter().map(|p| p.x).fold(f32::MAX, f32::min),
+        y: points.iter().map(|p| p.y).fold(f32::MAX, f32::min),
+    };
+    let max = cgmath::Point2 {
+        x: points.iter().map(|p| p.x).fold(f32::MIN, f32::max),
+        y: points.iter().map(|p| p.y).fold(f32::MIN, f32::max),
+    };
+    let diagonal = (max - min).magnitude();
+    if diagonal < MIN_FIT_SIZE {
+        return None;
+    }
+
+    let chord = last - first;
+    if chord.magnitude() >= diagonal / 2.0 {
+        let normal = cgmath::vec2(-chord.y, chord.x).normalize();
+        let worst = points
+            .iter()
+            .map(|p| (*p - first).dot(normal).abs())
+            .fold(0.0, f32::max);
+        if worst <= LINE_TOLERANCE * chord.magnitude() {
+            return Some((Shape::Line, first, last));
+        }
+        return None;
+    }
+
+    // Closed loop, compare against the ellipse and rectangle in its bounds
+    let center = min + (max - min) / 2.0;
+    let (rx, ry) = ((max.x - min.x) / 2.0, (max.y - min.y) / 2.0);
+    if rx < 1.0 || ry < 1.0 {
+        return None;
+    }
+    let n = points.len() as f32;
+    let ellipse_error = points
+        .iter()
+        .map(|p| {
+            let (dx, dy) = ((p.x - center.x) / rx, (p.y - center.y) / ry);
+            ((dx * dx + dy * dy).sqrt() - 1.0).abs()
+        })
+        .sum::<f32>()
+        / n;
+    let rectangle_error = points
+        .iter()
+        .map(|p| {
+            let to_edge = (p.x - min.x)
+                .abs()
+                .min((max.x - p.x).abs())
+                .min((p.y - min.y).abs())
+                .min((max.y - p.y).abs());
+            to_edge / rx.min(ry)
+        })
+        .sum::<f32>()
+        / n;
+
+    let (shape, error) = if ellipse_error <= rectangle_error {
+        (Shape::Ellipse, ellipse_error)
+    } else {
+        (Shape::Rectangle, rectangle_error)
+    };
+    if error <= CLOSED_TOLERANCE {
+        Some((shape, min, max))
+    } else {
+        None
+    }
+}
+
+/// Stroke points along the outline of `shape`, all at `pressure`
+fn trace(
+    shape: Shape,
+    start: cgmath::Point2<f32>,
+    end: cgmath::Point2<f32>,
+    pressure: i32,
+) -> Vec<StrokePoint> {
+    densify(&shape.outline(start, end))
+        .into_iter()
+        .map(|p| (p, pressure))
+        .collect()
+}
+
+/// Adds points along each edge so that no two are more than SAMPLE_SPACING
+/// apart. The bezier fitting needs a point either side of each corner.
+pub fn densify(corners: &[cgmath::Point2<f32>]) -> Vec<cgmath::Point2<f32>> {
+    let mut points = Vec::new();
+    for pair in corners.windows(2) {
+        let edge = pair[1] - pair[0];
+        let steps = ((edge.magnitude() / SAMPLE_SPACING).ceil() as usize).max(1);
+        for i in 0..steps {
+            points.push(pair[0] + edge * (i as f32 / steps as f32));
+        }
+    }
+    points.extend(corners.last());
+    points
+}
+
+fn points_rect(points: &[cgmath::Point2<f32>], width: u32) -> mxcfb_rect {
+    let pad = width as f32 + 2.0;
+    let min_x = points.iter().map(|p| p.x).fold(f32::MAX, f32::min) - pad;
+    let max_x = points.iter().map(|p| p.x).fold(f32::MIN, f32::max) + pad;
+    let min_y = points.iter().map(|p| p.y).fold(f32::MAX, f32::min) - pad;
+    let max_y = points.iter().map(|p| p.y).fold(f32::MIN, f32::max) + pad;
+    let left = min_x.max(0.0);
+    let top = min_y.max(0.0);
+    mxcfb_rect {
+        top: top as u32,
+        left: left as u32,
+        width: (max_x - left).ceil() as u32,
+        height: (max_y - top).ceil() as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32) -> cgmath::Point2<f32> {
+        cgmath::Point2 { x, y }
+    }
+
+    /// Points around an ellipse centred on `center`, starting and ending on
+    /// its right
+    fn ellipse(center: cgmath::Point2<f32>, rx: f32, ry: f32) -> Vec<cgmath::Point2<f32>> {
+        (0..=64)
+            .map(|i| {
+                let theta = 2.0 * std::f32::consts::PI * i as f32 / 64.0;
+                point(center.x + rx * theta.cos(), center.y + ry * theta.sin())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_nearly_straight_stroke_is_a_line() {
+        let points: Vec<_> = (0..=20)
+            .map(|i| point(100.0 + i as f32 * 10.0, 300.0 + (i % 2) as f32 * 3.0))
+            .collect();
+        let (shape, start, end) = fit(&points).unwrap();
+        assert_eq!(shape, Shape::Line);
+        assert_eq!((start, end), (points[0], points[20]));
+    }
+
+    #[test]
+    fn a_wobbly_open_stroke_fits_nothing() {
+        let points: Vec<_> = (0..=20)
+            .map(|i| point(100.0 + i as f32 * 10.0, 300.0 + (i % 2) as f32 * 40.0))
+            .collect();
+        assert_eq!(fit(&points), None);
+    }
+
+    #[test]
+    fn a_closed_loop_is_an_ellipse_or_rectangle() {
+        let round = ellipse(point(300.0, 300.0), 100.0, 60.0);
+        let (shape, start, end) = fit(&round).unwrap();
+        assert_eq!(shape, Shape::Ellipse);
+        assert!((start.x - 200.0).abs() < 1.0 && (end.y - 360.0).abs() < 1.0);
+
+        let square = densify(&[
+            point(100.0, 100.0),
+            point(300.0, 100.0),
+            point(300.0, 250.0),
+            point(100.0, 250.0),
+            point(100.0, 104.0),
+        ]);
+        let (shape, start, end) = fit(&square).unwrap();
+        assert_eq!(shape, Shape::Rectangle);
+        assert_eq!((start, end), (point(100.0, 100.0), point(300.0, 250.0)));
+    }
+
+    #[test]
+    fn strokes_smaller_than_the_minimum_fit_nothing() {
+        let tiny = ellipse(point(50.0, 50.0), 5.0, 5.0);
+        assert_eq!(fit(&tiny), None);
+        assert_eq!(fit(&[]), None);
+    }
+}
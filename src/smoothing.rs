@@ -0,0 +1,112 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::InnerSpace;
+
+// Nominal time between two pen reports, in seconds. Events carry no
+// timestamp, so they're timed by how many reports apart they are rather
+// than by when they get processed, which bunches up whenever the UI thread
+// falls behind and would throw the cutoffs off.
+const REPORT_INTERVAL: f32 = 1.0 / 200.0;
+
+/// One-euro filter over pen positions (Casiez et al. 2012). It smooths
+/// heavily while the pen moves slowly, where jitter shows, and backs off as
+/// it speeds up so fast strokes don't lag behind the pen.
+pub struct OneEuroFilter {
+    /// Cutoff frequency at rest, in Hz. Lower is smoother.
+    min_cutoff: f32,
+    /// How quickly the cutoff rises with speed
+    beta: f32,
+    /// Cutoff used when smoothing the speed itself
+    d_cutoff: f32,
+    /// Last filtered position and velocity
+    last: Option<(cgmath::Point2<f32>, cgmath::Vector2<f32>)>,
+}
+
+impl OneEuroFilter {
+    pub fn new(min_cutoff: f32, beta: f32, d_cutoff: f32) -> Self {
+        OneEuroFilter {
+            min_cutoff,
+            beta,
+            d_cutoff,
+            last: None,
+        }
+    }
+
+    /// Filters the position `reports` pen reports after the last one, which
+    /// is more than one where the backlog was thinned in between
+    pub fn filter(&mut self, pos: cgmath::Point2<f32>, reports: u32) -> cgmath::Point2<f32> {
+        let (last_pos, last_velocity) = match self.last {
+            None => {
+                self.last = Some((pos, cgmath::vec2(0.0, 0.0)));
+                return pos;
+            }
+            Some(last) => last,
+        };
+        let dt = REPORT_INTERVAL * reports.max(1) as f32;
+
+        let velocity = (pos - last_pos) / dt;
+        let velocity = last_velocity + (velocity - last_velocity) * alpha(self.d_cutoff, dt);
+        let cutoff = self.min_cutoff + self.beta * velocity.magnitude();
+        let filtered = last_pos + (pos - last_pos) * alpha(cutoff, dt);
+        self.last = Some((filtered, velocity));
+        filtered
+    }
+
+    /// Forgets the stroke so far, for when the pen lifts
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Smoothing factor of an exponential filter with the given cutoff
+fn alpha(cutoff: f32, dt: f32) -> f32 {
+    let tau = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32) -> cgmath::Point2<f32> {
+        cgmath::Point2 { x, y }
+    }
+
+    #[test]
+    fn first_position_passes_through() {
+        let mut filter = OneEuroFilter::new(1.0, 0.01, 1.0);
+        assert_eq!(filter.filter(point(10.0, 20.0), 1), point(10.0, 20.0));
+    }
+
+    #[test]
+    fn jitter_at_rest_is_smoothed_out() {
+        let mut filter = OneEuroFilter::new(1.0, 0.01, 1.0);
+        filter.filter(point(100.0, 100.0), 1);
+        let mut worst: f32 = 0.0;
+        for i in 0..50 {
+            let jitter = if i % 2 == 0 { 2.0 } else { -2.0 };
+            let filtered = filter.filter(point(100.0 + jitter, 100.0), 1);
+            worst = worst.max((filtered.x - 100.0).abs());
+        }
+        assert!(worst < 1.0, "jitter of 2px came through as {0}px", worst);
+    }
+
+    #[test]
+    fn reports_dropped_in_between_count_as_time_passing() {
+        let mut one = OneEuroFilter::new(1.0, 0.0, 1.0);
+        let mut four = OneEuroFilter::new(1.0, 0.0, 1.0);
+        for filter in [&mut one, &mut four] {
+            filter.filter(point(0.0, 0.0), 1);
+        }
+        let after_one = one.filter(point(10.0, 0.0), 1);
+        let after_four = four.filter(point(10.0, 0.0), 4);
+        assert!(after_four.x > after_one.x);
+    }
+
+    #[test]
+    fn reset_forgets_the_stroke() {
+        let mut filter = OneEuroFilter::new(1.0, 0.01, 1.0);
+        filter.filter(point(0.0, 0.0), 1);
+        filter.reset();
+        assert_eq!(filter.filter(point(500.0, 500.0), 1), point(500.0, 500.0));
+    }
+}
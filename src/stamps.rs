@@ -0,0 +1,149 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::Angle;
+use libremarkable::framebuffer::common::*;
+
+use crate::shapes;
+use crate::stroke::{Brush, Stroke, REFERENCE_PRESSURE};
+
+/// One pen stroke of a stamp, in a box running from -1 to 1 both ways with y
+/// pointing down
+enum Path {
+    Polyline(&'static [(f32, f32)]),
+    Circle((f32, f32), f32),
+}
+
+const ARROW: &[Path] = &[
+    Path::Polyline(&[(-1.0, 0.0), (1.0, 0.0)]),
+    Path::Polyline(&[(0.5, -0.4), (1.0, 0.0), (0.5, 0.4)]),
+];
+const STAR: &[Path] = &[Path::Polyline(&[
+    (0.0, -1.0),
+    (0.225, -0.309),
+    (0.951, -0.309),
+    (0.363, 0.118),
+    (0.588, 0.809),
+    (0.0, 0.382),
+    (-0.588, 0.809),
+    (-0.363, 0.118),
+    (-0.951, -0.309),
+    (-0.225, -0.309),
+    (0.0, -1.0),
+])];
+const CHECK: &[Path] = &[Path::Polyline(&[(-0.8, 0.0), (-0.25, 0.6), (0.9, -0.7)])];
+const CROSS: &[Path] = &[
+    Path::Polyline(&[(-0.7, -0.7), (0.7, 0.7)]),
+    Path::Polyline(&[(0.7, -0.7), (-0.7, 0.7)]),
+];
+const HEART: &[Path] = &[Path::Polyline(&[
+    (0.0, 1.0),
+    (-0.9, 0.1),
+    (-1.0, -0.4),
+    (-0.8, -0.85),
+    (-0.4, -0.95),
+    (0.0, -0.55),
+    (0.4, -0.95),
+    (0.8, -0.85),
+    (1.0, -0.4),
+    (0.9, 0.1),
+    (0.0, 1.0),
+])];
+const MALE: &[Path] = &[
+    Path::Circle((-0.25, 0.25), 0.55),
+    Path::Polyline(&[(0.14, -0.14), (0.9, -0.9)]),
+    Path::Polyline(&[(0.4, -0.9), (0.9, -0.9), (0.9, -0.4)]),
+];
+const FEMALE: &[Path] = &[
+    Path::Circle((0.0, -0.35), 0.55),
+    Path::Polyline(&[(0.0, 0.2), (0.0, 1.0)]),
+    Path::Polyline(&[(-0.35, 0.6), (0.35, 0.6)]),
+];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Stamp {
+    Arrow,
+    Star,
+    Check,
+    Cross,
+    Heart,
+    Male,
+    Female,
+}
+
+impl Stamp {
+    /// The stamp after this one in the toolbar cycle, None after the last
+    pub fn next(self) -> Option<Stamp> {
+        match self {
+            Stamp::Arrow => Some(Stamp::Star),
+            Stamp::Star => Some(Stamp::Check),
+            Stamp::Check => Some(Stamp::Cross),
+            Stamp::Cross => Some(Stamp::Heart),
+            Stamp::Heart => Some(Stamp::Male),
+            Stamp::Male => Some(Stamp::Female),
+            Stamp::Female => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stamp::Arrow => "Arrow",
+            Stamp::Star => "Star",
+            Stamp::Check => "Check",
+            Stamp::Cross => "Cross",
+            Stamp::Heart => "Heart",
+            Stamp::Male => "Male",
+            Stamp::Female => "Female",
+        }
+    }
+
+    fn paths(self) -> &'static [Path] {
+        match self {
+            Stamp::Arrow => ARROW,
+            Stamp::Star => STAR,
+            Stamp::Check => CHECK,
+            Stamp::Cross => CROSS,
+            Stamp::Heart => HEART,
+            Stamp::Male => MALE,
+            Stamp::Female => FEMALE,
+        }
+    }
+
+    /// The stamp `size` pixels across centered on `center`, as undrawn pen
+    /// strokes
+    pub fn strokes(
+        self,
+        center: cgmath::Point2<f32>,
+        size: f32,
+        col: color,
+        mult: u32,
+    ) -> Vec<Stroke> {
+        let place = |(x, y): (f32, f32)| cgmath::Point2 {
+            x: center.x + x * size / 2.0,
+            y: center.y + y * size / 2.0,
+        };
+        self.paths()
+            .iter()
+            .map(|path| {
+                let corners: Vec<cgmath::Point2<f32>> = match path {
+                    Path::Polyline(points) => points.iter().map(|&p| place(p)).collect(),
+                    Path::Circle((x, y), r) => {
+                        let n = ((std::f32::consts::PI * r * size / 4.0) as usize).max(16);
+                        (0..=n)
+                            .map(|i| {
+                                let theta =
+                                    cgmath::Rad(2.0 * std::f32::consts::PI * i as f32 / n as f32);
+                                place((x + r * theta.cos(), y + r * theta.sin()))
+                            })
+                            .collect()
+                    }
+                };
+                let mut stroke = Stroke::new(col, mult, Brush::Pen);
+                stroke.points = shapes::densify(&corners)
+                    .into_iter()
+                    .map(|p| (p, REFERENCE_PRESSURE as i32))
+                    .collect();
+                stroke.rect = stroke.bounds();
+                stroke
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,1012 @@
+use libremarkable::framebuffer::cgmath;
+use libremarkable::framebuffer::cgmath::{EuclideanSpace, InnerSpace};
+use libremarkable::framebuffer::common::*;
+use libremarkable::framebuffer::core::Framebuffer;
+use libremarkable::framebuffer::storage;
+use libremarkable::framebuffer::{FramebufferDraw, FramebufferIO};
+
+use log::{error, warn};
+use once_cell::sync::Lazy;
+
+use crate::canvas;
+use crate::CONFIG;
+
+/// Position and raw pressure of a single wacom sample
+pub type StrokePoint = (cgmath::Point2<f32>, i32);
+
+/// One row of a fill: y, then the first and last x covered
+pub type Span = (u32, u32, u32);
+
+/// Compressed pixels of an area, as they were before a stroke covered them
+pub type Snapshot = (mxcfb_rect, storage::CompressedCanvasState);
+
+// Pressure at which a stroke is drawn exactly `mult` wide, and the most the
+// digitizer reports
+pub const REFERENCE_PRESSURE: f32 = 2048.0;
+const MAX_PRESSURE: i32 = 4095;
+
+// Bayer matrix for ordered dithers, thresholds in sixteenths. Gray fills use
+// it, and so do pictures placed with the ordered dither.
+pub const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+// Exponent of the pressure to width curve. Below 1 light strokes come out
+// thicker, above 1 thinner; the reference pressure always maps to `mult`.
+static PRESSURE_GAMMA: Lazy<f32> = Lazy::new(|| match CONFIG.get("pressure_gamma", 1.0) {
+    gamma if gamma > 0.0 => gamma,
+    gamma => {
+        warn!("Ignoring invalid value for pressure_gamma: {0}", gamma);
+        1.0
+    }
+});
+
+// Points sampled along each pen curve, set with `bezier_samples` in the
+// config. Fewer draw faster at the cost of visible corners.
+static BEZIER_SAMPLES: Lazy<i32> = Lazy::new(|| CONFIG.get("bezier_samples", 10).max(2));
+// With `bezier_segment_px` set, short curves use fewer samples, spaced about
+// that many pixels apart. 0 always uses the full count.
+static BEZIER_SEGMENT_PX: Lazy<f32> =
+    Lazy::new(|| CONFIG.get("bezier_segment_px", 0.0f32).max(0.0));
+
+// Horizontal extent of the highlighter's chisel tip. Its height is the
+// stroke's `mult`.
+const HIGHLIGHTER_TIP_WIDTH: f32 = 4.0;
+
+// How far over the pencil has to lean, in degrees from upright, before its
+// tip starts to lie down, and by when it is fully on its side
+const PENCIL_UPRIGHT: f32 = 30.0;
+const PENCIL_FLAT: f32 = 70.0;
+// Length over width of the pencil's footprint when it's fully on its side
+const PENCIL_MAX_STRETCH: f32 = 4.0;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Brush {
+    /// Round, pressure sensitive tip
+    Pen,
+    /// Flat tip that ignores pressure and never lightens what's underneath,
+    /// so ink stays on top of it
+    Highlighter,
+    /// Flood fill. Its shape is given by `spans` rather than `points`.
+    Fill,
+    /// Pressure sensitive tip that stretches out along the direction the pen
+    /// leans in and shades lighter and grainier the lower it's held
+    Pencil,
+    /// Typed text. `points` run along its baseline and `mult` is the height
+    /// of its letters.
+    Text,
+}
+
+/// Strokes on the notes layer always show above those on the diagram layer,
+/// and each layer's strokes can only be erased or selected while it's active
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Layer {
+    Diagram,
+    Notes,
+}
+
+/// Everything the pen laid down between pen-down and pen-up, along with the
+/// pixels it painted over so it can be taken back.
+pub struct Stroke {
+    pub points: Vec<StrokePoint>,
+    pub color: color,
+    pub mult: u32,
+    pub brush: Brush,
+    pub layer: Layer,
+    /// Union of the rects of every segment drawn so far
+    pub rect: mxcfb_rect,
+    /// Area covered by a fill, empty for every other brush
+    pub spans: Vec<Span>,
+    /// What a text stroke says, empty for every other brush
+    pub text: String,
+    /// Which way and how far the pen leaned at each point, in degrees along
+    /// the screen axes. Only recorded for the pencil.
+    pub tilts: Vec<cgmath::Vector2<f32>>,
+    /// Canvas contents from just before each segment was drawn, in drawing
+    /// order. Only populated while the stroke is in progress.
+    segments: Vec<(mxcfb_rect, Vec<u8>)>,
+    /// The segments above merged into one compressed snapshot by `finish`
+    before: Option<Snapshot>,
+}
+
+impl Stroke {
+    pub fn new(color: color, mult: u32, brush: Brush) -> Self {
+        Stroke {
+            points: Vec::new(),
+            color,
+            mult,
+            brush,
+            layer: Layer::Diagram,
+            rect: mxcfb_rect::invalid(),
+            spans: Vec::new(),
+            text: String::new(),
+            tilts: Vec::new(),
+            segments: Vec::new(),
+            before: None,
+        }
+    }
+
+    /// An undrawn copy of the stroke, shifted by `dx` and `dy`
+    pub fn translated(&self, dx: i32, dy: i32) -> Stroke {
+        let mut copy = Stroke::new(self.color, self.mult, self.brush);
+        copy.layer = self.layer;
+        copy.points = self
+            .points
+            .iter()
+            .map(|p| (p.0 + cgmath::vec2(dx as f32, dy as f32), p.1))
+            .collect();
+        copy.spans = self
+            .spans
+            .iter()
+            .map(|&(y, left, right)| {
+                (
+                    (y as i32 + dy) as u32,
+                    (left as i32 + dx) as u32,
+                    (right as i32 + dx) as u32,
+                )
+            })
+            .collect();
+        copy.text = self.text.clone();
+        copy.tilts = self.tilts.clone();
+        copy.rect = copy.bounds();
+        copy
+    }
+
+    /// A fill of `spans`, not drawn yet
+    pub fn fill(color: color, spans: Vec<Span>) -> Self {
+        let mut stroke = Stroke::new(color, 1, Brush::Fill);
+        stroke.spans = spans;
+        stroke
+    }
+
+    /// `text` in letters `size` pixels high, its baseline starting at
+    /// `anchor`. Not drawn yet.
+    pub fn label(
+        framebuffer: &mut Framebuffer,
+        color: color,
+        size: u32,
+        anchor: cgmath::Point2<f32>,
+        text: String,
+    ) -> Self {
+        let drawn = framebuffer.draw_text(anchor, &text, size as f32, color, true);
+        let end = cgmath::Point2 {
+            x: ((drawn.left + drawn.width) as f32).max(anchor.x),
+            y: anchor.y,
+        };
+        let mut stroke = Stroke::new(color, size, Brush::Text);
+        stroke.points = vec![
+            (anchor, REFERENCE_PRESSURE as i32),
+            (end, REFERENCE_PRESSURE as i32),
+        ];
+        stroke.text = text;
+        stroke
+    }
+
+    /// True if nothing was drawn, i.e. the pen never produced a full segment
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty() && self.before.is_none()
+    }
+
+    /// Draws one bezier segment through `points` (oldest first), saving the
+    /// part of `bounds` it is about to cover beforehand. `tilt` is only used
+    /// by the pencil.
+    pub fn draw_segment(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        bounds: &mxcfb_rect,
+        points: [StrokePoint; 3],
+        tilt: cgmath::Vector2<f32>,
+    ) -> mxcfb_rect {
+        let max_width = match self.brush {
+            Brush::Pen => {
+                self.mult as f32 * pressure_scale(points.iter().map(|p| p.1).max().unwrap())
+            }
+            Brush::Pencil => {
+                self.mult as f32
+                    * pressure_scale(points.iter().map(|p| p.1).max().unwrap())
+                    * PENCIL_MAX_STRETCH
+            }
+            Brush::Highlighter | Brush::Fill | Brush::Text => self.mult as f32,
+        };
+        if let Some(covered) = bezier_bounds(&points, max_width, bounds) {
+            match framebuffer.dump_region(covered) {
+                Err(err) => error!("Failed to dump stroke region: {0}", err),
+                Ok(buff) => self.segments.push((covered, buff)),
+            }
+        }
+
+        let rect = paint(self.brush, framebuffer, points, tilt, self.mult, self.color);
+        self.rect = self.rect.merge_rect(&rect);
+        rect
+    }
+
+    /// Tilt recorded at point `i`. Points added after the pen lifted, as when
+    /// a stroke is straightened, take the last one recorded.
+    fn tilt_at(&self, i: usize) -> cgmath::Vector2<f32> {
+        match self.tilts.get(i).or_else(|| self.tilts.last()) {
+            Some(&tilt) => tilt,
+            None => cgmath::vec2(0.0, 0.0),
+        }
+    }
+
+    /// Folds the per-segment snapshots into a single compressed one covering
+    /// the whole stroke. Called on pen-up so that the overlapping segment
+    /// copies don't pile up in memory.
+    pub fn finish(&mut self, framebuffer: &mut Framebuffer) {
+        let covered = self
+            .segments
+            .iter()
+            .fold(mxcfb_rect::invalid(), |acc, (rect, _)| acc.merge_rect(rect));
+        if self.segments.is_empty() {
+            return;
+        }
+
+        let mut buff = match framebuffer.dump_region(covered) {
+            Err(err) => {
+                error!("Failed to dump stroke region: {0}", err);
+                return;
+            }
+            Ok(buff) => buff,
+        };
+        let bpp = buff.len() / (covered.width * covered.height) as usize;
+        let stride = covered.width as usize * bpp;
+        for (rect, pixels) in self.segments.drain(..).rev() {
+            let row = rect.width as usize * bpp;
+            let x_offset = (rect.left - covered.left) as usize * bpp;
+            for y in 0..rect.height as usize {
+                let start = ((rect.top - covered.top) as usize + y) * stride + x_offset;
+                buff[start..start + row].copy_from_slice(&pixels[y * row..(y + 1) * row]);
+            }
+        }
+        self.before = Some((
+            covered,
+            storage::CompressedCanvasState::new(buff.as_slice(), covered.height, covered.width),
+        ));
+    }
+
+    /// Drops the saved pixels, for when they no longer match what's on screen
+    pub fn discard_snapshot(&mut self) {
+        self.segments.clear();
+        self.before = None;
+    }
+
+    /// Hands over the pixels saved by `finish`, so that they live only as long
+    /// as whatever can still put them back
+    pub fn take_snapshot(&mut self) -> Option<Snapshot> {
+        self.before.take()
+    }
+
+    /// Restores the pixels under the stroke. Returns the rect that needs
+    /// refreshing.
+    pub fn undraw(&mut self, framebuffer: &mut Framebuffer) -> mxcfb_rect {
+        self.rect = mxcfb_rect::invalid();
+        match self.before.take() {
+            None => mxcfb_rect::invalid(),
+            Some(snapshot) => restore(framebuffer, snapshot),
+        }
+    }
+
+    /// Replays the recorded points, as when redoing an undone stroke
+    pub fn redraw(&mut self, framebuffer: &mut Framebuffer, bounds: &mxcfb_rect) -> mxcfb_rect {
+        if let Brush::Fill | Brush::Text = self.brush {
+            let covered = self.bounds();
+            match framebuffer.dump_region(covered) {
+                Err(err) => error!("Failed to dump stroke region: {0}", err),
+                Ok(buff) => self.segments.push((covered, buff)),
+            }
+            self.rect = self.render(framebuffer);
+        }
+        for i in 2..self.points.len() {
+            let window = [self.points[i - 2], self.points[i - 1], self.points[i]];
+            let tilt = self.tilt_at(i - 1);
+            self.draw_segment(framebuffer, bounds, window, tilt);
+        }
+        self.finish(framebuffer);
+        self.rect
+    }
+
+    /// Draws the stroke without keeping track of what it covers, for when the
+    /// area underneath is being repainted from scratch anyway
+    pub fn render(&self, framebuffer: &mut Framebuffer) -> mxcfb_rect {
+        match self.brush {
+            Brush::Fill => return draw_fill(framebuffer, &self.spans, self.color),
+            Brush::Text => {
+                let size = self.mult as f32;
+                return draw_label(framebuffer, self.points[0].0, &self.text, size, self.color);
+            }
+            _ => {}
+        }
+        let mut rect = mxcfb_rect::invalid();
+        for (i, window) in self.points.windows(3).enumerate() {
+            let points = [window[0], window[1], window[2]];
+            let tilt = self.tilt_at(i + 1);
+            rect = rect.merge_rect(&paint(
+                self.brush,
+                framebuffer,
+                points,
+                tilt,
+                self.mult,
+                self.color,
+            ));
+        }
+        rect
+    }
+
+    /// How wide the tip draws at `pressure`
+    pub fn width_at(&self, pressure: i32) -> f32 {
+        match self.brush {
+            Brush::Pen | Brush::Pencil => self.mult as f32 * pressure_scale(pressure),
+            _ => self.mult as f32,
+        }
+    }
+
+    /// The stroke as SVG elements, shifted so `origin` lands on 0, 0. Pen and
+    /// pencil strokes come out as one curve per segment, each as wide as the
+    /// tip was at that point's pressure. The pencil's grain is left out.
+    pub fn svg(&self, origin: cgmath::Point2<f32>) -> String {
+        let color = svg_color(self.color);
+        let at = |pos: cgmath::Point2<f32>| pos - origin.to_vec();
+        match self.brush {
+            Brush::Fill => {
+                let path: String = self
+                    .spans
+                    .iter()
+                    .map(|&(y, x0, x1)| {
+                        let (x, y) = (x0 as f32 - origin.x, y as f32 - origin.y);
+                        format!("M{0:.1} {1:.1}h{2}v1h-{2}z", x, y, x1 - x0 + 1)
+                    })
+                    .collect();
+                format!("<path d=\"{0}\" fill=\"{1}\"/>\n", path, color)
+            }
+            Brush::Text => {
+                let pos = at(self.points[0].0);
+                format!(
+                    "<text x=\"{0:.1}\" y=\"{1:.1}\" font-size=\"{2}\" fill=\"{3}\">{4}</text>\n",
+                    pos.x,
+                    pos.y,
+                    self.mult,
+                    color,
+                    svg_escape(&self.text)
+                )
+            }
+            // Like on screen, nothing shows until there's a full segment
+            _ if self.points.len() < 3 => String::new(),
+            Brush::Highlighter => {
+                let path: String = self
+                    .points
+                    .iter()
+                    .enumerate()
+                    .map(|(i, point)| {
+                        let pos = at(point.0);
+                        let command = if i == 0 { 'M' } else { 'L' };
+                        format!("{0}{1:.1} {2:.1}", command, pos.x, pos.y)
+                    })
+                    .collect();
+                format!(
+                    "<path d=\"{0}\" fill=\"none\" stroke=\"{1}\" stroke-width=\"{2}\" stroke-linejoin=\"round\"/>\n",
+                    path, color, self.mult
+                )
+            }
+            Brush::Pen | Brush::Pencil => self
+                .points
+                .windows(3)
+                .map(|window| {
+                    // The same curve draw_bezier takes through the window
+                    let start = at(window[2].0.midpoint(window[1].0));
+                    let ctrl = at(window[1].0);
+                    let end = at(window[1].0.midpoint(window[0].0));
+                    let width = self.width_at(window[1].1);
+                    format!(
+                        "<path d=\"M{0:.1} {1:.1}Q{2:.1} {3:.1} {4:.1} {5:.1}\" fill=\"none\" stroke=\"{6}\" stroke-width=\"{7:.2}\" stroke-linecap=\"round\"/>\n",
+                        start.x, start.y, ctrl.x, ctrl.y, end.x, end.y, color, width
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Draws the stroke with every point moved through `map` and its width
+    /// scaled by `zoom`, as seen through a zoomed view. Fills are clipped to
+    /// `clip`.
+    pub fn render_through<F>(
+        &self,
+        framebuffer: &mut Framebuffer,
+        map: F,
+        zoom: f32,
+        clip: &mxcfb_rect,
+    ) -> mxcfb_rect
+    where
+        F: Fn(cgmath::Point2<f32>) -> cgmath::Point2<f32>,
+    {
+        if self.brush == Brush::Fill {
+            let spans = map_spans(&self.spans, map, clip);
+            return draw_fill(framebuffer, &spans, self.color);
+        }
+        if self.brush == Brush::Text {
+            let size = self.mult as f32 * zoom;
+            return draw_label(
+                framebuffer,
+                map(self.points[0].0),
+                &self.text,
+                size,
+                self.color,
+            );
+        }
+
+        let mult = ((self.mult as f32 * zoom).round() as u32).max(1);
+        let points: Vec<StrokePoint> = self.points.iter().map(|p| (map(p.0), p.1)).collect();
+        let mut rect = mxcfb_rect::invalid();
+        for (i, window) in points.windows(3).enumerate() {
+            let window = [window[0], window[1], window[2]];
+            let tilt = self.tilt_at(i + 1);
+            rect = rect.merge_rect(&paint(
+                self.brush,
+                framebuffer,
+                window,
+                tilt,
+                mult,
+                self.color,
+            ));
+        }
+        rect
+    }
+
+    /// Rect the stroke will cover once drawn, worked out from its points
+    /// alone. Used after moving points around, when `rect` is stale.
+    pub fn bounds(&self) -> mxcfb_rect {
+        if self.brush == Brush::Fill {
+            return spans_rect(&self.spans);
+        }
+        if self.points.is_empty() {
+            return mxcfb_rect::invalid();
+        }
+        if self.brush == Brush::Text {
+            // Room for the tallest capitals above the baseline and the
+            // deepest descenders below
+            let (start, end) = (self.points[0].0, self.points[self.points.len() - 1].0);
+            let size = self.mult as f32;
+            let left = (start.x - 2.0).max(0.0);
+            let top = (start.y - size).max(0.0);
+            return mxcfb_rect {
+                top: top as u32,
+                left: left as u32,
+                width: (end.x + 2.0 - left).ceil() as u32,
+                height: (start.y + size / 3.0 - top).ceil() as u32,
+            };
+        }
+        let radius = self.mult as f32 * pressure_scale(MAX_PRESSURE) / 2.0;
+        let pad = match self.brush {
+            Brush::Pencil => radius * PENCIL_MAX_STRETCH + 2.0,
+            _ => radius.max(self.mult as f32) + 2.0,
+        };
+        let min_x = self.points.iter().map(|p| p.0.x).fold(f32::MAX, f32::min) - pad;
+        let max_x = self.points.iter().map(|p| p.0.x).fold(f32::MIN, f32::max) + pad;
+        let min_y = self.points.iter().map(|p| p.0.y).fold(f32::MAX, f32::min) - pad;
+        let max_y = self.points.iter().map(|p| p.0.y).fold(f32::MIN, f32::max) + pad;
+        let left = min_x.max(0.0);
+        let top = min_y.max(0.0);
+        mxcfb_rect {
+            top: top as u32,
+            left: left as u32,
+            width: (max_x - left).ceil() as u32,
+            height: (max_y - top).ceil() as u32,
+        }
+    }
+
+    /// Points spread over the stroke, for telling whether it lies inside an
+    /// outline. Fills have no points of their own, so the ends of their spans
+    /// stand in.
+    pub fn outline(&self) -> Vec<cgmath::Point2<f32>> {
+        if self.brush == Brush::Fill {
+            return self
+                .spans
+                .iter()
+                .flat_map(|&(y, left, right)| {
+                    [left, right].map(|x| cgmath::Point2 {
+                        x: x as f32,
+                        y: y as f32,
+                    })
+                })
+                .collect();
+        }
+        self.points.iter().map(|p| p.0).collect()
+    }
+
+    /// Whether an eraser of `radius` centered on `pos` touches the stroke
+    pub fn hit_by(&self, pos: cgmath::Point2<f32>, radius: f32) -> bool {
+        let reach = radius + self.mult as f32 / 2.0;
+        if pos.x + reach < self.rect.left as f32
+            || pos.y + reach < self.rect.top as f32
+            || pos.x - reach > (self.rect.left + self.rect.width) as f32
+            || pos.y - reach > (self.rect.top + self.rect.height) as f32
+        {
+            return false;
+        }
+        if self.brush == Brush::Fill {
+            return self.spans.iter().any(|&(y, left, right)| {
+                (y as f32 - pos.y).abs() <= radius
+                    && pos.x + radius >= left as f32
+                    && pos.x - radius <= right as f32
+            });
+        }
+        self.points
+            .windows(2)
+            .any(|w| distance_to_segment(pos, w[0].0, w[1].0) <= reach)
+    }
+}
+
+/// Moves every pixel of `spans` through `map`, which may scale them, keeping
+/// only what lands inside `clip`
+pub fn map_spans<F>(spans: &[Span], map: F, clip: &mxcfb_rect) -> Vec<Span>
+where
+    F: Fn(cgmath::Point2<f32>) -> cgmath::Point2<f32>,
+{
+    let mut mapped = Vec::with_capacity(spans.len());
+    for &(y, left, right) in spans.iter() {
+        let top_left = map(cgmath::Point2 {
+            x: left as f32,
+            y: y as f32,
+        });
+        let bottom_right = map(cgmath::Point2 {
+            x: (right + 1) as f32,
+            y: (y + 1) as f32,
+        });
+        let x0 = (top_left.x.max(clip.left as f32)) as u32;
+        let x1 = (bottom_right.x.min((clip.left + clip.width) as f32)) as u32;
+        let y0 = (top_left.y.max(clip.top as f32)) as u32;
+        let y1 = (bottom_right.y.min((clip.top + clip.height) as f32)) as u32;
+        if x1 > x0 {
+            mapped.extend((y0..y1).map(|row| (row, x0, x1 - 1)));
+        }
+    }
+    mapped
+}
+
+/// Puts back the pixels in `snapshot`. Returns the rect that needs refreshing.
+pub fn restore(framebuffer: &mut Framebuffer, snapshot: Snapshot) -> mxcfb_rect {
+    let (rect, compressed) = snapshot;
+    if let Err(e) = framebuffer.restore_region(rect, &compressed.decompress()) {
+        error!("Error while restoring region: {0}", e);
+    }
+    rect
+}
+
+fn paint(
+    brush: Brush,
+    framebuffer: &mut Framebuffer,
+    points: [StrokePoint; 3],
+    tilt: cgmath::Vector2<f32>,
+    mult: u32,
+    col: color,
+) -> mxcfb_rect {
+    match brush {
+        Brush::Pen => draw_bezier(framebuffer, points, mult, col),
+        Brush::Highlighter => draw_highlight(framebuffer, points, mult, col),
+        Brush::Pencil => draw_pencil(framebuffer, points, tilt, mult, col),
+        Brush::Fill | Brush::Text => mxcfb_rect::invalid(),
+    }
+}
+
+/// Width of the tip at `pressure`, as a multiple of the stroke's `mult`
+fn pressure_scale(pressure: i32) -> f32 {
+    (pressure.max(0) as f32 / REFERENCE_PRESSURE).powf(*PRESSURE_GAMMA)
+}
+
+fn svg_color(col: color) -> String {
+    let [r, g, b] = col.to_rgb8();
+    format!("#{0:02x}{1:02x}{2:02x}", r, g, b)
+}
+
+fn svg_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn draw_bezier(
+    framebuffer: &mut Framebuffer,
+    points: [StrokePoint; 3],
+    mult: u32,
+    col: color,
+) -> mxcfb_rect {
+    let radii: Vec<f32> = points
+        .iter()
+        .map(|point| mult as f32 * pressure_scale(point.1) / 2.0)
+        .collect();
+    // calculate control points
+    let start_point = points[2].0.midpoint(points[1].0);
+    let ctrl_point = points[1].0;
+    let end_point = points[1].0.midpoint(points[0].0);
+    // calculate diameters
+    let start_width = radii[2] + radii[1];
+    let ctrl_width = radii[1] * 2.0;
+    let end_width = radii[1] + radii[0];
+    let samples = match *BEZIER_SEGMENT_PX {
+        seg if seg > 0.0 => {
+            let length =
+                (ctrl_point - start_point).magnitude() + (end_point - ctrl_point).magnitude();
+            ((length / seg).ceil() as i32).clamp(2, *BEZIER_SAMPLES)
+        }
+        _ => *BEZIER_SAMPLES,
+    };
+    framebuffer.draw_dynamic_bezier(
+        (start_point, start_width),
+        (ctrl_point, ctrl_width),
+        (end_point, end_width),
+        samples,
+        col,
+    )
+}
+
+/// The part of `rect` that's on the display, so that a tip running off the
+/// edge still draws the part of it that isn't
+fn clip_to_screen(rect: mxcfb_rect) -> Option<mxcfb_rect> {
+    let screen = mxcfb_rect {
+        top: 0,
+        left: 0,
+        width: DISPLAYWIDTH as u32,
+        height: DISPLAYHEIGHT as u32,
+    };
+    canvas::intersection(&rect, &screen)
+}
+
+/// Drags a flat tip along the polyline through `points`, only ever
+/// darkening pixels. Stamping the same spot twice is harmless, so overlapping
+/// windows can each cover their full length.
+fn draw_highlight(
+    framebuffer: &mut Framebuffer,
+    points: [StrokePoint; 3],
+    height: u32,
+    col: color,
+) -> mxcfb_rect {
+    let half_w = HIGHLIGHTER_TIP_WIDTH / 2.0;
+    let half_h = height as f32 / 2.0;
+    let min_x = points.iter().map(|p| p.0.x).fold(f32::MAX, f32::min) - half_w;
+    let max_x = points.iter().map(|p| p.0.x).fold(f32::MIN, f32::max) + half_w;
+    let min_y = points.iter().map(|p| p.0.y).fold(f32::MAX, f32::min) - half_h;
+    let max_y = points.iter().map(|p| p.0.y).fold(f32::MIN, f32::max) + half_h;
+    let rect = match clip_to_screen(mxcfb_rect {
+        top: min_y.max(0.0) as u32,
+        left: min_x.max(0.0) as u32,
+        width: (max_x - min_x.max(0.0)).ceil() as u32 + 1,
+        height: (max_y - min_y.max(0.0)).ceil() as u32 + 1,
+    }) {
+        Some(rect) => rect,
+        None => return mxcfb_rect::invalid(),
+    };
+
+    let mut buff = match framebuffer.dump_region(rect) {
+        Err(err) => {
+            error!("Failed to dump highlight region: {0}", err);
+            return mxcfb_rect::invalid();
+        }
+        Ok(buff) => buff,
+    };
+    let bpp = buff.len() / (rect.width * rect.height) as usize;
+    let ink = col.as_native();
+
+    for pair in points.windows(2) {
+        let (a, b) = (pair[0].0, pair[1].0);
+        let steps = (b.x - a.x).abs().max((b.y - a.y).abs()).ceil() as usize + 1;
+        for step in 0..=steps {
+            let t = step as f32 / steps as f32;
+            let x = a.x + (b.x - a.x) * t;
+            let y = a.y + (b.y - a.y) * t;
+            let x0 = ((x - half_w).max(rect.left as f32) as u32 - rect.left) as usize;
+            let x1 = ((x + half_w) as u32).min(rect.left + rect.width - 1) - rect.left;
+            let y0 = ((y - half_h).max(rect.top as f32) as u32 - rect.top) as usize;
+            let y1 = ((y + half_h) as u32).min(rect.top + rect.height - 1) - rect.top;
+            for row in y0..=y1 as usize {
+                for column in x0..=x1 as usize {
+                    let i = (row * rect.width as usize + column) * bpp;
+                    let pixel = [buff[i], buff[i + 1]];
+                    if luminance(ink) < luminance(pixel) {
+                        buff[i..i + 2].copy_from_slice(&ink);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Err(e) = framebuffer.restore_region(rect, &buff) {
+        error!("Error while restoring region: {0}", e);
+    }
+    rect
+}
+
+/// Stamps an elliptical footprint along the polyline through `points`. The
+/// further the pen leans past upright, the longer the footprint grows in the
+/// direction of `tilt` and the fewer of the pixels under it get ink, like the
+/// side of a pencil lead. Only ever darkens, as with the highlighter.
+fn draw_pencil(
+    framebuffer: &mut Framebuffer,
+    points: [StrokePoint; 3],
+    tilt: cgmath::Vector2<f32>,
+    mult: u32,
+    col: color,
+) -> mxcfb_rect {
+    let lean =
+        ((tilt.magnitude() - PENCIL_UPRIGHT) / (PENCIL_FLAT - PENCIL_UPRIGHT)).clamp(0.0, 1.0);
+    let stretch = 1.0 + (PENCIL_MAX_STRETCH - 1.0) * lean;
+    let along = if tilt.magnitude2() > 0.0 {
+        tilt.normalize()
+    } else {
+        cgmath::vec2(1.0, 0.0)
+    };
+    let across = cgmath::vec2(-along.y, along.x);
+    // Share of the pixels under the footprint that take ink, out of 256
+    let density = (256.0 * (1.0 - 0.6 * lean)) as u32;
+
+    let half_width = |pressure: i32| (mult as f32 * pressure_scale(pressure) / 2.0).max(0.5);
+    let reach = half_width(points.iter().map(|p| p.1).max().unwrap()) * stretch + 1.0;
+    let min_x = points.iter().map(|p| p.0.x).fold(f32::MAX, f32::min) - reach;
+    let max_x = points.iter().map(|p| p.0.x).fold(f32::MIN, f32::max) + reach;
+    let min_y = points.iter().map(|p| p.0.y).fold(f32::MAX, f32::min) - reach;
+    let max_y = points.iter().map(|p| p.0.y).fold(f32::MIN, f32::max) + reach;
+    let rect = match clip_to_screen(mxcfb_rect {
+        top: min_y.max(0.0) as u32,
+        left: min_x.max(0.0) as u32,
+        width: (max_x - min_x.max(0.0)).ceil() as u32 + 1,
+        height: (max_y - min_y.max(0.0)).ceil() as u32 + 1,
+    }) {
+        Some(rect) => rect,
+        None => return mxcfb_rect::invalid(),
+    };
+
+    let mut buff = match framebuffer.dump_region(rect) {
+        Err(err) => {
+            error!("Failed to dump pencil region: {0}", err);
+            return mxcfb_rect::invalid();
+        }
+        Ok(buff) => buff,
+    };
+    let bpp = buff.len() / (rect.width * rect.height) as usize;
+    let ink = col.as_native();
+
+    for pair in points.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let steps = (b.0 - a.0).magnitude().ceil() as usize + 1;
+        for step in 0..=steps {
+            let t = step as f32 / steps as f32;
+            let center = a.0 + (b.0 - a.0) * t;
+            let minor = half_width((a.1 as f32 + (b.1 - a.1) as f32 * t) as i32);
+            let major = minor * stretch;
+            let x0 = (center.x - major).max(rect.left as f32) as u32;
+            let x1 = ((center.x + major) as u32).min(rect.left + rect.width - 1);
+            let y0 = (center.y - major).max(rect.top as f32) as u32;
+            let y1 = ((center.y + major) as u32).min(rect.top + rect.height - 1);
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    let offset = cgmath::vec2(x as f32 + 0.5, y as f32 + 0.5) - center.to_vec();
+                    let (u, v) = (offset.dot(along) / major, offset.dot(across) / minor);
+                    if u * u + v * v > 1.0 || grain(x, y) >= density {
+                        continue;
+                    }
+                    let i = (((y - rect.top) * rect.width + (x - rect.left)) as usize) * bpp;
+                    let pixel = [buff[i], buff[i + 1]];
+                    if luminance(ink) < luminance(pixel) {
+                        buff[i..i + 2].copy_from_slice(&ink);
+                    }
+                }
+            }
+        }
+    }
+
+    if let Err(e) = framebuffer.restore_region(rect, &buff) {
+        error!("Error while restoring region: {0}", e);
+    }
+    rect
+}
+
+/// Fixed noise in 0..256 for each pixel, so a pencil stroke grains the same
+/// way every time it's painted
+fn grain(x: u32, y: u32) -> u32 {
+    let mut h = x.wrapping_mul(374_761_393) ^ y.wrapping_mul(668_265_263);
+    h = (h ^ (h >> 13)).wrapping_mul(1_274_126_177);
+    (h ^ (h >> 16)) & 0xff
+}
+
+/// Draws `text` with its baseline starting at `pos`. Each glyph gets painted
+/// onto a white box, so wherever the box covered something darker, that is
+/// put back.
+fn draw_label(
+    framebuffer: &mut Framebuffer,
+    pos: cgmath::Point2<f32>,
+    text: &str,
+    size: f32,
+    col: color,
+) -> mxcfb_rect {
+    let rect = framebuffer.draw_text(pos, text, size, col, true);
+    if rect.width == 0 || rect.height == 0 {
+        return mxcfb_rect::invalid();
+    }
+    let before = match framebuffer.dump_region(rect) {
+        Err(err) => {
+            error!("Failed to dump text region: {0}", err);
+            return mxcfb_rect::invalid();
+        }
+        Ok(buff) => buff,
+    };
+    framebuffer.draw_text(pos, text, size, col, false);
+    let mut after = match framebuffer.dump_region(rect) {
+        Err(err) => {
+            error!("Failed to dump text region: {0}", err);
+            return rect;
+        }
+        Ok(buff) => buff,
+    };
+    let bpp = after.len() / (rect.width * rect.height) as usize;
+    for i in (0..after.len()).step_by(bpp) {
+        if luminance([before[i], before[i + 1]]) < luminance([after[i], after[i + 1]]) {
+            after[i..i + bpp].copy_from_slice(&before[i..i + bpp]);
+        }
+    }
+
+    if let Err(e) = framebuffer.restore_region(rect, &after) {
+        error!("Error while restoring region: {0}", e);
+    }
+    rect
+}
+
+/// Paints `spans` solid black or white, or for grays an ordered dither
+/// pattern, which stays crisp under the fast black and white waveforms
+fn draw_fill(framebuffer: &mut Framebuffer, spans: &[Span], col: color) -> mxcfb_rect {
+    let rect = spans_rect(spans);
+    if rect == mxcfb_rect::invalid() {
+        return rect;
+    }
+    let mut buff = match framebuffer.dump_region(rect) {
+        Err(err) => {
+            error!("Failed to dump fill region: {0}", err);
+            return mxcfb_rect::invalid();
+        }
+        Ok(buff) => buff,
+    };
+    let bpp = buff.len() / (rect.width * rect.height) as usize;
+    let (black, white) = (color::BLACK.as_native(), color::WHITE.as_native());
+
+    for &(y, left, right) in spans {
+        for x in left..=right {
+            let ink = match col {
+                color::GRAY(level) => {
+                    if (BAYER[y as usize % 4][x as usize % 4] as u16 * 16 + 8) < level as u16 {
+                        black
+                    } else {
+                        white
+                    }
+                }
+                other => other.as_native(),
+            };
+            let i = (((y - rect.top) * rect.width + (x - rect.left)) as usize) * bpp;
+            buff[i..i + 2].copy_from_slice(&ink);
+        }
+    }
+
+    if let Err(e) = framebuffer.restore_region(rect, &buff) {
+        error!("Error while restoring region: {0}", e);
+    }
+    rect
+}
+
+fn spans_rect(spans: &[Span]) -> mxcfb_rect {
+    spans
+        .iter()
+        .fold(mxcfb_rect::invalid(), |acc, &(y, left, right)| {
+            acc.merge_rect(&mxcfb_rect {
+                top: y,
+                left,
+                width: right - left + 1,
+                height: 1,
+            })
+        })
+}
+
+/// Green channel of a native rgb565 pixel. The panel is grayscale, so this
+/// (the widest channel) is enough to tell which of two pixels is darker.
+fn luminance(native: [u8; 2]) -> u16 {
+    (u16::from_le_bytes(native) >> 5) & 0b111111
+}
+
+fn distance_to_segment(
+    p: cgmath::Point2<f32>,
+    a: cgmath::Point2<f32>,
+    b: cgmath::Point2<f32>,
+) -> f32 {
+    let ab = b - a;
+    let len2 = ab.magnitude2();
+    if len2 == 0.0 {
+        return (p - a).magnitude();
+    }
+    let t = ((p - a).dot(ab) / len2).clamp(0.0, 1.0);
+    (p - (a + ab * t)).magnitude()
+}
+
+/// Conservative rect covering a bezier segment of at most `width`, clipped to
+/// `bounds`. None if it falls entirely outside.
+fn bezier_bounds(points: &[StrokePoint; 3], width: f32, bounds: &mxcfb_rect) -> Option<mxcfb_rect> {
+    let pad = width.ceil() + 2.0;
+    let min_x = points.iter().map(|p| p.0.x).fold(f32::MAX, f32::min) - pad;
+    let max_x = points.iter().map(|p| p.0.x).fold(f32::MIN, f32::max) + pad;
+    let min_y = points.iter().map(|p| p.0.y).fold(f32::MAX, f32::min) - pad;
+    let max_y = points.iter().map(|p| p.0.y).fold(f32::MIN, f32::max) + pad;
+
+    let left = (min_x.floor() as i64).max(bounds.left as i64);
+    let top = (min_y.floor() as i64).max(bounds.top as i64);
+    let right = (max_x.ceil() as i64).min((bounds.left + bounds.width) as i64);
+    let bottom = (max_y.ceil() as i64).min((bounds.top + bounds.height) as i64);
+    if right <= left || bottom <= top {
+        return None;
+    }
+    Some(mxcfb_rect {
+        top: top as u32,
+        left: left as u32,
+        width: (right - left) as u32,
+        height: (bottom - top) as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32) -> StrokePoint {
+        (cgmath::Point2 { x, y }, REFERENCE_PRESSURE as i32)
+    }
+
+    #[test]
+    fn bounds_cover_the_widest_the_tip_can_draw() {
+        let mut stroke = Stroke::new(color::BLACK, 4, Brush::Pen);
+        stroke.points = vec![point(100.0, 200.0), point(150.0, 220.0)];
+        let bounds = stroke.bounds();
+        let pad = 4.0 * pressure_scale(MAX_PRESSURE) / 2.0;
+        assert!(bounds.left as f32 <= 100.0 - pad && bounds.top as f32 <= 200.0 - pad);
+        assert!((bounds.left + bounds.width) as f32 >= 150.0 + pad);
+        assert!((bounds.top + bounds.height) as f32 >= 220.0 + pad);
+    }
+
+    #[test]
+    fn bounds_stop_at_the_top_left_corner() {
+        let mut stroke = Stroke::new(color::BLACK, 10, Brush::Pen);
+        stroke.points = vec![point(1.0, 2.0), point(30.0, 40.0)];
+        let bounds = stroke.bounds();
+        assert_eq!((bounds.left, bounds.top), (0, 0));
+    }
+
+    #[test]
+    fn translated_strokes_move_their_bounds() {
+        let mut stroke = Stroke::new(color::BLACK, 3, Brush::Pen);
+        stroke.points = vec![point(100.0, 100.0), point(120.0, 140.0)];
+        let before = stroke.bounds();
+        let after = stroke.translated(25, -10).rect;
+        assert_eq!(after.left, before.left + 25);
+        assert_eq!(after.top, before.top - 10);
+        assert_eq!((after.width, after.height), (before.width, before.height));
+    }
+
+    #[test]
+    fn segment_bounds_are_clipped() {
+        let canvas = mxcfb_rect {
+            top: 0,
+            left: 0,
+            width: 100,
+            height: 100,
+        };
+        let points = [point(90.0, 50.0), point(95.0, 55.0), point(120.0, 60.0)];
+        let covered = bezier_bounds(&points, 4.0, &canvas).unwrap();
+        assert_eq!(covered.left + covered.width, 100);
+        let outside = [
+            point(200.0, 200.0),
+            point(210.0, 210.0),
+            point(220.0, 220.0),
+        ];
+        assert!(bezier_bounds(&outside, 4.0, &canvas).is_none());
+    }
+
+    #[test]
+    fn eraser_hits_only_near_the_line() {
+        let mut stroke = Stroke::new(color::BLACK, 2, Brush::Pen);
+        stroke.points = vec![point(100.0, 100.0), point(200.0, 100.0)];
+        stroke.rect = stroke.bounds();
+        assert!(stroke.hit_by(cgmath::Point2 { x: 150.0, y: 104.0 }, 5.0));
+        assert!(!stroke.hit_by(cgmath::Point2 { x: 150.0, y: 120.0 }, 5.0));
+        assert!(!stroke.hit_by(cgmath::Point2 { x: 215.0, y: 100.0 }, 5.0));
+    }
+}